@@ -1,6 +1,9 @@
+#[cfg(feature = "arp")]
 extern crate cpp_build;
+#[cfg(feature = "arp")]
 use std::env;
 
+#[cfg(feature = "arp")]
 fn main() {
     let mut my_config = cpp_build::Config::new();
     match env::var("PLCNEXT_HEADERS") {
@@ -11,4 +14,8 @@ fn main() {
     // my_config.include("/opt/pxc/sdk/AXCF2152/2019.9/sysroots/cortexa9t2hf-neon-pxc-linux-gnueabi/usr/include/plcnext");
     // my_config.build("src/lib.rs");
     println!("cargo:rustc-link-lib=cppformat");
-}
\ No newline at end of file
+}
+
+// Without the arp feature there is no C++ code to build, and no library to link.
+#[cfg(not(feature = "arp"))]
+fn main() {}
@@ -0,0 +1,307 @@
+use crate::logging::{self, LogLevel};
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::{Arc, RwLock};
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Level, LevelFilter, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A tracing_subscriber::Layer that writes tracing events to the Arp log of the controller.
+///
+/// Each message starts with the target, followed by the spans that the event is in,
+/// from the outermost, with their fields, and then the message with the other fields
+/// of the event:
+///
+/// ```text
+/// my_app::net: connection{peer="10.0.0.7"}: request{id=12}: sent reply bytes=512
+/// ```
+///
+/// Which events are written is decided per target by the TargetFilter, which can be
+/// changed while the application runs. Without the arp feature, events are written to stdout.
+pub struct ArpLayer {
+    filter: TargetFilter
+}
+
+impl ArpLayer {
+    /// Creates a layer that writes Info and higher levels for all targets.
+    pub fn new() -> ArpLayer {
+        ArpLayer { filter: TargetFilter::new(LevelFilter::INFO) }
+    }
+
+    /// Uses the given filter instead of the default one.
+    pub fn with_filter(mut self, filter: TargetFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// The filter of this layer. It can be cloned, and kept to change the levels later.
+    pub fn filter(&self) -> &TargetFilter {
+        &self.filter
+    }
+}
+
+impl Default for ArpLayer {
+    fn default() -> ArpLayer {
+        ArpLayer::new()
+    }
+}
+
+// The fields of a span, formatted once when they're recorded.
+struct SpanFields(String);
+
+impl<S> Layer<S> for ArpLayer where S: Subscriber + for<'a> LookupSpan<'a> {
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            match extensions.get_mut::<SpanFields>() {
+                Some(fields) => fields.0.push_str(&visitor.fields),
+                None => extensions.insert(SpanFields(visitor.fields))
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let metadata = event.metadata();
+        let level = log_level(metadata.level());
+        if !self.filter.enabled(metadata.target(), metadata.level()) || !logging::is_enabled(level) {
+            return;
+        }
+        logging::log_at(level, &format_event(event, &ctx));
+    }
+}
+
+// The Arp level of a tracing level.
+fn log_level(level: &Level) -> LogLevel {
+    match *level {
+        Level::ERROR => LogLevel::Error,
+        Level::WARN => LogLevel::Warning,
+        Level::INFO => LogLevel::Info,
+        Level::DEBUG => LogLevel::Debug,
+        Level::TRACE => LogLevel::Trace
+    }
+}
+
+// Formats an event with its target and the spans that it is in, as shown in the ArpLayer documentation.
+fn format_event<S>(event: &Event, ctx: &Context<S>) -> String where S: Subscriber + for<'a> LookupSpan<'a> {
+    let mut msg = format!("{}: ", event.metadata().target());
+    if let Some(scope) = ctx.event_scope(event) {
+        for span in scope.from_root() {
+            msg.push_str(span.name());
+            if let Some(fields) = span.extensions().get::<SpanFields>() {
+                if !fields.0.is_empty() {
+                    let _ = write!(msg, "{{{}}}", fields.0.trim_start());
+                }
+            }
+            msg.push_str(": ");
+        }
+    }
+    let mut visitor = FieldVisitor::default();
+    event.record(&mut visitor);
+    msg.push_str(&visitor.message);
+    if visitor.message.is_empty() {
+        msg.push_str(visitor.fields.trim_start());
+    } else {
+        msg.push_str(&visitor.fields);
+    }
+    msg
+}
+
+// Collects the message of an event, and the other fields as " name=value".
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: String
+}
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// The levels that an ArpLayer writes, per target.
+///
+/// A level set for a target also applies to the targets below it, e.g. a level for
+/// "my_app::net" applies to "my_app::net::tcp" as well, unless that has its own level.
+/// Targets without a level use the default level. Clones share the same levels, so that
+/// a clone kept by the application changes the filter of the layer.
+#[derive(Clone)]
+pub struct TargetFilter {
+    levels: Arc<RwLock<Levels>>
+}
+
+struct Levels {
+    default: LevelFilter,
+    targets: HashMap<String, LevelFilter>
+}
+
+impl TargetFilter {
+    /// Creates a filter with a default level, and no levels for targets.
+    pub fn new(default: LevelFilter) -> TargetFilter {
+        TargetFilter { levels: Arc::new(RwLock::new(Levels { default, targets: HashMap::new() })) }
+    }
+
+    /// Sets the level for targets that don't have their own level.
+    pub fn set_default_level(&self, level: LevelFilter) {
+        self.levels.write().unwrap_or_else(|e| e.into_inner()).default = level;
+    }
+
+    /// Sets the level for a target and the targets below it.
+    pub fn set_level(&self, target: &str, level: LevelFilter) {
+        self.levels.write().unwrap_or_else(|e| e.into_inner()).targets.insert(target.to_string(), level);
+    }
+
+    /// Removes the level of a target, which then uses the level of the target above it, or the default.
+    pub fn clear_level(&self, target: &str) {
+        self.levels.write().unwrap_or_else(|e| e.into_inner()).targets.remove(target);
+    }
+
+    /// The level that applies to a target.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let levels = self.levels.read().unwrap_or_else(|e| e.into_inner());
+        // Try the target itself, then each parent module, from the most specific.
+        let mut prefix = target;
+        loop {
+            if let Some(level) = levels.targets.get(prefix) {
+                return *level;
+            }
+            match prefix.rfind("::") {
+                Some(index) => prefix = &prefix[..index],
+                None => return levels.default
+            }
+        }
+    }
+
+    /// Whether an event with this target and level is written.
+    pub fn enabled(&self, target: &str, level: &Level) -> bool {
+        self.level_for(target) >= *level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Keeps the messages that an ArpLayer would write, instead of writing them to the log.
+    struct Capture {
+        layer: ArpLayer,
+        messages: Arc<Mutex<Vec<String>>>
+    }
+
+    impl<S> Layer<S> for Capture where S: Subscriber + for<'a> LookupSpan<'a> {
+        fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+            self.layer.on_new_span(attrs, id, ctx);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+            self.layer.on_record(id, values, ctx);
+        }
+
+        fn on_event(&self, event: &Event, ctx: Context<S>) {
+            let metadata = event.metadata();
+            if self.layer.filter().enabled(metadata.target(), metadata.level()) {
+                self.messages.lock().unwrap().push(format_event(event, &ctx));
+            }
+        }
+    }
+
+    // Runs the function with a subscriber that captures the messages of an ArpLayer with the filter.
+    fn capture<F: FnOnce()>(filter: TargetFilter, f: F) -> Vec<String> {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let layer = Capture { layer: ArpLayer::new().with_filter(filter), messages: messages.clone() };
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), f);
+        let messages = messages.lock().unwrap();
+        messages.clone()
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(log_level(&Level::ERROR), LogLevel::Error);
+        assert_eq!(log_level(&Level::WARN), LogLevel::Warning);
+        assert_eq!(log_level(&Level::INFO), LogLevel::Info);
+        assert_eq!(log_level(&Level::DEBUG), LogLevel::Debug);
+        assert_eq!(log_level(&Level::TRACE), LogLevel::Trace);
+    }
+
+    #[test]
+    fn level_for_falls_back_to_parents_and_default() {
+        let filter = TargetFilter::new(LevelFilter::WARN);
+        filter.set_level("my_app::net", LevelFilter::DEBUG);
+        filter.set_level("my_app::net::tcp", LevelFilter::ERROR);
+
+        assert_eq!(filter.level_for("my_app::net"), LevelFilter::DEBUG);
+        assert_eq!(filter.level_for("my_app::net::udp::socket"), LevelFilter::DEBUG);
+        assert_eq!(filter.level_for("my_app::net::tcp::listener"), LevelFilter::ERROR);
+        assert_eq!(filter.level_for("my_app"), LevelFilter::WARN);
+        assert_eq!(filter.level_for("my_app::network"), LevelFilter::WARN);
+        assert!(filter.enabled("my_app::net::udp", &Level::DEBUG));
+        assert!(!filter.enabled("my_app::net::udp", &Level::TRACE));
+
+        filter.clear_level("my_app::net::tcp");
+        assert_eq!(filter.level_for("my_app::net::tcp::listener"), LevelFilter::DEBUG);
+        filter.clear_level("my_app::net");
+        filter.set_default_level(LevelFilter::OFF);
+        assert_eq!(filter.level_for("my_app::net::tcp"), LevelFilter::OFF);
+        assert!(!filter.enabled("my_app::net::tcp", &Level::ERROR));
+    }
+
+    #[test]
+    fn clones_share_the_levels() {
+        let filter = TargetFilter::new(LevelFilter::INFO);
+        let layer = ArpLayer::new().with_filter(filter.clone());
+        filter.set_level("my_app", LevelFilter::TRACE);
+        assert_eq!(layer.filter().level_for("my_app::io"), LevelFilter::TRACE);
+        layer.filter().clone().set_default_level(LevelFilter::ERROR);
+        assert_eq!(filter.level_for("other"), LevelFilter::ERROR);
+    }
+
+    #[test]
+    fn message_and_fields() {
+        let messages = capture(TargetFilter::new(LevelFilter::INFO), || {
+            tracing::info!(target: "my_app", bytes = 512, peer = "10.0.0.7", "sent {}", "reply");
+            tracing::warn!(target: "my_app", retries = 3);
+            tracing::debug!(target: "my_app", "not written");
+        });
+        assert_eq!(messages, vec![
+            "my_app: sent reply bytes=512 peer=\"10.0.0.7\"".to_string(),
+            "my_app: retries=3".to_string()
+        ]);
+    }
+
+    #[test]
+    fn span_prefix() {
+        let messages = capture(TargetFilter::new(LevelFilter::INFO), || {
+            let connection = tracing::info_span!("connection", peer = "10.0.0.7");
+            let _connection = connection.enter();
+            let request = tracing::info_span!("request", id = tracing::field::Empty);
+            request.record("id", 12);
+            let _request = request.enter();
+            let idle = tracing::info_span!("idle");
+            idle.in_scope(|| tracing::info!(target: "my_app::net", "waiting"));
+            tracing::info!(target: "my_app::net", bytes = 512, "sent reply");
+        });
+        assert_eq!(messages, vec![
+            "my_app::net: connection{peer=\"10.0.0.7\"}: request{id=12}: idle: waiting".to_string(),
+            "my_app::net: connection{peer=\"10.0.0.7\"}: request{id=12}: sent reply bytes=512".to_string()
+        ]);
+    }
+}
@@ -1,28 +1,27 @@
+#![recursion_limit="512"]
+
+#[cfg(feature = "arp")]
 #[macro_use]
 extern crate cpp;
 
-// use std::ffi::CStr;
-use std::ffi::CString;
-
-cpp!{{
-    #include "Arp/System/Commons/Logging.h"
-}}
+#[cfg(feature = "tracing")]
+mod layer;
+mod logger;
+mod logging;
 
-pub fn main() {
-    unsafe {
-        cpp!([] {
-            int a = 12;
-            Arp::Log::Info("hello");
-        });
-    }
-}
+#[cfg(feature = "tracing")]
+pub use layer::ArpLayer;
+#[cfg(feature = "tracing")]
+pub use layer::TargetFilter;
+pub use logger::ArpLogger;
+pub use logger::init;
+pub use logger::init_with_level;
+pub use logging::LogLevel;
+pub use logging::Logger;
+pub use logging::is_enabled;
+pub use logging::log_at;
 
+/// Writes a message to the root logger at the Info level.
 pub fn log(msg: &str) {
-    let text = CString::new(msg).expect("CString::new(msg) failed");;
-    let raw_text = text.into_raw();
-    unsafe {
-        cpp!([raw_text as "const char *"] {
-            Arp::Log::Info(raw_text);
-        });
-    }
+    log_at(LogLevel::Info, msg);
 }
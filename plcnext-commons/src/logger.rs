@@ -0,0 +1,48 @@
+use crate::logging::{self, LogLevel};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+static LOGGER: ArpLogger = ArpLogger;
+
+/// A backend for the log crate that writes to the Arp log of the controller, i.e. to Output.log.
+///
+/// The log crate levels map to the Arp levels Trace, Debug, Info, Warning and Error.
+/// Each message starts with its target, followed by the module path if that is different.
+/// Messages at levels that are disabled in the Arp log are skipped before they are formatted.
+/// Without the arp feature, messages are written to stdout instead.
+pub struct ArpLogger;
+
+impl Log for ArpLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        logging::is_enabled(LogLevel::from(metadata.level()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = match record.module_path() {
+            Some(module_path) if module_path != record.target() =>
+                format!("{} ({}): {}", record.target(), module_path, record.args()),
+            _ => format!("{}: {}", record.target(), record.args())
+        };
+        logging::log_at(LogLevel::from(record.level()), &msg);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the ArpLogger as the logger of the log crate, for Info and higher levels.
+/// This can only be done once per process.
+pub fn init() -> Result<(), SetLoggerError> {
+    init_with_level(LevelFilter::Info)
+}
+
+/// Installs the ArpLogger as the logger of the log crate.
+/// * 'level' - The most detailed level that is passed on. Messages with more detailed levels
+///   are skipped by the log macros, before they are formatted.
+pub fn init_with_level(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    Ok(())
+}
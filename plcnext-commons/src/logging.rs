@@ -0,0 +1,402 @@
+#[cfg(feature = "arp")]
+use std::ffi::CString;
+use std::fmt;
+#[cfg(feature = "arp")]
+use std::os::raw::c_void;
+
+#[cfg(feature = "arp")]
+cpp!{{
+    #include "Arp/System/Commons/Logging.h"
+
+    using Arp::System::Commons::Diagnostics::Logging::Logger;
+    using Arp::System::Commons::Diagnostics::Logging::LogLevel;
+
+    // The levels in the order of LogLevel in Rust.
+    static LogLevel ToLogLevel(int level)
+    {
+        switch (level)
+        {
+            case 0: return LogLevel::Trace;
+            case 1: return LogLevel::Debug;
+            case 2: return LogLevel::Info;
+            case 3: return LogLevel::Warning;
+            case 4: return LogLevel::Error;
+            case 5: return LogLevel::Critical;
+            default: return LogLevel::Fatal;
+        }
+    }
+
+    // The text is passed as an argument of the format string,
+    // so that braces in it are not taken as placeholders.
+    static void WriteToLogger(Logger& logger, int level, const char* text)
+    {
+        switch (level)
+        {
+            case 0: logger.Trace("{0}", text); break;
+            case 1: logger.Debug("{0}", text); break;
+            case 2: logger.Info("{0}", text); break;
+            case 3: logger.Warning("{0}", text); break;
+            case 4: logger.Error("{0}", text); break;
+            case 5: logger.Critical("{0}", text); break;
+            default: logger.Fatal("{0}", text); break;
+        }
+    }
+
+    static void WriteToRoot(int level, const char* text)
+    {
+        switch (level)
+        {
+            case 0: Arp::Log::Trace("{0}", text); break;
+            case 1: Arp::Log::Debug("{0}", text); break;
+            case 2: Arp::Log::Info("{0}", text); break;
+            case 3: Arp::Log::Warning("{0}", text); break;
+            case 4: Arp::Log::Error("{0}", text); break;
+            case 5: Arp::Log::Critical("{0}", text); break;
+            default: Arp::Log::Fatal("{0}", text); break;
+        }
+    }
+}}
+
+/// The levels of the Arp log, from the most detailed to the most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warning,
+    Error,
+    Critical,
+    Fatal
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warning => "WARN",
+            LogLevel::Error => "ERROR",
+            LogLevel::Critical => "CRIT",
+            LogLevel::Fatal => "FATAL"
+        };
+        f.pad(name)
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> LogLevel {
+        match level {
+            log::Level::Trace => LogLevel::Trace,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Warn => LogLevel::Warning,
+            log::Level::Error => LogLevel::Error
+        }
+    }
+}
+
+/// A named logger, so that the messages of a component can be told apart in the Arp log.
+///
+/// Creating a logger is more expensive than writing to it, so components usually keep one
+/// in a static:
+///
+/// ```ignore
+/// lazy_static! {
+///     static ref LOG: Logger = Logger::new("MyCompany.MyApp.Io");
+/// }
+///
+/// plc_info!(logger: LOG, "Read {} ports", count);
+/// ```
+///
+/// Without the arp feature, messages are written to stdout, with the name of the logger.
+pub struct Logger {
+    name: String,
+    #[cfg(feature = "arp")]
+    raw: *mut c_void
+}
+
+// The Arp logger can be used from any thread.
+unsafe impl Send for Logger {}
+unsafe impl Sync for Logger {}
+
+impl Logger {
+    /// Creates a logger with the given name, e.g. "MyCompany.MyApp.Io".
+    pub fn new(name: &str) -> Logger {
+        #[cfg(feature = "arp")]
+        {
+            let text = c_string(name);
+            let raw_name = text.as_ptr();
+            let raw = unsafe {
+                cpp!([raw_name as "const char *"] -> *mut c_void as "void *" {
+                    return new Logger(Arp::String(raw_name));
+                })
+            };
+            Logger { name: name.to_string(), raw }
+        }
+        #[cfg(not(feature = "arp"))]
+        Logger { name: name.to_string() }
+    }
+
+    /// The name of the logger.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether messages at this level are written. Check this before formatting
+    /// an expensive message, or use the plc_log! macros, which do it for you.
+    pub fn is_enabled(&self, level: LogLevel) -> bool {
+        #[cfg(feature = "arp")]
+        {
+            let raw = self.raw;
+            let level = level as i32;
+            unsafe {
+                cpp!([raw as "Logger *", level as "int"] -> bool as "bool" {
+                    return raw->IsEnabled(ToLogLevel(level));
+                })
+            }
+        }
+        #[cfg(not(feature = "arp"))]
+        {
+            let _ = level;
+            true
+        }
+    }
+
+    /// Writes a message at the given level.
+    pub fn log(&self, level: LogLevel, msg: &str) {
+        #[cfg(feature = "arp")]
+        {
+            let text = c_string(msg);
+            let raw_text = text.as_ptr();
+            let raw = self.raw;
+            let level = level as i32;
+            unsafe {
+                cpp!([raw as "Logger *", level as "int", raw_text as "const char *"] {
+                    WriteToLogger(*raw, level, raw_text);
+                });
+            }
+        }
+        #[cfg(not(feature = "arp"))]
+        println!("{:<5} [{}] {}", level, self.name, msg);
+    }
+
+    /// Writes a message at the Trace level.
+    pub fn trace(&self, msg: &str) {
+        self.log(LogLevel::Trace, msg);
+    }
+
+    /// Writes a message at the Debug level.
+    pub fn debug(&self, msg: &str) {
+        self.log(LogLevel::Debug, msg);
+    }
+
+    /// Writes a message at the Info level.
+    pub fn info(&self, msg: &str) {
+        self.log(LogLevel::Info, msg);
+    }
+
+    /// Writes a message at the Warning level.
+    pub fn warning(&self, msg: &str) {
+        self.log(LogLevel::Warning, msg);
+    }
+
+    /// Writes a message at the Error level.
+    pub fn error(&self, msg: &str) {
+        self.log(LogLevel::Error, msg);
+    }
+
+    /// Writes a message at the Critical level.
+    pub fn critical(&self, msg: &str) {
+        self.log(LogLevel::Critical, msg);
+    }
+
+    /// Writes a message at the Fatal level.
+    pub fn fatal(&self, msg: &str) {
+        self.log(LogLevel::Fatal, msg);
+    }
+}
+
+#[cfg(feature = "arp")]
+impl Drop for Logger {
+    fn drop(&mut self) {
+        let raw = self.raw;
+        unsafe {
+            cpp!([raw as "Logger *"] {
+                delete raw;
+            });
+        }
+    }
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Logger({})", self.name)
+    }
+}
+
+/// Whether messages at this level are written to the root logger.
+pub fn is_enabled(level: LogLevel) -> bool {
+    #[cfg(feature = "arp")]
+    {
+        let level = level as i32;
+        unsafe {
+            cpp!([level as "int"] -> bool as "bool" {
+                return Arp::Log::IsEnabled(ToLogLevel(level));
+            })
+        }
+    }
+    #[cfg(not(feature = "arp"))]
+    {
+        let _ = level;
+        true
+    }
+}
+
+/// Writes a message to the root logger at the given level.
+pub fn log_at(level: LogLevel, msg: &str) {
+    #[cfg(feature = "arp")]
+    {
+        let text = c_string(msg);
+        let raw_text = text.as_ptr();
+        let level = level as i32;
+        unsafe {
+            cpp!([level as "int", raw_text as "const char *"] {
+                WriteToRoot(level, raw_text);
+            });
+        }
+    }
+    #[cfg(not(feature = "arp"))]
+    println!("{:<5} {}", level, msg);
+}
+
+// A NUL in the text would end the C string, so it's escaped instead.
+#[cfg(feature = "arp")]
+fn c_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "\\0")).unwrap_or_default()
+}
+
+/// Writes a formatted message at the given level, to the root logger or to a named logger.
+/// The message is only formatted if the level is enabled.
+///
+/// ```ignore
+/// plc_log!(LogLevel::Info, "Cycle {} took {} us", cycle, micros);
+/// plc_log!(logger: LOG, LogLevel::Info, "Cycle {} took {} us", cycle, micros);
+/// ```
+#[macro_export]
+macro_rules! plc_log {
+    (logger: $logger:expr, $level:expr, $($arg:tt)+) => {{
+        let logger: &$crate::Logger = &$logger;
+        let level = $level;
+        if logger.is_enabled(level) {
+            logger.log(level, &format!($($arg)+));
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::is_enabled(level) {
+            $crate::log_at(level, &format!($($arg)+));
+        }
+    }};
+}
+
+/// Writes a formatted message at the Trace level, like plc_log!.
+#[macro_export]
+macro_rules! plc_trace {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Trace, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Trace, $($arg)+) };
+}
+
+/// Writes a formatted message at the Debug level, like plc_log!.
+#[macro_export]
+macro_rules! plc_debug {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Debug, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Debug, $($arg)+) };
+}
+
+/// Writes a formatted message at the Info level, like plc_log!.
+#[macro_export]
+macro_rules! plc_info {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Info, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Info, $($arg)+) };
+}
+
+/// Writes a formatted message at the Warning level, like plc_log!.
+#[macro_export]
+macro_rules! plc_warning {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Warning, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Warning, $($arg)+) };
+}
+
+/// Writes a formatted message at the Error level, like plc_log!.
+#[macro_export]
+macro_rules! plc_error {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Error, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Error, $($arg)+) };
+}
+
+/// Writes a formatted message at the Critical level, like plc_log!.
+#[macro_export]
+macro_rules! plc_critical {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Critical, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Critical, $($arg)+) };
+}
+
+/// Writes a formatted message at the Fatal level, like plc_log!.
+#[macro_export]
+macro_rules! plc_fatal {
+    (logger: $logger:expr, $($arg:tt)+) => { $crate::plc_log!(logger: $logger, $crate::LogLevel::Fatal, $($arg)+) };
+    ($($arg:tt)+) => { $crate::plc_log!($crate::LogLevel::Fatal, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_log_level() {
+        let levels = [log::Level::Trace, log::Level::Debug, log::Level::Info, log::Level::Warn, log::Level::Error];
+        let expected = [LogLevel::Trace, LogLevel::Debug, LogLevel::Info, LogLevel::Warning, LogLevel::Error];
+        for (level, expected) in levels.iter().zip(expected.iter()) {
+            assert_eq!(LogLevel::from(*level), *expected);
+        }
+    }
+
+    #[test]
+    fn display_is_padded() {
+        assert_eq!(format!("{:<5}|", LogLevel::Info), "INFO |");
+        assert_eq!(format!("{:<5}|", LogLevel::Warning), "WARN |");
+        assert_eq!(format!("{:<5}|", LogLevel::Critical), "CRIT |");
+        assert_eq!(format!("{:>6}|", LogLevel::Error), " ERROR|");
+        assert_eq!(LogLevel::Fatal.to_string(), "FATAL");
+    }
+
+    #[cfg(not(feature = "arp"))]
+    #[test]
+    fn logger_name() {
+        let logger = Logger::new("MyCompany.MyApp.Io");
+        assert_eq!(logger.name(), "MyCompany.MyApp.Io");
+        assert_eq!(format!("{:?}", logger), "Logger(MyCompany.MyApp.Io)");
+        assert!(logger.is_enabled(LogLevel::Trace));
+    }
+
+    #[cfg(not(feature = "arp"))]
+    #[test]
+    fn macros_evaluate_the_level_once() {
+        use std::cell::Cell;
+
+        let logger = Logger::new("MyCompany.MyApp.Io");
+        let evaluated = Cell::new(0);
+        let level = || {
+            evaluated.set(evaluated.get() + 1);
+            LogLevel::Info
+        };
+        plc_log!(level(), "Read {} ports", 3);
+        plc_log!(logger: logger, level(), "Read {} ports", 3);
+        assert_eq!(evaluated.get(), 2);
+
+        plc_warning!("Cycle {} took {} us", 1, 1200);
+        plc_warning!(logger: logger, "Cycle {} took {} us", 1, 1200);
+        plc_fatal!(logger: &logger, "{{braces}} are written as they are");
+    }
+}
@@ -0,0 +1,200 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Lit, Meta, NestedMeta};
+
+// The direction(s) in which a field is transferred.
+enum Direction {
+    Input,
+    Output,
+    Both
+}
+
+// A field that is mapped onto a GDS port.
+struct PortField {
+    field: syn::Ident,
+    fb_io_system_name: String,
+    port_name: String,
+    direction: Direction
+}
+
+/// Derives plcnext::ProcessImage for a struct with named fields.
+///
+/// Each field that is mapped onto a GDS port carries one port attribute with
+/// the full port name, which is checked at compile time. The fieldbus I/O system
+/// name is the part of the port name before the first '/'.
+/// The field type must implement plcnext::PortValue.
+///
+/// ```ignore
+/// #[derive(ProcessImage)]
+/// struct Io {
+///     #[port("Arp.Io.AxlC/0.DI16", input)]
+///     inputs: u16,
+///     #[port("Arp.Io.AxlC/1.DO16", output)]
+///     outputs: u16,
+///     #[port("Arp.Io.AxlC/2.AO1")]
+///     setpoint: i16,
+///     // Fields without a port attribute are left alone.
+///     cycles: u64
+/// }
+/// ```
+///
+/// Fields marked 'input' are only read by read_all(), fields marked 'output'
+/// are only written by write_all(), and unmarked fields are read and written.
+/// All ports are read or written together, through a plcnext::PortBatch.
+#[proc_macro_derive(ProcessImage, attributes(port))]
+pub fn derive_process_image(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into()
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(input, "ProcessImage can only be derived for structs with named fields"))
+        },
+        _ => return Err(Error::new_spanned(input, "ProcessImage can only be derived for structs"))
+    };
+
+    let mut port_fields = Vec::new();
+    for field in fields {
+        let mut attrs = field.attrs.iter().filter(|a| a.path.is_ident("port"));
+        if let Some(attr) = attrs.next() {
+            port_fields.push(parse_port_attribute(field.ident.clone().unwrap(), attr)?);
+        }
+        // A field holds the value of one port, so a second attribute is a mistake.
+        if let Some(attr) = attrs.next() {
+            return Err(Error::new_spanned(attr, "a field can only be mapped onto one port"));
+        }
+    }
+
+    let inputs: Vec<&PortField> = port_fields.iter().filter(|p| !matches!(p.direction, Direction::Output)).collect();
+    let outputs: Vec<&PortField> = port_fields.iter().filter(|p| !matches!(p.direction, Direction::Input)).collect();
+
+    // The fields are decoded from and encoded into the snapshot of a PortBatch of the ports,
+    // at the index of their port in INPUT_PORTS or OUTPUT_PORTS.
+    let input_ports = inputs.iter().map(|p| port_tuple(p));
+    let output_ports = outputs.iter().map(|p| port_tuple(p));
+    let loads = inputs.iter().enumerate().map(|(index, p)| {
+        let field = &p.field;
+        quote! {
+            self.#field = batch.value(snapshot, #index)?;
+        }
+    });
+    let stores = outputs.iter().enumerate().map(|(index, p)| {
+        let field = &p.field;
+        quote! {
+            batch.set_value(snapshot, #index, &self.#field)?;
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::plcnext::ProcessImage for #name #ty_generics #where_clause {
+            const INPUT_PORTS: &'static [(&'static str, &'static str)] = &[#(#input_ports),*];
+            const OUTPUT_PORTS: &'static [(&'static str, &'static str)] = &[#(#output_ports),*];
+
+            #[allow(unused_variables)]
+            fn load(&mut self, batch: &::plcnext::PortBatch, snapshot: &::plcnext::PortSnapshot) -> ::plcnext::Result<()> {
+                #(#loads)*
+                Ok(())
+            }
+
+            #[allow(unused_variables)]
+            fn store(&self, batch: &::plcnext::PortBatch, snapshot: &mut ::plcnext::PortSnapshot) -> ::plcnext::Result<()> {
+                #(#stores)*
+                Ok(())
+            }
+        }
+    })
+}
+
+fn port_tuple(p: &PortField) -> TokenStream2 {
+    let PortField { fb_io_system_name, port_name, .. } = p;
+    quote! { (#fb_io_system_name, #port_name) }
+}
+
+// Parses #[port("Arp.Io.AxlC/0.DI16")], optionally followed by 'input' or 'output'.
+fn parse_port_attribute(field: syn::Ident, attr: &syn::Attribute) -> syn::Result<PortField> {
+    let list = match attr.parse_meta()? {
+        Meta::List(list) => list,
+        meta => return Err(Error::new_spanned(meta, "expected #[port(\"<port name>\")]"))
+    };
+
+    let mut nested = list.nested.iter();
+    let port_name = match nested.next() {
+        Some(NestedMeta::Lit(Lit::Str(s))) => s,
+        _ => return Err(Error::new_spanned(&list, "expected the port name as the first argument, e.g. #[port(\"Arp.Io.AxlC/0.DI16\")]"))
+    };
+
+    let direction = match nested.next() {
+        None => Direction::Both,
+        Some(NestedMeta::Meta(Meta::Path(path))) if path.is_ident("input") => Direction::Input,
+        Some(NestedMeta::Meta(Meta::Path(path))) if path.is_ident("output") => Direction::Output,
+        Some(other) => return Err(Error::new_spanned(other, "expected 'input' or 'output'"))
+    };
+    if let Some(other) = nested.next() {
+        return Err(Error::new_spanned(other, "unexpected argument"));
+    }
+
+    // The fieldbus I/O system name is the part of the port name before the first '/'.
+    let value = port_name.value();
+    let fb_io_system_name = match validate_port_name(&value) {
+        Ok(fb_io_system_name) => fb_io_system_name.to_string(),
+        Err(reason) => return Err(Error::new_spanned(port_name, format!("invalid port name \"{}\": {}", value, reason)))
+    };
+
+    Ok(PortField { field, fb_io_system_name, port_name: value, direction })
+}
+
+// Checks a port name against the grammar of plcnext::PortName, which this crate can't use,
+// so that a misspelt name is found at compile time. Returns the fieldbus I/O system name.
+fn validate_port_name(name: &str) -> Result<&str, String> {
+    let separator = name.find('/').ok_or("expected \"<I/O system>/<port path>\"")?;
+    let (component, path) = (&name[..separator], &name[separator + 1..]);
+
+    // The component segments are identifiers, e.g. "Arp", "Io" or "AxlC".
+    for (position, segment) in component.split('.').enumerate() {
+        if segment.is_empty() {
+            return Err(format!("component segment {} is empty", position + 1));
+        }
+        if let Some(c) = segment.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_')) {
+            return Err(format!("invalid character '{}' in component segment \"{}\"", c, segment));
+        }
+    }
+
+    // The path segments are module numbers, device or variable names, optionally prefixed
+    // with '~' and followed by array indices, e.g. "0", "axc-f-2152", "~DI8" or "Values[3]".
+    for (position, segment) in path.split('.').enumerate() {
+        let (base, indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        let base = base.strip_prefix('~').unwrap_or(base);
+        if base.is_empty() {
+            return Err(format!("port path segment {} is empty", position + 1));
+        }
+        if let Some(c) = base.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')) {
+            return Err(format!("invalid character '{}' in port path segment \"{}\"", c, segment));
+        }
+        let mut rest = indices;
+        while !rest.is_empty() {
+            let index = match (rest.starts_with('['), rest.find(']')) {
+                (true, Some(close)) => {
+                    let index = &rest[1..close];
+                    rest = &rest[close + 1..];
+                    index
+                },
+                _ => return Err(format!("malformed array index in port path segment \"{}\"", segment))
+            };
+            if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+                return Err(format!("invalid array index '{}' in port path segment \"{}\"", index, segment));
+            }
+        }
+    }
+    Ok(component)
+}
@@ -0,0 +1,7 @@
+// The derive must reject mistakes in the port attributes with an error at the attribute,
+// instead of generating code that fails at run time.
+#[test]
+fn port_attribute_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
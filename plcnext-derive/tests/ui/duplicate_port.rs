@@ -0,0 +1,10 @@
+use plcnext_derive::ProcessImage;
+
+#[derive(ProcessImage)]
+struct Io {
+    #[port("Arp.Io.AxlC/0.DI16", input)]
+    #[port("Arp.Io.AxlC/1.DI16", input)]
+    inputs: u16
+}
+
+fn main() {}
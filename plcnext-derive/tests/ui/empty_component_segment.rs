@@ -0,0 +1,9 @@
+use plcnext_derive::ProcessImage;
+
+#[derive(ProcessImage)]
+struct Io {
+    #[port("Arp..AxlC/0.DI16")]
+    inputs: u16
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use plcnext_derive::ProcessImage;
+
+#[derive(ProcessImage)]
+struct Io {
+    #[port("Arp.Plc.Eclr/MainInstance.Values[x]")]
+    value: i16
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use plcnext_derive::ProcessImage;
+
+#[derive(ProcessImage)]
+struct Io {
+    #[port("Arp.Io.AxlC/0.DI 16")]
+    inputs: u16
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use plcnext_derive::ProcessImage;
+
+#[derive(ProcessImage)]
+struct Io {
+    #[port("Arp.Io.AxlC/0.DI16", inout)]
+    inputs: u16
+}
+
+fn main() {}
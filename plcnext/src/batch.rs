@@ -0,0 +1,357 @@
+use crate::error::Result;
+use crate::error::ParameterError;
+use crate::gds::GdsBuffer;
+use crate::recorder::{recorder, RecordKind};
+use crate::registry::{PortHandle, SharedBuffer};
+use crate::value::{self, PortValue};
+use crate::SystemHandle;
+
+use std::ops::Range;
+use std::sync::{Arc, MutexGuard};
+
+// The ports in a batch that share one GDS buffer.
+struct BufferGroup {
+    gds_buffer: SharedBuffer,
+    // Indices into PortBatch::ports
+    ports: Vec<usize>
+}
+
+/// A set of ports that are read or written together.
+///
+/// Ports are grouped by the GDS buffer that contains them, and each buffer
+/// is locked once per read or write. All buffers are locked before any data
+/// is copied, so a read returns a consistent snapshot of every port in the
+/// batch, and a write makes all new values visible at the same time.
+///
+/// Grouping is done when the batch is created, so a batch should be
+/// created once and then used in every cycle.
+pub struct PortBatch {
+    ports: Vec<Arc<PortHandle>>,
+    groups: Vec<BufferGroup>,
+    // The location of each port's data in a snapshot
+    ranges: Vec<Range<usize>>
+}
+
+impl PortBatch {
+    /// Creates a batch from ports in the port registry.
+    pub fn new(ports: Vec<Arc<PortHandle>>) -> PortBatch {
+        let mut groups: Vec<BufferGroup> = Vec::new();
+        let mut ranges = Vec::with_capacity(ports.len());
+        let mut snapshot_size = 0;
+        for (index, port) in ports.iter().enumerate() {
+            match groups.iter_mut().find(|g| Arc::ptr_eq(&g.gds_buffer, port.shared_buffer())) {
+                Some(group) => group.ports.push(index),
+                None => groups.push(BufferGroup { gds_buffer: port.shared_buffer().clone(), ports: vec![index] })
+            }
+            ranges.push(snapshot_size..snapshot_size + port.size());
+            snapshot_size += port.size();
+        }
+
+        // Buffers are always locked in the same order, so that two batches
+        // with overlapping buffers can't deadlock.
+        groups.sort_by_key(|g| Arc::as_ptr(&g.gds_buffer) as usize);
+
+        PortBatch { ports, groups, ranges }
+    }
+
+    /// Creates a batch from port names, resolving the ports in the port registry.
+    /// * 'system' - The handle returned by load()
+    /// * 'ports' - Pairs of fieldbus I/O system name and port name, e.g. ("Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16")
+    pub fn resolve(system: &SystemHandle, ports: &[(&str, &str)]) -> Result<PortBatch> {
+        let registry = crate::registry::port_registry(system);
+        let handles = ports.iter()
+            .map(|(fb_io_system_name, port_name)| registry.get(fb_io_system_name, port_name))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PortBatch::new(handles))
+    }
+
+    /// The ports in the batch, in the order they were given.
+    pub fn ports(&self) -> &[Arc<PortHandle>] {
+        &self.ports
+    }
+
+    /// The number of distinct GDS buffers that are locked for each read or write.
+    pub fn buffer_count(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Reads all ports in the batch into a new snapshot.
+    pub fn read(&self) -> Result<PortSnapshot> {
+        let mut snapshot = self.snapshot();
+        self.read_into(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    /// Creates a snapshot of this batch with all data zero, without reading the ports,
+    /// e.g. to fill in and write with write_snapshot().
+    pub fn snapshot(&self) -> PortSnapshot {
+        let size = self.ranges.last().map_or(0, |r| r.end);
+        PortSnapshot { data: vec![0; size], ranges: self.ranges.clone() }
+    }
+
+    /// Decodes the typed value of the port at 'index' from a snapshot created by this batch.
+    /// Panics if index is out of range.
+    pub fn value<T: PortValue>(&self, snapshot: &PortSnapshot, index: usize) -> Result<T> {
+        value::check_size::<T>(&self.ports[index])?;
+        Ok(T::from_port_bytes(snapshot.get(index)))
+    }
+
+    /// Encodes a typed value into the data of the port at 'index' in a snapshot created by this batch.
+    /// Panics if index is out of range.
+    pub fn set_value<T: PortValue>(&self, snapshot: &mut PortSnapshot, index: usize, value: &T) -> Result<()> {
+        value::check_size::<T>(&self.ports[index])?;
+        value.to_port_bytes(snapshot.get_mut(index))
+    }
+
+    /// Reads all ports in the batch into an existing snapshot, which must
+    /// have been created by this batch. The snapshot's memory is reused,
+    /// so this is the better choice in a cyclic task.
+    pub fn read_into(&self, snapshot: &mut PortSnapshot) -> Result<()> {
+        if snapshot.ranges != self.ranges {
+            return Err(ParameterError::ForeignSnapshot.into());
+        }
+
+        let mut buffers = self.lock_buffers()?;
+        let pages = buffers.iter_mut()
+            .map(|b| b.read())
+            .collect::<Result<Vec<_>>>()?;
+
+        for (group, page) in self.groups.iter().zip(pages.iter()) {
+            for &index in &group.ports {
+                let port = &self.ports[index];
+                let data = page.port(&port.info());
+                snapshot.data[self.ranges[index].clone()].copy_from_slice(data);
+                recorder().record(RecordKind::Read, port.fb_io_system_name(), port.port_name(), data);
+            }
+        }
+
+        // Unlock all buffers, returning the first error
+        first_error(pages.into_iter().map(|p| p.end()))
+    }
+
+    /// Writes a value to every port in the batch.
+    /// 'values' must hold one value for each port, in the order of the ports,
+    /// and each value must be the size of its port.
+    pub fn write(&self, values: &[&[u8]]) -> Result<()> {
+        // Check everything before locking, so that nothing is written if any value is wrong.
+        if values.len() != self.ports.len() {
+            return Err(ParameterError::ValueCount { expected: self.ports.len(), actual: values.len() }.into());
+        }
+        for (port, value) in self.ports.iter().zip(values) {
+            port.check_len(value.len())?;
+        }
+        self.write_values(|index| values[index])
+    }
+
+    /// Writes the data in a snapshot, e.g. one returned by read() and then modified,
+    /// to every port in the batch.
+    pub fn write_snapshot(&self, snapshot: &PortSnapshot) -> Result<()> {
+        if snapshot.ranges != self.ranges {
+            return Err(ParameterError::ForeignSnapshot.into());
+        }
+        self.write_values(|index| snapshot.get(index))
+    }
+
+    // Writes the value returned by 'value' for each port index, once all buffers are locked.
+    // The size of each value has already been checked.
+    fn write_values<'v, F: Fn(usize) -> &'v [u8]>(&self, value: F) -> Result<()> {
+        let mut buffers = self.lock_buffers()?;
+        let mut pages = buffers.iter_mut()
+            .map(|b| b.write())
+            .collect::<Result<Vec<_>>>()?;
+
+        for (group, page) in self.groups.iter().zip(pages.iter_mut()) {
+            for &index in &group.ports {
+                let port = &self.ports[index];
+                page.port_mut(&port.info()).copy_from_slice(value(index));
+                recorder().record(RecordKind::Write, port.fb_io_system_name(), port.port_name(), value(index));
+            }
+        }
+
+        // Unlock all buffers, returning the first error
+        first_error(pages.into_iter().map(|p| p.end()))
+    }
+
+    // Locks every buffer in the batch, after checking that all ports are still valid.
+    fn lock_buffers(&self) -> Result<Vec<MutexGuard<'_, GdsBuffer>>> {
+        let buffers: Vec<_> = self.groups.iter()
+            .map(|g| g.gds_buffer.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+        for port in &self.ports {
+            port.check_valid()?;
+        }
+        Ok(buffers)
+    }
+}
+
+// Returns the first error, after running every operation.
+fn first_error<I: Iterator<Item = Result<()>>>(results: I) -> Result<()> {
+    let mut first = Ok(());
+    for result in results {
+        if first.is_ok() {
+            first = result;
+        }
+    }
+    first
+}
+
+/// The data of every port in a batch, copied under a single lock per GDS buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortSnapshot {
+    data: Vec<u8>,
+    ranges: Vec<Range<usize>>
+}
+
+impl PortSnapshot {
+    /// The number of ports in the snapshot.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Returns true if the snapshot has no ports.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The data of the port at 'index' in the batch.
+    /// Panics if index is out of range.
+    pub fn get(&self, index: usize) -> &[u8] {
+        &self.data[self.ranges[index].clone()]
+    }
+
+    /// The data of the port at 'index' in the batch, for modification
+    /// before the snapshot is written back with PortBatch::write_snapshot().
+    /// Panics if index is out of range.
+    pub fn get_mut(&mut self, index: usize) -> &mut [u8] {
+        &mut self.data[self.ranges[index].clone()]
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::error::{LifecycleError, PlcnextError};
+    use crate::layout::PortDataType;
+    use crate::simulation::{self, simulation, PortDirection, SimulatedPort};
+    use crate::PlcOperation;
+
+    use std::thread;
+
+    const DI16: (&str, &str) = ("Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16");
+    const DI8: (&str, &str) = ("Arp.Io.AxlC", "Arp.Io.AxlC/1.DI8");
+    const DO16: (&str, &str) = ("Arp.Io.AxlC", "Arp.Io.AxlC/2.DO16");
+    const DO32: (&str, &str) = ("Arp.Io.AxlC", "Arp.Io.AxlC/3.DO32");
+
+    // Configures an input and an output frame, with an empty port registry.
+    fn configure() -> SystemHandle {
+        simulation().configure(&[
+            SimulatedPort::new(DI16.1, PortDirection::Input, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new(DI8.1, PortDirection::Input, PortDataType::UInt8).unwrap(),
+            SimulatedPort::new(DO16.1, PortDirection::Output, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new(DO32.1, PortDirection::Output, PortDataType::UInt32).unwrap()
+        ]).unwrap();
+        simulation().trigger(PlcOperation::Unload);
+        SystemHandle::new()
+    }
+
+    #[test]
+    fn ports_are_grouped_by_buffer() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        simulation().set_input(DI16.1, &[1, 2]).unwrap();
+        simulation().set_input(DI8.1, &[3]).unwrap();
+        crate::read_from_axio_to_gds(&system, 100).unwrap();
+
+        // The ports keep their order, but each frame is locked once.
+        let batch = PortBatch::resolve(&system, &[DI16, DO16, DI8]).unwrap();
+        assert_eq!(batch.buffer_count(), 2);
+        assert_eq!(batch.ports().iter().map(|p| p.port_name()).collect::<Vec<_>>(), [DI16.1, DO16.1, DI8.1]);
+        let snapshot = batch.read().unwrap();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!((snapshot.get(0), snapshot.get(1), snapshot.get(2)), (&[1u8, 2][..], &[0u8, 0][..], &[3u8][..]));
+        assert_eq!(PortBatch::resolve(&system, &[DI16, DI8]).unwrap().buffer_count(), 1);
+        assert!(PortBatch::new(Vec::new()).read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn typed_values_and_snapshots() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let batch = PortBatch::resolve(&system, &[DO16, DO32]).unwrap();
+
+        let mut snapshot = batch.snapshot();
+        batch.set_value(&mut snapshot, 0, &0x1234u16).unwrap();
+        batch.set_value(&mut snapshot, 1, &7u32).unwrap();
+        assert!(batch.set_value(&mut snapshot, 1, &7u16).is_err());
+        batch.write_snapshot(&snapshot).unwrap();
+
+        let mut read = batch.snapshot();
+        batch.read_into(&mut read).unwrap();
+        assert_eq!(read, snapshot);
+        assert_eq!(batch.value::<u16>(&read, 0).unwrap(), 0x1234);
+        assert_eq!(batch.value::<u32>(&read, 1).unwrap(), 7);
+
+        batch.write(&[&[1, 2], &[3, 4, 5, 6]]).unwrap();
+        assert_eq!(batch.read().unwrap().get(1), [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn nothing_is_written_if_a_value_is_wrong() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let batch = PortBatch::resolve(&system, &[DO16, DO32]).unwrap();
+        batch.write(&[&[1, 2], &[3, 4, 5, 6]]).unwrap();
+
+        match batch.write(&[&[9, 9]]) {
+            Err(PlcnextError::Parameter(ParameterError::ValueCount { expected: 2, actual: 1 })) => {},
+            other => panic!("Expected a value count error, got {:?}", other)
+        }
+        assert!(batch.write(&[&[9, 9], &[9, 9]]).is_err());
+        let other = PortBatch::resolve(&system, &[DO16]).unwrap();
+        match batch.write_snapshot(&other.snapshot()) {
+            Err(PlcnextError::Parameter(ParameterError::ForeignSnapshot)) => {},
+            other => panic!("Expected a foreign snapshot error, got {:?}", other)
+        }
+        assert!(batch.read_into(&mut other.snapshot()).is_err());
+        assert_eq!(batch.read().unwrap().get(0), [1, 2]);
+    }
+
+    #[test]
+    fn invalidated_ports_are_not_accessed() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let batch = PortBatch::resolve(&system, &[DI16, DO16]).unwrap();
+        simulation().trigger(PlcOperation::Reset);
+        match batch.read() {
+            Err(PlcnextError::Lifecycle(LifecycleError::HandleInvalidated { port_name })) => assert_eq!(port_name, DI16.1),
+            other => panic!("Expected an invalidated handle, got {:?}", other)
+        }
+        assert!(batch.write(&[&[1, 2], &[3, 4]]).is_err());
+        // The buffers were unlocked again, so new handles can use them.
+        PortBatch::resolve(&system, &[DI16, DO16]).unwrap().write(&[&[1, 2], &[3, 4]]).unwrap();
+    }
+
+    #[test]
+    fn reads_are_consistent_with_writes() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let writer = PortBatch::resolve(&system, &[DO16, DO32]).unwrap();
+        let reader = PortBatch::resolve(&system, &[DO32, DO16]).unwrap();
+
+        // Both ports are written together, so a reader never sees one without the other.
+        let thread = thread::spawn(move || {
+            let mut snapshot = writer.snapshot();
+            for n in 0..500u16 {
+                writer.set_value(&mut snapshot, 0, &n).unwrap();
+                writer.set_value(&mut snapshot, 1, &u32::from(n)).unwrap();
+                writer.write_snapshot(&snapshot).unwrap();
+            }
+        });
+        let mut snapshot = reader.snapshot();
+        for _ in 0..500 {
+            reader.read_into(&mut snapshot).unwrap();
+            assert_eq!(reader.value::<u32>(&snapshot, 0).unwrap(), u32::from(reader.value::<u16>(&snapshot, 1).unwrap()));
+        }
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+// Makes signing keys and license files for plcnext::License, offline in the back office.
+//
+// plcnext-license keygen <secret-key-file>
+// plcnext-license public-key <secret-key-file>
+// plcnext-license sign <secret-key-file> <device-id> [--feature <name>]... [--expires <YYYY-MM-DD>] [--output <file>]
+// plcnext-license verify <public-key> <device-id> <license-file>
+
+use plcnext::{License, UniqueHardwareId};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process;
+use std::time::SystemTime;
+
+const USAGE: &str = "\
+Usage:
+  plcnext-license keygen <secret-key-file>
+      Makes a new secret key, and prints the public key to embed in the application.
+  plcnext-license public-key <secret-key-file>
+      Prints the public key that belongs to a secret key.
+  plcnext-license sign <secret-key-file> <device-id> [--feature <name>]... [--expires <YYYY-MM-DD>] [--output <file>]
+      Makes a license for a controller, with the given features and last day.
+      The device id is the unique hardware id in hex or base64. Without --expires the license doesn't expire.
+  plcnext-license verify <public-key> <device-id> <license-file>
+      Checks a license like the application does.";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["keygen", secret_key_file] => keygen(secret_key_file),
+        ["public-key", secret_key_file] => read_secret_key(secret_key_file).map(|key| print_public_key(&key)),
+        ["sign", secret_key_file, device_id, options @ ..] => sign(secret_key_file, device_id, options),
+        ["verify", public_key, device_id, license_file] => verify(public_key, device_id, license_file),
+        _ => Err(USAGE.to_string())
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+fn keygen(secret_key_file: &str) -> Result<(), String> {
+    let mut secret_key = [0u8; 32];
+    getrandom::getrandom(&mut secret_key).map_err(|e| format!("Failed to get random bytes: {}", e))?;
+
+    // The secret key must not replace an existing one, and only its owner may read it.
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(secret_key_file).map_err(|e| format!("Failed to create {}: {}", secret_key_file, e))?;
+    writeln!(file, "{}", to_hex(&secret_key)).map_err(|e| format!("Failed to write {}: {}", secret_key_file, e))?;
+
+    println!("Wrote the secret key to {}. Keep it safe: anyone who has it can make licenses.", secret_key_file);
+    print_public_key(&secret_key);
+    Ok(())
+}
+
+fn print_public_key(secret_key: &[u8; 32]) {
+    let public_key = License::public_key(secret_key);
+    println!("Public key: {}", to_hex(&public_key));
+    let bytes: Vec<String> = public_key.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+    println!("const LICENSE_PUBLIC_KEY: [u8; 32] = [{}];", bytes.join(", "));
+}
+
+fn sign(secret_key_file: &str, device_id: &str, options: &[&str]) -> Result<(), String> {
+    let secret_key = read_secret_key(secret_key_file)?;
+    let device_id: UniqueHardwareId = device_id.parse().map_err(|e| format!("Invalid device id: {}", e))?;
+
+    let mut features = Vec::new();
+    let mut expires = None;
+    let mut output = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(|| format!("{} needs a value\n\n{}", option, USAGE))?;
+        match *option {
+            "--feature" => features.push(*value),
+            "--expires" => expires = Some(*value),
+            "--output" => output = Some(*value),
+            other => return Err(format!("Unknown option {}\n\n{}", other, USAGE))
+        }
+    }
+
+    let license = License::sign(&device_id, &features, expires, &secret_key).map_err(|e| e.to_string())?;
+    match output {
+        Some(path) => fs::write(path, license).map_err(|e| format!("Failed to write {}: {}", path, e)),
+        None => {
+            print!("{}", license);
+            Ok(())
+        }
+    }
+}
+
+fn verify(public_key: &str, device_id: &str, license_file: &str) -> Result<(), String> {
+    let public_key = License::parse_key(public_key).map_err(|e| format!("Invalid public key: {}", e))?;
+    let device_id: UniqueHardwareId = device_id.parse().map_err(|e| format!("Invalid device id: {}", e))?;
+    let text = fs::read_to_string(license_file).map_err(|e| format!("Failed to read {}: {}", license_file, e))?;
+
+    let license = License::verify_for(&text, &public_key, &device_id, SystemTime::now()).map_err(|e| e.to_string())?;
+    println!("The license is valid for device {}", license.device_id());
+    println!("Features: {}", license.features().join(", "));
+    println!("Expires: {}", license.expires().unwrap_or("never"));
+    Ok(())
+}
+
+fn read_secret_key(path: &str) -> Result<[u8; 32], String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    License::parse_key(text.trim()).map_err(|e| format!("Invalid secret key in {}: {}", path, e))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
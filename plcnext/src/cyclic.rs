@@ -0,0 +1,524 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::events::{self, SubscriptionId};
+use crate::fault::{FaultPolicy, FaultReason};
+use crate::image::{ImageBatch, ProcessImage};
+use crate::realtime::RealtimeConfig;
+use crate::recorder;
+use crate::state::{self, PlcState};
+use crate::timing::{CycleStats, CycleStatsHandle, CycleTimes};
+use crate::{PlcOperation, SystemHandle};
+
+use std::convert::TryFrom;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How long Unload waits for the task to write the substitute values, by default.
+const DEFAULT_UNLOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs the cyclic I/O loop of an application:
+/// 1. Transfer inputs from the Axioline bus to the GDS
+/// 2. Read the input image
+/// 3. Call the cycle logic with the input and output images
+/// 4. Write the output image
+/// 5. Transfer outputs from the GDS to the Axioline bus
+///
+/// The ports of each image are looked up once, and then read or written
+/// through a PortBatch, with one lock per GDS buffer.
+///
+/// Cycles are scheduled at absolute deadlines, one period apart, so that
+/// the time taken by each cycle doesn't add up to a drift. If a cycle takes
+/// longer than a period, the missed deadlines are skipped and the next cycle
+/// starts at the next deadline after the overrun.
+///
+/// The duration of each phase of every cycle is measured, along with the
+/// jitter of the start of each cycle and the number of overruns.
+/// The statistics can be read from any thread through stats_handle().
+///
+/// The task only runs cycles while the PLC is running. It starts on
+/// StartCold, StartWarm or StartHot, pauses on Stop, and finishes on Unload
+/// or when it is asked to stop.
+///
+/// The fault policy is applied when the PLC is stopped while the task is running,
+/// when the PLC is unloaded, and when the task ends because the cycle logic
+/// panicked or overran too often. The substitute values are transferred
+/// to the Axioline bus straight away. Stop and Unload are handled by the task
+/// between two cycles. Unload waits until the task has done so, for up to
+/// the unload timeout, so that the outputs are safe before the PLC is unloaded.
+pub struct CyclicTask<I, O> {
+    system: SystemHandle,
+    period: Duration,
+    axio_timeout: u32,
+    inputs: I,
+    outputs: O,
+    input_batch: ImageBatch,
+    output_batch: ImageBatch,
+    stop: Arc<AtomicBool>,
+    stats: CycleStatsHandle,
+    fault_policy: FaultPolicy,
+    unload_timeout: Duration,
+    realtime: Option<RealtimeConfig>
+}
+
+impl<I: ProcessImage, O: ProcessImage> CyclicTask<I, O> {
+    /// Creates a task.
+    /// * 'system' - The handle returned by load()
+    /// * 'period' - The time from the start of one cycle to the start of the next
+    /// * 'inputs' - The image that is read at the start of each cycle
+    /// * 'outputs' - The image that is written at the end of each cycle
+    pub fn new(system: &SystemHandle, period: Duration, inputs: I, outputs: O) -> CyclicTask<I, O> {
+        CyclicTask {
+            system: *system,
+            period,
+            axio_timeout: 0,
+            inputs,
+            outputs,
+            input_batch: ImageBatch::new(I::INPUT_PORTS),
+            output_batch: ImageBatch::new(O::OUTPUT_PORTS),
+            stop: Arc::new(AtomicBool::new(false)),
+            stats: CycleStatsHandle::new(CycleStats::new(period, period / 10)),
+            fault_policy: FaultPolicy::new(),
+            unload_timeout: DEFAULT_UNLOAD_TIMEOUT,
+            realtime: None
+        }
+    }
+
+    /// Sets the timeout for the transfers to and from the Axioline bus.
+    /// * 'timeout' - Timeout in milliseconds. If zero, which is the default,
+    ///   the task blocks until each transfer has been processed.
+    pub fn with_axio_timeout(mut self, timeout: u32) -> CyclicTask<I, O> {
+        self.axio_timeout = timeout;
+        self
+    }
+
+    /// Sets the range of jitter covered by each bucket of the jitter histogram.
+    /// The default is a tenth of the period.
+    /// This clears the statistics.
+    pub fn with_jitter_bucket_width(mut self, width: Duration) -> CyclicTask<I, O> {
+        self.stats = CycleStatsHandle::new(CycleStats::new(self.period, width));
+        self
+    }
+
+    /// Sets the fault policy. By default, no substitute values are written,
+    /// so outputs keep their last values when a fault occurs.
+    pub fn with_fault_policy(mut self, fault_policy: FaultPolicy) -> CyclicTask<I, O> {
+        self.fault_policy = fault_policy;
+        self
+    }
+
+    /// Sets how long Unload is held up while the task finishes its cycle and writes
+    /// the substitute values of the fault policy. The default is one second.
+    /// If the task takes longer, e.g. because a transfer blocks, the PLC is unloaded
+    /// anyway, and the substitute values may not reach the bus.
+    pub fn with_unload_timeout(mut self, timeout: Duration) -> CyclicTask<I, O> {
+        self.unload_timeout = timeout;
+        self
+    }
+
+    /// Sets the real-time settings, which are applied to the thread that runs
+    /// the task when run() is called. By default, the thread is not changed.
+    pub fn with_realtime(mut self, realtime: RealtimeConfig) -> CyclicTask<I, O> {
+        self.realtime = Some(realtime);
+        self
+    }
+
+    /// The time from the start of one cycle to the start of the next.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// The input image, as read in the last cycle.
+    pub fn inputs(&self) -> &I {
+        &self.inputs
+    }
+
+    /// The output image, as written in the last cycle.
+    pub fn outputs(&self) -> &O {
+        &self.outputs
+    }
+
+    /// Returns a handle that stops the task from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle { stop: self.stop.clone() }
+    }
+
+    /// Returns a handle to the timing statistics, which can be read from any thread.
+    pub fn stats_handle(&self) -> CycleStatsHandle {
+        self.stats.clone()
+    }
+
+    /// A copy of the timing statistics so far.
+    pub fn stats(&self) -> CycleStats {
+        self.stats.snapshot()
+    }
+
+    /// Runs the task on the calling thread, calling 'logic' once per cycle.
+    /// Returns when the PLC is unloaded or the task is stopped, or with the
+    /// first error from a transfer or from reading or writing an image.
+    /// If the cycle logic panics, or the cycle overruns too often, the fault policy
+    /// is applied and a Fault error is returned.
+    pub fn run<F: FnMut(&I, &mut O)>(&mut self, mut logic: F) -> Result<()> {
+        if let Some(realtime) = &self.realtime {
+            realtime.apply()?;
+        }
+
+        // The operation handler passes the operations on, and the task handles them between cycles.
+        // On Unload, the handler waits until the task has written the substitute values,
+        // so that they reach the bus before the ports are invalidated and the PLC is unloaded.
+        // Subscribe before checking the state, so that no operation is missed in between.
+        let (sender, operations) = mpsc::channel::<(PlcOperation, Option<SyncSender<()>>)>();
+        let unload_timeout = self.unload_timeout;
+        let _subscription = Subscription(events::subscribe(move |operation| {
+            if operation != PlcOperation::Unload {
+                let _ = sender.send((operation, None));
+                return;
+            }
+            let (done, acknowledged) = mpsc::sync_channel(1);
+            if sender.send((operation, Some(done))).is_ok() {
+                if let Err(RecvTimeoutError::Timeout) = acknowledged.recv_timeout(unload_timeout) {
+                    log::error!("The cyclic task didn't write the substitute values for Unload within {:?}", unload_timeout);
+                }
+            }
+        }));
+        let mut running = state::plc_state().state() == PlcState::Running;
+
+        let mut deadline = Instant::now();
+        let mut consecutive_overruns = 0;
+        loop {
+            // Wait for the next deadline, or while paused for up to a period, so that a stop request
+            // is seen in time. Operations are handled as soon as they arrive.
+            let mut wait_until = if running { deadline } else { Instant::now() + self.period };
+            while let Ok((operation, done)) = operations.recv_timeout(wait_until.saturating_duration_since(Instant::now())) {
+                match operation {
+                    PlcOperation::StartCold | PlcOperation::StartWarm | PlcOperation::StartHot if !running => {
+                        running = true;
+                        deadline = Instant::now();
+                        wait_until = deadline;
+                    },
+                    PlcOperation::Stop if running => {
+                        running = false;
+                        self.apply_fault_policy(&FaultReason::Stop);
+                    },
+                    PlcOperation::Unload => {
+                        self.apply_fault_policy(&FaultReason::Unload);
+                        if let Some(done) = done {
+                            let _ = done.send(());
+                        }
+                        return Ok(());
+                    },
+                    _ => {}
+                }
+            }
+            if self.stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if !running {
+                continue;
+            }
+
+            let times = match self.cycle(&mut logic, deadline) {
+                Ok(times) => times,
+                Err(PlcnextError::Fault(reason)) => return Err(self.fault(reason)),
+                Err(error) => return Err(error)
+            };
+            self.stats.record(&times);
+
+            // Counted here, so that the statistics don't have to be copied in every cycle.
+            consecutive_overruns = if times.overrun { consecutive_overruns + 1 } else { 0 };
+            if matches!(self.fault_policy.max_consecutive_overruns(), Some(max) if consecutive_overruns >= max) {
+                return Err(self.fault(FaultReason::Overruns { consecutive: consecutive_overruns }));
+            }
+
+            deadline = next_deadline(deadline, self.period, Instant::now());
+        }
+    }
+
+    /// Runs the task on a new thread.
+    /// The task and the result of run() are returned by CyclicTaskHandle::join().
+    /// If the task has real-time settings that pre-fault the stack,
+    /// the thread's stack is made large enough.
+    pub fn spawn<F>(mut self, logic: F) -> std::io::Result<CyclicTaskHandle<I, O>>
+        where I: 'static + Send, O: 'static + Send, F: 'static + FnMut(&I, &mut O) + Send {
+
+        let stop = self.stop_handle();
+        let mut builder = thread::Builder::new().name("plcnext-cyclic-task".to_string());
+        if let Some(bytes) = self.realtime.as_ref().and_then(|r| r.prefault_stack()) {
+            builder = builder.stack_size(bytes + crate::realtime::STACK_MARGIN);
+        }
+        let thread = builder
+            .spawn(move || {
+                let result = self.run(logic);
+                (self, result)
+            })?;
+        Ok(CyclicTaskHandle { stop, thread })
+    }
+
+    // Runs a single cycle, that was due to start at 'deadline', and measures its timing.
+    fn cycle<F: FnMut(&I, &mut O)>(&mut self, logic: &mut F, deadline: Instant) -> Result<CycleTimes> {
+        let start = Instant::now();
+        recorder::recorder().next_cycle();
+        crate::read_from_axio_to_gds(&self.system, self.axio_timeout)?;
+        self.input_batch.read(&self.system, &mut self.inputs)?;
+
+        // A panic in the logic is caught, so that the fault policy can be applied.
+        let read = Instant::now();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| logic(&self.inputs, &mut self.outputs))) {
+            let message = events::panic_message(&*payload).to_string();
+            return Err(PlcnextError::Fault(FaultReason::Panic { message }));
+        }
+
+        let logic_done = Instant::now();
+        self.output_batch.write(&self.system, &self.outputs)?;
+        crate::write_from_gds_to_axio(&self.system, self.axio_timeout)?;
+
+        let end = Instant::now();
+        Ok(CycleTimes {
+            jitter: start.saturating_duration_since(deadline),
+            read: read - start,
+            logic: logic_done - read,
+            write: end - logic_done,
+            overrun: end > deadline + self.period
+        })
+    }
+
+    // Applies the fault policy, and returns the error that ends the task.
+    fn fault(&self, reason: FaultReason) -> PlcnextError {
+        self.apply_fault_policy(&reason);
+        PlcnextError::Fault(reason)
+    }
+
+    // Writes the substitute values of the fault policy, and transfers them to the bus.
+    // Errors are only logged, because the fault is reported to the caller anyway.
+    fn apply_fault_policy(&self, reason: &FaultReason) {
+        let result = self.fault_policy.apply(&self.system, reason)
+            .and_then(|_| crate::write_from_gds_to_axio(&self.system, self.axio_timeout));
+        if let Err(error) = result {
+            log::error!("Fault reaction for {} failed: {}", reason, error);
+        }
+    }
+}
+
+// Unsubscribes the operation handler when the task ends.
+struct Subscription(SubscriptionId);
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        events::unsubscribe(self.0);
+    }
+}
+
+// The first deadline after 'now', counting whole periods from the previous deadline.
+// Deadlines that have already passed are skipped, rather than run late one after the other.
+pub(crate) fn next_deadline(deadline: Instant, period: Duration, now: Instant) -> Instant {
+    let next = deadline + period;
+    if next > now || period == Duration::from_secs(0) {
+        return next;
+    }
+    let missed = (now - next).as_nanos() / period.as_nanos() + 1;
+    // The skipped time is at most a period more than the time since 'next', so this can't overflow.
+    // Should it not fit in an Instant after all, the schedule starts over from now.
+    let skipped = period.as_nanos() * missed;
+    u64::try_from(skipped / 1_000_000_000).ok()
+        .and_then(|secs| next.checked_add(Duration::new(secs, (skipped % 1_000_000_000) as u32)))
+        .unwrap_or(now + period)
+}
+
+/// Stops a CyclicTask from another thread.
+/// The task finishes the current cycle before it stops.
+#[derive(Clone)]
+pub struct StopHandle {
+    stop: Arc<AtomicBool>
+}
+
+impl StopHandle {
+    /// Asks the task to stop.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A CyclicTask that is running on its own thread.
+pub struct CyclicTaskHandle<I, O> {
+    stop: StopHandle,
+    thread: JoinHandle<(CyclicTask<I, O>, Result<()>)>
+}
+
+impl<I, O> CyclicTaskHandle<I, O> {
+    /// Asks the task to stop. Call join() to wait for it.
+    pub fn stop(&self) {
+        self.stop.stop();
+    }
+
+    /// Returns a handle that stops the task from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Waits for the task to finish, and returns it with the result of run().
+    pub fn join(self) -> (CyclicTask<I, O>, Result<()>) {
+        match self.thread.join() {
+            Ok(finished) => finished,
+            Err(payload) => std::panic::resume_unwind(payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_deadline_is_one_period_later() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        assert_eq!(next_deadline(start, period, start + Duration::from_millis(3)), start + period);
+    }
+
+    #[test]
+    fn next_deadline_skips_missed_deadlines() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        assert_eq!(next_deadline(start, period, start + Duration::from_millis(35)), start + Duration::from_millis(40));
+        // A deadline that is exactly now has passed as well.
+        assert_eq!(next_deadline(start, period, start + Duration::from_millis(20)), start + Duration::from_millis(30));
+    }
+
+    #[test]
+    fn next_deadline_skips_more_periods_than_fit_in_u32() {
+        let start = Instant::now();
+        let period = Duration::from_nanos(1);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(next_deadline(start, period, now), now + period);
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod fault_tests {
+    use super::*;
+    use crate::batch::{PortBatch, PortSnapshot};
+    use crate::fault::SubstituteValue;
+    use crate::layout::PortDataType;
+    use crate::simulation::{self, simulation, PortDirection, SimulatedPort};
+
+    const OUTPUT: &str = "Arp.Io.AxlC/0.DO16";
+    const SUBSTITUTE: [u8; 2] = [0xaa, 0xbb];
+
+    struct Inputs;
+
+    impl ProcessImage for Inputs {
+        const INPUT_PORTS: &'static [(&'static str, &'static str)] = &[];
+        const OUTPUT_PORTS: &'static [(&'static str, &'static str)] = &[];
+
+        fn load(&mut self, _batch: &PortBatch, _snapshot: &PortSnapshot) -> Result<()> {
+            Ok(())
+        }
+
+        fn store(&self, _batch: &PortBatch, _snapshot: &mut PortSnapshot) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Outputs {
+        value: u16,
+        cycles: u32
+    }
+
+    impl ProcessImage for Outputs {
+        const INPUT_PORTS: &'static [(&'static str, &'static str)] = &[];
+        const OUTPUT_PORTS: &'static [(&'static str, &'static str)] = &[("Arp.Io.AxlC", OUTPUT)];
+
+        fn load(&mut self, _batch: &PortBatch, _snapshot: &PortSnapshot) -> Result<()> {
+            Ok(())
+        }
+
+        fn store(&self, batch: &PortBatch, snapshot: &mut PortSnapshot) -> Result<()> {
+            batch.set_value(snapshot, 0, &self.value)
+        }
+    }
+
+    // Starts a task with a running PLC, whose logic writes 0x1234 and then calls 'logic'.
+    fn start<F: 'static + FnMut(u32) + Send>(mut logic: F) -> CyclicTaskHandle<Inputs, Outputs> {
+        let ports = [SimulatedPort::new(OUTPUT, PortDirection::Output, PortDataType::UInt16).unwrap()];
+        simulation().configure(&ports).unwrap();
+        // Not loaded through load(), which can only succeed once in a process.
+        let system = SystemHandle::new();
+        for operation in &[PlcOperation::Unload, PlcOperation::Load, PlcOperation::Setup, PlcOperation::StartCold] {
+            simulation().trigger(*operation);
+        }
+
+        let fault_policy = FaultPolicy::new()
+            .substitute("Arp.Io.AxlC", OUTPUT, SubstituteValue::Value(SUBSTITUTE.to_vec()))
+            .with_max_consecutive_overruns(3);
+        let task = CyclicTask::new(&system, Duration::from_millis(5), Inputs, Outputs { value: 0, cycles: 0 })
+            .with_fault_policy(fault_policy);
+        let handle = task.spawn(move |_, outputs| {
+            outputs.value = 0x1234;
+            outputs.cycles += 1;
+            logic(outputs.cycles);
+        }).unwrap();
+        assert!(wait_for_output(&0x1234u16.to_ne_bytes()), "the task doesn't write its outputs");
+        handle
+    }
+
+    // Waits up to a second for the output on the bus to have the given value.
+    fn wait_for_output(value: &[u8]) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if simulation().output(OUTPUT).unwrap() == value {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+
+    #[test]
+    fn substitutes_on_panic() {
+        let _simulation = simulation::exclusive();
+        let handle = start(|cycles| if cycles == 5 { panic!("logic error") });
+        let (_, result) = handle.join();
+        match result {
+            Err(PlcnextError::Fault(FaultReason::Panic { message })) => assert_eq!(message, "logic error"),
+            other => panic!("expected a panic fault, got {:?}", other)
+        }
+        assert_eq!(simulation().output(OUTPUT).unwrap(), SUBSTITUTE);
+    }
+
+    #[test]
+    fn substitutes_on_overruns() {
+        let _simulation = simulation::exclusive();
+        let handle = start(|cycles| if cycles >= 5 { thread::sleep(Duration::from_millis(8)) });
+        let (task, result) = handle.join();
+        assert!(matches!(result, Err(PlcnextError::Fault(FaultReason::Overruns { consecutive: 3 }))), "{:?}", result);
+        assert_eq!(task.outputs().cycles, 7);
+        assert_eq!(simulation().output(OUTPUT).unwrap(), SUBSTITUTE);
+    }
+
+    #[test]
+    fn substitutes_on_stop() {
+        let _simulation = simulation::exclusive();
+        let handle = start(|_| {});
+        simulation().trigger(PlcOperation::Stop);
+        assert!(wait_for_output(&SUBSTITUTE), "the substitute values weren't written on Stop");
+
+        // The task is paused, so the substitute values stay on the bus.
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(simulation().output(OUTPUT).unwrap(), SUBSTITUTE);
+        handle.stop();
+        assert!(handle.join().1.is_ok());
+    }
+
+    #[test]
+    fn substitutes_before_unload() {
+        let _simulation = simulation::exclusive();
+        let handle = start(|_| {});
+        // Unload returns only once the substitute values are on the bus.
+        simulation().trigger(PlcOperation::Unload);
+        assert_eq!(simulation().output(OUTPUT).unwrap(), SUBSTITUTE);
+        assert!(handle.join().1.is_ok());
+    }
+}
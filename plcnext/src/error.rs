@@ -1,37 +1,257 @@
+use crate::fault::FaultReason;
+#[cfg(feature = "licensing")]
+use crate::licensing::LicenseError;
+use crate::realtime::RealtimeError;
+
 use std::error;
+use std::ffi::NulError;
 use std::fmt;
 
 pub type Result<T> = std::result::Result<T, PlcnextError>;
 
-// TODO: Consider changing PlcnextError to be an enum of possible error types, e.g.
-// - SystemError error (from the ANSI-C library)
-// - Parameter error (e.g. invalid port name, data array length not correct, etc )
-// - RSC Service errors? e.g. Axioline module gives its own error struct.
-// Possibly define errors in each module and then wrap them here?
-
+/// An error from this crate.
+/// Match on the variant to find out what kind of failure it was.
+/// The message includes the message of the inner error, which holds the details,
+/// so source() skips the inner error and returns its source, if it has one.
+/// Variants are added with new features, e.g. License with the licensing feature,
+/// so a match needs a wildcard arm.
 #[derive(Debug)]
-pub struct PlcnextError {
-    pub details: String
+#[non_exhaustive]
+pub enum PlcnextError {
+    /// A call to the ANSI-C library failed.
+    System(SystemError),
+    /// A parameter passed in by the caller is not valid.
+    Parameter(ParameterError),
+    /// A function was called at the wrong point in the lifecycle
+    /// of the system or of a port handle.
+    Lifecycle(LifecycleError),
+    /// An Axioline service failed.
+    /// The wrapped error is usually plcnext_axioline's AxiolineError,
+    /// which can be recovered with downcast_ref().
+    Axioline(Box<dyn error::Error + Send + Sync>),
+    /// A CyclicTask ended because of a fault, after its fault policy was applied.
+    Fault(FaultReason),
+    /// A real-time setting could not be applied to a thread.
+    Realtime(RealtimeError),
+    /// A license was rejected.
+    #[cfg(feature = "licensing")]
+    License(LicenseError)
 }
 
 impl PlcnextError {
-    pub fn new(msg: &str) -> PlcnextError {
-        PlcnextError{ details: msg.to_string() }
+    /// An error from the ANSI-C library.
+    /// * 'function' - The name of the ANSI-C function that failed
+    /// * 'message' - The text from get_last_error()
+    pub fn system(function: &'static str, message: &str) -> PlcnextError {
+        PlcnextError::System(SystemError { function, message: message.to_string() })
+    }
+
+    pub fn length_mismatch(port_name: &str, expected: usize, actual: usize) -> PlcnextError {
+        PlcnextError::Parameter(ParameterError::LengthMismatch {
+            port_name: port_name.to_string(), expected, actual
+        })
+    }
+
+    pub fn invalid_port_name(port_name: &str, segment: &str, reason: &str) -> PlcnextError {
+        PlcnextError::Parameter(ParameterError::InvalidPortName {
+            port_name: port_name.to_string(), segment: segment.to_string(), reason: reason.to_string()
+        })
+    }
+
+    pub fn invalid_value(reason: &str) -> PlcnextError {
+        PlcnextError::Parameter(ParameterError::InvalidValue(reason.to_string()))
+    }
+
+    /// Wraps an error from an Axioline service.
+    pub fn axioline<E: 'static + error::Error + Send + Sync>(error: E) -> PlcnextError {
+        PlcnextError::Axioline(Box::new(error))
+    }
+
+    // Builds a system error from the last error message of the ANSI-C library.
+    pub(crate) fn last_error(function: &'static str) -> PlcnextError {
+        PlcnextError::system(function, &crate::get_last_error())
+    }
+}
+
+impl From<SystemError> for PlcnextError {
+    fn from(error: SystemError) -> PlcnextError {
+        PlcnextError::System(error)
+    }
+}
+
+impl From<ParameterError> for PlcnextError {
+    fn from(error: ParameterError) -> PlcnextError {
+        PlcnextError::Parameter(error)
+    }
+}
+
+impl From<LifecycleError> for PlcnextError {
+    fn from(error: LifecycleError) -> PlcnextError {
+        PlcnextError::Lifecycle(error)
+    }
+}
+
+#[cfg(feature = "licensing")]
+impl From<LicenseError> for PlcnextError {
+    fn from(error: LicenseError) -> PlcnextError {
+        PlcnextError::License(error)
     }
 }
 
 impl fmt::Display for PlcnextError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,"{}",self.details)
+        match self {
+            PlcnextError::System(e) => write!(f, "{}", e),
+            PlcnextError::Parameter(e) => write!(f, "{}", e),
+            PlcnextError::Lifecycle(e) => write!(f, "{}", e),
+            PlcnextError::Axioline(e) => write!(f, "Axioline service failed: {}", e),
+            PlcnextError::Fault(reason) => write!(f, "Cyclic task ended after a fault: {}", reason),
+            PlcnextError::Realtime(e) => write!(f, "{}", e),
+            #[cfg(feature = "licensing")]
+            PlcnextError::License(e) => write!(f, "{}", e)
+        }
     }
 }
 
 impl error::Error for PlcnextError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        // Generic error, underlying cause isn't tracked.
-        None
+        match self {
+            PlcnextError::System(e) => e.source(),
+            PlcnextError::Parameter(e) => e.source(),
+            PlcnextError::Lifecycle(e) => e.source(),
+            PlcnextError::Axioline(e) => e.source(),
+            PlcnextError::Fault(_) => None,
+            PlcnextError::Realtime(e) => e.source(),
+            #[cfg(feature = "licensing")]
+            PlcnextError::License(e) => e.source()
+        }
     }
-    fn description(&self) -> &str {
-        &self.details
+}
+
+/// A call to the ANSI-C library failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemError {
+    /// The name of the ANSI-C function that failed, e.g. "ArpPlcGds_BeginRead".
+    pub function: &'static str,
+    /// The error message from the ANSI-C library, as returned by get_last_error().
+    /// This may be empty if the library didn't set a message.
+    pub message: String
+}
+
+impl fmt::Display for SystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{} failed", self.function)
+        } else {
+            write!(f, "{} failed: {}", self.function, self.message)
+        }
+    }
+}
+
+impl error::Error for SystemError {}
+
+/// A parameter passed in by the caller is not valid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParameterError {
+    /// The port name is not valid. 'segment' is the part of the name that is wrong.
+    InvalidPortName { port_name: String, segment: String, reason: String },
+    /// The length of the data passed in is not the size of the port.
+    LengthMismatch { port_name: String, expected: usize, actual: usize },
+    /// A name contains a NUL character, so it can't be passed to the ANSI-C library.
+    /// 'parameter' is the name of the parameter, e.g. "port_name".
+    InteriorNul { parameter: &'static str, source: NulError },
+    /// The number of values passed in is not the number of ports, e.g. in a PortBatch.
+    ValueCount { expected: usize, actual: usize },
+    /// A PortSnapshot was passed to a PortBatch that didn't create it.
+    ForeignSnapshot,
+    /// A value can't be stored in its port, e.g. a string that is too long.
+    InvalidValue(String)
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParameterError::InvalidPortName { port_name, segment, reason } =>
+                write!(f, "Invalid port name '{}' at '{}': {}", port_name, segment, reason),
+            ParameterError::LengthMismatch { port_name, expected, actual } =>
+                write!(f, "Port {} has size {}, but {} bytes were passed", port_name, expected, actual),
+            ParameterError::InteriorNul { parameter, source } =>
+                write!(f, "Parameter '{}' contains a NUL character at position {}", parameter, source.nul_position()),
+            ParameterError::ValueCount { expected, actual } =>
+                write!(f, "Batch has {} ports, but {} values were passed", expected, actual),
+            ParameterError::ForeignSnapshot =>
+                write!(f, "Snapshot was not created by this batch"),
+            ParameterError::InvalidValue(reason) =>
+                write!(f, "{}", reason)
+        }
+    }
+}
+
+impl error::Error for ParameterError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ParameterError::InteriorNul { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+/// A function was called at the wrong point in the lifecycle
+/// of the system or of a port handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleError {
+    /// The function needs the ARP system module, but load() hasn't been called.
+    NotLoaded,
+    /// load() was called again with different parameters.
+    /// The parameters are the ones that the system was loaded with.
+    AlreadyLoaded { arp_binary_dir: String, application_name: String, acf_settings_path: String },
+    /// The port handle was invalidated, e.g. because the PLC was reset or unloaded.
+    /// Get a new handle from the port registry.
+    HandleInvalidated { port_name: String }
+}
+
+impl fmt::Display for LifecycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LifecycleError::NotLoaded =>
+                write!(f, "The ARP system module has not been loaded"),
+            LifecycleError::AlreadyLoaded { arp_binary_dir, application_name, acf_settings_path } =>
+                write!(f, "The ARP system module has already been loaded with different parameters: \
+                    arp_binary_dir '{}', application_name '{}', acf_settings_path '{}'",
+                    arp_binary_dir, application_name, acf_settings_path),
+            LifecycleError::HandleInvalidated { port_name } =>
+                write!(f, "Port handle for {} is no longer valid", port_name)
+        }
+    }
+}
+
+impl error::Error for LifecycleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The messages of an error and its sources, as a chain reporter would print them.
+    fn chain(error: &dyn error::Error) -> Vec<String> {
+        let mut messages = vec![error.to_string()];
+        let mut source = error.source();
+        while let Some(e) = source {
+            messages.push(e.to_string());
+            source = e.source();
+        }
+        messages
+    }
+
+    #[test]
+    fn messages_are_reported_once() {
+        let error = PlcnextError::system("ArpPlcGds_BeginRead", "Buffer is locked");
+        assert_eq!(chain(&error), vec!["ArpPlcGds_BeginRead failed: Buffer is locked"]);
+
+        let error = PlcnextError::invalid_value("Bad value");
+        assert_eq!(chain(&error).len(), 1);
+
+        let inner = std::io::Error::other("Bus is down");
+        let error = PlcnextError::axioline(inner);
+        assert_eq!(chain(&error), vec!["Axioline service failed: Bus is down"]);
     }
 }
@@ -0,0 +1,306 @@
+use crate::PlcOperation;
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// A subscriber's handler. Each handler has its own lock, so that
+// it can be called without holding the lock on the subscriber list.
+type Handler = Arc<Mutex<Box<dyn FnMut(PlcOperation) + Send>>>;
+
+struct Subscribers {
+    next_id: u64,
+    // In order of subscription, which is the order in which handlers are called.
+    handlers: Vec<(SubscriptionId, Handler)>
+}
+
+// The sending end of a channel returned by operations() or operation_stream().
+enum EventSender {
+    Channel(Sender<PlcEvent>),
+    #[cfg(feature = "stream")]
+    Stream(futures::channel::mpsc::UnboundedSender<PlcEvent>)
+}
+
+impl EventSender {
+    // Returns false if the receiver has been dropped.
+    fn send(&self, event: PlcEvent) -> bool {
+        match self {
+            EventSender::Channel(sender) => sender.send(event).is_ok(),
+            #[cfg(feature = "stream")]
+            EventSender::Stream(sender) => sender.unbounded_send(event).is_ok()
+        }
+    }
+}
+
+// The state that is only changed while an operation is delivered.
+struct Delivery {
+    next_sequence: u64
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Subscribers> = Mutex::new(Subscribers { next_id: 1, handlers: Vec::new() });
+    static ref SENDERS: Mutex<Vec<EventSender>> = Mutex::new(Vec::new());
+    // Held while an operation is delivered, so that every handler and receiver
+    // gets the operations in the order in which they occurred.
+    static ref DELIVERY: Mutex<Delivery> = Mutex::new(Delivery { next_sequence: 0 });
+}
+
+/// A PLC operation, as delivered by operations() and operation_stream().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlcEvent {
+    /// The operation.
+    pub operation: PlcOperation,
+    /// When the operation was received from the ANSI-C library.
+    pub timestamp: Instant,
+    /// The number of the operation, counting from 0 for the first operation in the process.
+    /// Every receiver sees the same number for the same operation, so a gap
+    /// means that the receiver was created after the missing operations.
+    pub sequence: u64
+}
+
+/// Identifies a handler registered with subscribe(), so that it can be unsubscribed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+/// Registers a handler that is called with every PLC operation.
+///
+/// Any number of handlers can be registered, e.g. by different libraries in
+/// the same process, and each one receives every operation in order.
+/// Handlers are called one after the other, in the order in which they were
+/// registered, on the thread that the ANSI-C library calls back on.
+/// A handler that panics is logged at the Error level and stays subscribed;
+/// the panic doesn't reach the other handlers or the ANSI-C library.
+///
+/// Operations are delivered one at a time, and the next one waits until every
+/// handler has returned. A handler may subscribe and unsubscribe, but it must not
+/// cause another operation to be delivered, e.g. by triggering one in the simulation,
+/// or wait for another thread that does, because that would deadlock.
+///
+/// Handlers can be registered before load() is called,
+/// so that they receive all operations as the system is loaded.
+pub fn subscribe<H: 'static + FnMut(PlcOperation) + Send>(handler: H) -> SubscriptionId {
+    // Make sure our own callback function is registered
+    crate::install_event_handler();
+
+    let mut subscribers = lock(&SUBSCRIBERS);
+    let id = SubscriptionId(subscribers.next_id);
+    subscribers.next_id += 1;
+    subscribers.handlers.push((id, Arc::new(Mutex::new(Box::new(handler)))));
+    id
+}
+
+/// Removes a handler registered with subscribe().
+/// Returns false if the handler had already been removed.
+/// A handler can unsubscribe itself, or any other handler, while it is being called.
+/// If an operation is being delivered on another thread, the handler may be
+/// called once more with that operation.
+pub fn unsubscribe(id: SubscriptionId) -> bool {
+    let mut subscribers = lock(&SUBSCRIBERS);
+    let count = subscribers.handlers.len();
+    subscribers.handlers.retain(|(handler_id, _)| *handler_id != id);
+    subscribers.handlers.len() != count
+}
+
+/// Returns a channel that receives every PLC operation from now on, in order.
+/// The channel is unbounded, so operations are never lost, and the ANSI-C
+/// library is never blocked by a slow receiver.
+/// Dropping the receiver unsubscribes it.
+pub fn operations() -> Receiver<PlcEvent> {
+    let (sender, receiver) = mpsc::channel();
+    add_sender(EventSender::Channel(sender));
+    receiver
+}
+
+/// Returns a stream of every PLC operation from now on, in order,
+/// for use with async runtimes such as tokio.
+/// The stream is unbounded, so operations are never lost.
+/// Dropping the stream unsubscribes it.
+#[cfg(feature = "stream")]
+pub fn operation_stream() -> futures::channel::mpsc::UnboundedReceiver<PlcEvent> {
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    add_sender(EventSender::Stream(sender));
+    receiver
+}
+
+fn add_sender(sender: EventSender) {
+    // Make sure our own callback function is registered
+    crate::install_event_handler();
+    lock(&SENDERS).push(sender);
+}
+
+// Delivers an operation to every receiver and subscriber.
+// Called from the callback function that is registered with the ANSI-C library,
+// so a panic in a handler must not unwind out of this function.
+pub(crate) fn dispatch(operation: PlcOperation) {
+    let mut delivery = lock(&DELIVERY);
+    let event = PlcEvent { operation, timestamp: Instant::now(), sequence: delivery.next_sequence };
+    delivery.next_sequence += 1;
+
+    // Receivers that have been dropped are removed.
+    lock(&SENDERS).retain(|sender| sender.send(event));
+
+    // The list is copied, so that handlers can subscribe and unsubscribe while they are called.
+    let handlers: Vec<Handler> = lock(&SUBSCRIBERS).handlers.iter()
+        .map(|(_, handler)| handler.clone())
+        .collect();
+
+    for handler in handlers {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut handler = lock(&handler);
+            (*handler)(operation)
+        }));
+        if let Err(payload) = result {
+            log::error!("Handler for PLC operation {:?} panicked: {}", operation, panic_message(&*payload));
+        }
+    }
+}
+
+// Gets the message from a panic payload, if it has one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "(no message)"
+    }
+}
+
+// A handler that panicked has already been reported,
+// so a poisoned lock is simply taken over.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::simulation;
+
+    // Records the operations received by each handler, in the order in which they were received.
+    fn recorder() -> Arc<Mutex<Vec<(u32, PlcOperation)>>> {
+        Arc::new(Mutex::new(Vec::new()))
+    }
+
+    fn record(received: &Arc<Mutex<Vec<(u32, PlcOperation)>>>, handler: u32) -> SubscriptionId {
+        let received = received.clone();
+        subscribe(move |operation| lock(&received).push((handler, operation)))
+    }
+
+    #[test]
+    fn handlers_are_called_in_order_of_subscription() {
+        let _simulation = simulation::exclusive();
+        let received = recorder();
+        let first = record(&received, 1);
+        let second = record(&received, 2);
+
+        dispatch(PlcOperation::Load);
+        dispatch(PlcOperation::Setup);
+        assert_eq!(*lock(&received), vec![
+            (1, PlcOperation::Load), (2, PlcOperation::Load), (1, PlcOperation::Setup), (2, PlcOperation::Setup)]);
+
+        assert!(unsubscribe(first));
+        assert!(unsubscribe(second));
+    }
+
+    #[test]
+    fn unsubscribed_handlers_are_not_called() {
+        let _simulation = simulation::exclusive();
+        let received = recorder();
+        let id = record(&received, 1);
+        dispatch(PlcOperation::Stop);
+
+        assert!(unsubscribe(id));
+        assert!(!unsubscribe(id));
+        dispatch(PlcOperation::Reset);
+        assert_eq!(*lock(&received), vec![(1, PlcOperation::Stop)]);
+    }
+
+    #[test]
+    fn panicking_handler_is_isolated() {
+        let _simulation = simulation::exclusive();
+        let received = recorder();
+        let panicking = {
+            let received = received.clone();
+            subscribe(move |operation| {
+                lock(&received).push((1, operation));
+                panic!("Handler failed");
+            })
+        };
+        let other = record(&received, 2);
+
+        dispatch(PlcOperation::StartCold);
+        dispatch(PlcOperation::Stop);
+        assert_eq!(*lock(&received), vec![
+            (1, PlcOperation::StartCold), (2, PlcOperation::StartCold), (1, PlcOperation::Stop), (2, PlcOperation::Stop)]);
+
+        assert!(unsubscribe(panicking));
+        assert!(unsubscribe(other));
+    }
+
+    #[test]
+    fn handlers_can_subscribe_and_unsubscribe() {
+        let _simulation = simulation::exclusive();
+        let received = recorder();
+        let own_id = Arc::new(Mutex::new(None));
+        let added = Arc::new(Mutex::new(None));
+        let id = {
+            let (received, own_id, added) = (received.clone(), own_id.clone(), added.clone());
+            subscribe(move |operation| {
+                lock(&received).push((1, operation));
+                *lock(&added) = Some(record(&received, 2));
+                if let Some(id) = lock(&own_id).take() {
+                    assert!(unsubscribe(id));
+                }
+            })
+        };
+        *lock(&own_id) = Some(id);
+
+        // The handler that is added isn't called with the operation that is being delivered.
+        dispatch(PlcOperation::Load);
+        dispatch(PlcOperation::Setup);
+        assert_eq!(*lock(&received), vec![(1, PlcOperation::Load), (2, PlcOperation::Setup)]);
+
+        assert!(!unsubscribe(id));
+        let added = lock(&added).take().unwrap();
+        assert!(unsubscribe(added));
+    }
+
+    #[test]
+    fn receivers_see_the_same_sequence_numbers() {
+        let _simulation = simulation::exclusive();
+        let early = operations();
+        dispatch(PlcOperation::Load);
+        let late = operations();
+        dispatch(PlcOperation::Setup);
+        dispatch(PlcOperation::StartWarm);
+
+        let early: Vec<PlcEvent> = early.try_iter().collect();
+        let late: Vec<PlcEvent> = late.try_iter().collect();
+        assert_eq!(early.iter().map(|e| e.operation).collect::<Vec<_>>(),
+                   vec![PlcOperation::Load, PlcOperation::Setup, PlcOperation::StartWarm]);
+        assert_eq!(early[1].sequence, early[0].sequence + 1);
+        assert_eq!(early[2].sequence, early[0].sequence + 2);
+        assert_eq!(late, early[1..].to_vec());
+        assert!(early[0].timestamp <= early[1].timestamp);
+    }
+
+    #[test]
+    fn dropped_receivers_are_removed() {
+        let _simulation = simulation::exclusive();
+        dispatch(PlcOperation::None);
+        let senders = lock(&SENDERS).len();
+
+        let kept = operations();
+        drop(operations());
+        #[cfg(feature = "stream")]
+        drop(operation_stream());
+        assert_eq!(lock(&SENDERS).len(), senders + if cfg!(feature = "stream") { 3 } else { 2 });
+
+        dispatch(PlcOperation::Stop);
+        assert_eq!(lock(&SENDERS).len(), senders + 1);
+        assert_eq!(kept.try_recv().map(|e| e.operation), Ok(PlcOperation::Stop));
+    }
+}
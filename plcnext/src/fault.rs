@@ -0,0 +1,125 @@
+use crate::error::Result;
+use crate::SystemHandle;
+
+use std::fmt;
+
+/// The value that is written to an output port when a fault occurs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubstituteValue {
+    /// All bytes of the port are set to zero.
+    Zero,
+    /// The port keeps the last value that was written to it before the fault.
+    HoldLast,
+    /// The given value is written. It must be the size of the port.
+    Value(Vec<u8>)
+}
+
+/// The reason that a fault policy was applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultReason {
+    /// The cycle logic panicked, with the given message.
+    Panic { message: String },
+    /// The given number of cycles in a row finished after the start of the next period.
+    Overruns { consecutive: u64 },
+    /// The PLC was stopped.
+    Stop,
+    /// The PLC is about to be unloaded.
+    Unload
+}
+
+impl fmt::Display for FaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultReason::Panic { message } => write!(f, "cycle logic panicked: {}", message),
+            FaultReason::Overruns { consecutive } => write!(f, "{} consecutive cycle overruns", consecutive),
+            FaultReason::Stop => write!(f, "PLC stopped"),
+            FaultReason::Unload => write!(f, "PLC unloading")
+        }
+    }
+}
+
+// An output port and the value that is written to it when a fault occurs.
+#[derive(Debug, Clone)]
+struct Substitute {
+    fb_io_system_name: String,
+    port_name: String,
+    value: SubstituteValue
+}
+
+/// Puts output ports into a safe state when a fault occurs.
+///
+/// A CyclicTask with a fault policy applies it when the cycle logic panics,
+/// when too many cycles overrun in a row, when the PLC is stopped, and when
+/// the PLC is unloaded. The substitute values are written with write_output_data(),
+/// and the reason is logged at the Warning level.
+/// Ports without a substitute value keep their last value.
+#[derive(Debug, Clone, Default)]
+pub struct FaultPolicy {
+    substitutes: Vec<Substitute>,
+    max_consecutive_overruns: Option<u64>
+}
+
+impl FaultPolicy {
+    /// Creates a policy without any substitute values.
+    pub fn new() -> FaultPolicy {
+        FaultPolicy::default()
+    }
+
+    /// Sets the value that is written to an output port when a fault occurs.
+    /// * 'fb_io_system_name' - Name of the fieldbus I/O system, e.g. "Arp.Io.AxlC"
+    /// * 'port_name' - Name of the port, e.g. "Arp.Io.AxlC/0.DO16"
+    /// * 'value' - The substitute value
+    pub fn substitute(mut self, fb_io_system_name: &str, port_name: &str, value: SubstituteValue) -> FaultPolicy {
+        self.substitutes.retain(|s| !(s.fb_io_system_name == fb_io_system_name && s.port_name == port_name));
+        self.substitutes.push(Substitute {
+            fb_io_system_name: fb_io_system_name.to_string(),
+            port_name: port_name.to_string(),
+            value
+        });
+        self
+    }
+
+    /// Sets the number of consecutive overruns after which the policy is applied
+    /// and the task is stopped. By default, overruns are only counted.
+    pub fn with_max_consecutive_overruns(mut self, overruns: u64) -> FaultPolicy {
+        self.max_consecutive_overruns = Some(overruns);
+        self
+    }
+
+    /// The number of consecutive overruns after which the policy is applied, if any.
+    pub fn max_consecutive_overruns(&self) -> Option<u64> {
+        self.max_consecutive_overruns
+    }
+
+    /// Writes the substitute values to their ports in the GDS, and logs the reason.
+    /// Every value is written, even if an earlier one fails, and the first error is returned.
+    /// The values only reach the bus with the next transfer from the GDS,
+    /// e.g. write_from_gds_to_axio().
+    pub fn apply(&self, system: &SystemHandle, reason: &FaultReason) -> Result<()> {
+        log::warn!("Fault reaction: {}, writing substitute values to {} ports", reason,
+            self.substitutes.iter().filter(|s| s.value != SubstituteValue::HoldLast).count());
+
+        let mut first = Ok(());
+        for substitute in &self.substitutes {
+            let result = substitute.write(system);
+            if first.is_ok() {
+                first = result;
+            }
+        }
+        first
+    }
+}
+
+impl Substitute {
+    fn write(&self, system: &SystemHandle) -> Result<()> {
+        match &self.value {
+            SubstituteValue::HoldLast => Ok(()),
+            SubstituteValue::Zero => {
+                let size = crate::port_registry(system).get(&self.fb_io_system_name, &self.port_name)?.size();
+                crate::write_output_data(system, &self.fb_io_system_name, &self.port_name, &vec![0; size])
+            },
+            SubstituteValue::Value(value) =>
+                crate::write_output_data(system, &self.fb_io_system_name, &self.port_name, value)
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::layout::PortDataType;
+use crate::simulation::{simulation, PortDirection, SimulatedPort};
+use crate::waveform::{self, Waveform};
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// The file format, which is the same in TOML and YAML.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldbusDefinition {
+    #[serde(default = "default_bus_cycle_ms")]
+    bus_cycle_ms: u64,
+    io_systems: Vec<IoSystemDefinition>
+}
+
+fn default_bus_cycle_ms() -> u64 {
+    10
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IoSystemDefinition {
+    name: String,
+    ports: Vec<PortDefinition>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PortDefinition {
+    name: String,
+    direction: DirectionDefinition,
+    data_type: String,
+    size: Option<usize>,
+    initial: Option<InitialValue>,
+    waveform: Option<WaveformDefinition>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DirectionDefinition {
+    Input,
+    Output
+}
+
+// An initial value is either a number, which is encoded like a waveform value,
+// or the raw bytes of the port.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InitialValue {
+    Number(f64),
+    Bytes(Vec<u8>)
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+enum WaveformDefinition {
+    Step { before: f64, after: f64, at_ms: u64 },
+    Ramp { from: f64, to: f64, #[serde(default)] start_ms: u64, duration_ms: u64 },
+    Square { low: f64, high: f64, period_ms: u64, #[serde(default = "default_duty")] duty: f64 },
+    Csv { file: PathBuf }
+}
+
+fn default_duty() -> f64 {
+    0.5
+}
+
+/// A simulated fieldbus, described in a TOML or YAML file.
+///
+/// The file lists the fieldbus I/O systems with their ports. Each port has a name,
+/// a direction, a data type, and optionally a size, an initial value and, for inputs,
+/// a waveform. In TOML:
+///
+/// ```toml
+/// bus_cycle_ms = 10
+///
+/// [[io_systems]]
+/// name = "Arp.Io.AxlC"
+///
+/// [[io_systems.ports]]
+/// name = "0.DI16"
+/// direction = "input"
+/// data_type = "UInt16"
+/// waveform = { kind = "square", low = 0, high = 1, period_ms = 100 }
+///
+/// [[io_systems.ports]]
+/// name = "1.AI2"
+/// direction = "input"
+/// data_type = "Int16"
+/// waveform = { kind = "csv", file = "ai2.csv" }
+///
+/// [[io_systems.ports]]
+/// name = "2.DO16"
+/// direction = "output"
+/// data_type = "UInt16"
+/// initial = [0, 0]
+/// ```
+///
+/// The same in YAML, with a step instead of a CSV file:
+///
+/// ```yaml
+/// bus_cycle_ms: 10
+/// io_systems:
+///   - name: Arp.Io.AxlC
+///     ports:
+///       - name: 0.DI16
+///         direction: input
+///         data_type: UInt16
+///         waveform: { kind: square, low: 0, high: 1, period_ms: 100 }
+///       - name: 1.AI2
+///         direction: input
+///         data_type: Int16
+///         waveform: { kind: step, before: 0, after: 1000, at_ms: 500 }
+///       - name: 2.DO16
+///         direction: output
+///         data_type: UInt16
+///         initial: [0, 0]
+/// ```
+///
+/// A port name is either the full name, or the path within its I/O system.
+/// The data type is a PortDataType variant, e.g. "UInt16" or "StaticString", and
+/// the size is only needed if the data type has no fixed size. A port can only be defined once.
+/// An initial value is either
+/// a number, or the raw bytes of the port. The waveforms are "step" (before, after, at_ms),
+/// "ramp" (from, to, start_ms, duration_ms), "square" (low, high, period_ms, duty)
+/// and "csv" (file), where a relative file name is relative to the definition file.
+pub struct SimulatedFieldbus {
+    bus_cycle: Duration,
+    ports: Vec<SimulatedPort>,
+    initial_values: Vec<(String, Vec<u8>)>,
+    waveforms: Vec<(String, Waveform)>
+}
+
+impl SimulatedFieldbus {
+    /// Reads a definition from a file. Files ending in ".yaml" or ".yml"
+    /// are read as YAML, and all other files as TOML.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SimulatedFieldbus> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| PlcnextError::invalid_value(
+            &format!("Failed to read {}: {}", path.display(), e)))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => SimulatedFieldbus::parse_yaml(&text, base_dir),
+            _ => SimulatedFieldbus::parse_toml(&text, base_dir)
+        }
+    }
+
+    /// Reads a definition from TOML text. CSV files are relative to the current directory.
+    pub fn from_toml_str(text: &str) -> Result<SimulatedFieldbus> {
+        SimulatedFieldbus::parse_toml(text, Path::new(""))
+    }
+
+    /// Reads a definition from YAML text. CSV files are relative to the current directory.
+    pub fn from_yaml_str(text: &str) -> Result<SimulatedFieldbus> {
+        SimulatedFieldbus::parse_yaml(text, Path::new(""))
+    }
+
+    fn parse_toml(text: &str, base_dir: &Path) -> Result<SimulatedFieldbus> {
+        let definition = toml::from_str(text).map_err(|e| PlcnextError::invalid_value(
+            &format!("Invalid fieldbus definition: {}", e)))?;
+        SimulatedFieldbus::from_definition(definition, base_dir)
+    }
+
+    fn parse_yaml(text: &str, base_dir: &Path) -> Result<SimulatedFieldbus> {
+        let definition = serde_yaml::from_str(text).map_err(|e| PlcnextError::invalid_value(
+            &format!("Invalid fieldbus definition: {}", e)))?;
+        SimulatedFieldbus::from_definition(definition, base_dir)
+    }
+
+    fn from_definition(definition: FieldbusDefinition, base_dir: &Path) -> Result<SimulatedFieldbus> {
+        let mut fieldbus = SimulatedFieldbus {
+            bus_cycle: Duration::from_millis(definition.bus_cycle_ms),
+            ports: Vec::new(),
+            initial_values: Vec::new(),
+            waveforms: Vec::new()
+        };
+
+        for io_system in definition.io_systems {
+            for port in io_system.ports {
+                // A port path is prefixed with the name of its I/O system.
+                let name = if port.name.contains('/') {
+                    port.name
+                } else {
+                    format!("{}/{}", io_system.name, port.name)
+                };
+                let direction = match port.direction {
+                    DirectionDefinition::Input => PortDirection::Input,
+                    DirectionDefinition::Output => PortDirection::Output
+                };
+                let mut simulated = SimulatedPort::new(&name, direction, data_type(&name, &port.data_type)?)?;
+                if simulated.name().fb_io_system_name() != io_system.name {
+                    return Err(PlcnextError::invalid_value(&format!(
+                        "Port '{}' is not in I/O system '{}'", name, io_system.name)));
+                }
+                if fieldbus.ports.iter().any(|p| p.name() == simulated.name()) {
+                    return Err(PlcnextError::invalid_value(&format!("Port '{}' is defined twice", name)));
+                }
+                // A new port has the size of its data type, or zero if the type has no fixed size.
+                let fixed_size = simulated.size();
+                match port.size {
+                    Some(0) => return Err(PlcnextError::invalid_value(&format!("Port '{}' has a size of zero", name))),
+                    Some(size) if fixed_size != 0 && size != fixed_size => return Err(PlcnextError::invalid_value(&format!(
+                        "Port '{}' has {} bytes, but its data type has {}", name, size, fixed_size))),
+                    Some(size) => simulated = simulated.with_size(size),
+                    None if fixed_size == 0 => return Err(PlcnextError::invalid_value(&format!(
+                        "Port '{}' needs a size, because its data type has no fixed size", name))),
+                    None => {}
+                }
+
+                match port.initial {
+                    Some(InitialValue::Number(value)) => {
+                        let mut bytes = vec![0; simulated.size()];
+                        waveform::encode(&name, simulated.data_type(), value, &mut bytes)?;
+                        fieldbus.initial_values.push((name.clone(), bytes));
+                    },
+                    Some(InitialValue::Bytes(bytes)) => fieldbus.initial_values.push((name.clone(), bytes)),
+                    None => {}
+                }
+
+                if let Some(definition) = port.waveform {
+                    if direction != PortDirection::Input {
+                        return Err(PlcnextError::invalid_value(&format!("Output port '{}' can't have a waveform", name)));
+                    }
+                    let waveform = match definition {
+                        WaveformDefinition::Step { before, after, at_ms } =>
+                            Waveform::Step { before, after, at: Duration::from_millis(at_ms) },
+                        WaveformDefinition::Ramp { from, to, start_ms, duration_ms } =>
+                            Waveform::Ramp { from, to, start: Duration::from_millis(start_ms), duration: Duration::from_millis(duration_ms) },
+                        WaveformDefinition::Square { low, high, period_ms, duty } =>
+                            Waveform::Square { low, high, period: Duration::from_millis(period_ms), duty },
+                        WaveformDefinition::Csv { file } => Waveform::load_csv(base_dir.join(file))?
+                    };
+                    fieldbus.waveforms.push((name.clone(), waveform));
+                }
+
+                fieldbus.ports.push(simulated);
+            }
+        }
+        Ok(fieldbus)
+    }
+
+    /// The ports, in the order of the definition.
+    pub fn ports(&self) -> &[SimulatedPort] {
+        &self.ports
+    }
+
+    /// The time by which the bus time advances with every read_from_axio_to_gds().
+    pub fn bus_cycle(&self) -> Duration {
+        self.bus_cycle
+    }
+
+    /// Configures the simulation with this fieldbus: replaces the port table,
+    /// sets the bus cycle, the initial values and the waveforms, and sets the bus time to zero.
+    /// Like Simulation::configure(), this should be done before load().
+    pub fn install(&self) -> Result<()> {
+        let simulation = simulation();
+        simulation.configure(&self.ports)?;
+        simulation.set_bus_cycle(self.bus_cycle);
+        for (port_name, value) in &self.initial_values {
+            simulation.initialize(port_name, value)?;
+        }
+        for (port_name, waveform) in &self.waveforms {
+            simulation.set_waveform(port_name, waveform.clone())?;
+        }
+        Ok(())
+    }
+}
+
+// Parses the name of a PortDataType variant.
+fn data_type(port_name: &str, name: &str) -> Result<PortDataType> {
+    let data_type = match name {
+        "Bit" => PortDataType::Bit,
+        "Boolean" => PortDataType::Boolean,
+        "UInt8" => PortDataType::UInt8,
+        "Int8" => PortDataType::Int8,
+        "Char8" => PortDataType::Char8,
+        "Char16" => PortDataType::Char16,
+        "UInt16" => PortDataType::UInt16,
+        "Int16" => PortDataType::Int16,
+        "UInt32" => PortDataType::UInt32,
+        "Int32" => PortDataType::Int32,
+        "UInt64" => PortDataType::UInt64,
+        "Int64" => PortDataType::Int64,
+        "Float32" => PortDataType::Float32,
+        "Float64" => PortDataType::Float64,
+        "IecTime" => PortDataType::IecTime,
+        "IecTime64" => PortDataType::IecTime64,
+        "StaticString" => PortDataType::StaticString,
+        "IecString" => PortDataType::IecString,
+        other => return Err(PlcnextError::invalid_value(&format!(
+            "Port '{}' has an unknown data type '{}'", port_name, other)))
+    };
+    Ok(data_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples from the documentation of SimulatedFieldbus.
+    const TOML_EXAMPLE: &str = r#"
+bus_cycle_ms = 10
+
+[[io_systems]]
+name = "Arp.Io.AxlC"
+
+[[io_systems.ports]]
+name = "0.DI16"
+direction = "input"
+data_type = "UInt16"
+waveform = { kind = "square", low = 0, high = 1, period_ms = 100 }
+
+[[io_systems.ports]]
+name = "1.AI2"
+direction = "input"
+data_type = "Int16"
+waveform = { kind = "csv", file = "ai2.csv" }
+
+[[io_systems.ports]]
+name = "2.DO16"
+direction = "output"
+data_type = "UInt16"
+initial = [0, 0]
+"#;
+
+    const YAML_EXAMPLE: &str = "
+bus_cycle_ms: 10
+io_systems:
+  - name: Arp.Io.AxlC
+    ports:
+      - name: 0.DI16
+        direction: input
+        data_type: UInt16
+        waveform: { kind: square, low: 0, high: 1, period_ms: 100 }
+      - name: 1.AI2
+        direction: input
+        data_type: Int16
+        waveform: { kind: step, before: 0, after: 1000, at_ms: 500 }
+      - name: 2.DO16
+        direction: output
+        data_type: UInt16
+        initial: [0, 0]
+";
+
+    fn names(fieldbus: &SimulatedFieldbus) -> Vec<&str> {
+        fieldbus.ports().iter().map(|port| port.name().as_str()).collect()
+    }
+
+    // The error message of a definition that is rejected.
+    fn rejected(text: &str) -> String {
+        match SimulatedFieldbus::from_toml_str(text) {
+            Err(e) => e.to_string(),
+            Ok(fieldbus) => panic!("Expected an error, got ports {:?}", names(&fieldbus))
+        }
+    }
+
+    fn port(name: &str, data_type: &str, extra: &str) -> String {
+        format!("[[io_systems.ports]]\nname = \"{}\"\ndirection = \"input\"\ndata_type = \"{}\"\n{}\n", name, data_type, extra)
+    }
+
+    #[test]
+    fn toml_example() {
+        // The CSV file is relative to the definition file.
+        let dir = std::env::temp_dir().join(format!("plcnext-fieldbus-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ai2.csv"), "time_ms,value\n0,0\n100,250\n").unwrap();
+        std::fs::write(dir.join("fieldbus.toml"), TOML_EXAMPLE).unwrap();
+        let fieldbus = SimulatedFieldbus::load(dir.join("fieldbus.toml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let fieldbus = fieldbus.unwrap();
+        assert_eq!(fieldbus.bus_cycle(), Duration::from_millis(10));
+        assert_eq!(names(&fieldbus), ["Arp.Io.AxlC/0.DI16", "Arp.Io.AxlC/1.AI2", "Arp.Io.AxlC/2.DO16"]);
+        assert_eq!(fieldbus.ports()[2].direction(), PortDirection::Output);
+        assert_eq!(fieldbus.initial_values, [("Arp.Io.AxlC/2.DO16".to_string(), vec![0, 0])]);
+        assert_eq!(fieldbus.waveforms[1].1, Waveform::Samples(vec![
+            (Duration::from_millis(0), 0.0), (Duration::from_millis(100), 250.0)]));
+    }
+
+    #[test]
+    fn yaml_example() {
+        let fieldbus = SimulatedFieldbus::from_yaml_str(YAML_EXAMPLE).unwrap();
+        assert_eq!(names(&fieldbus), ["Arp.Io.AxlC/0.DI16", "Arp.Io.AxlC/1.AI2", "Arp.Io.AxlC/2.DO16"]);
+        assert_eq!(fieldbus.ports()[1].data_type(), PortDataType::Int16);
+        assert_eq!(fieldbus.ports()[1].size(), 2);
+        assert_eq!(fieldbus.waveforms[0].1, Waveform::Square { low: 0.0, high: 1.0, period: Duration::from_millis(100), duty: 0.5 });
+        assert_eq!(fieldbus.waveforms[1].1, Waveform::Step { before: 0.0, after: 1000.0, at: Duration::from_millis(500) });
+    }
+
+    #[test]
+    fn sizes_and_initial_numbers() {
+        let text = format!("[[io_systems]]\nname = \"Arp.Io.AxlC\"\n{}{}",
+            port("0.Name", "StaticString", "size = 8"), port("1.AI2", "Int16", "size = 2\ninitial = -2"));
+        let fieldbus = SimulatedFieldbus::from_toml_str(&text).unwrap();
+        assert_eq!(fieldbus.bus_cycle(), Duration::from_millis(10));
+        assert_eq!(fieldbus.ports()[0].size(), 8);
+        assert_eq!(fieldbus.initial_values, [("Arp.Io.AxlC/1.AI2".to_string(), (-2i16).to_ne_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        let io_system = "[[io_systems]]\nname = \"Arp.Io.AxlC\"\n";
+        let twice = format!("{}{}{}", io_system, port("0.DI16", "UInt16", ""), port("Arp.Io.AxlC/0.DI16", "UInt16", ""));
+        assert!(rejected(&twice).contains("Port 'Arp.Io.AxlC/0.DI16' is defined twice"), "{}", rejected(&twice));
+
+        let unknown = format!("{}{}", io_system, port("0.DI16", "Word", ""));
+        assert!(rejected(&unknown).contains("unknown data type 'Word'"));
+
+        let zero = format!("{}{}", io_system, port("0.Name", "StaticString", "size = 0"));
+        assert!(rejected(&zero).contains("has a size of zero"));
+        let wrong = format!("{}{}", io_system, port("0.DI16", "UInt16", "size = 4"));
+        assert!(rejected(&wrong).contains("has 4 bytes, but its data type has 2"));
+        let missing = format!("{}{}", io_system, port("0.Name", "StaticString", ""));
+        assert!(rejected(&missing).contains("needs a size"));
+
+        let other_system = format!("{}{}", io_system, port("Arp.Io.PnC/0.DI16", "UInt16", ""));
+        assert!(rejected(&other_system).contains("is not in I/O system 'Arp.Io.AxlC'"));
+        let output_waveform = format!("{}{}", io_system, port("0.DO16", "UInt16", "waveform = { kind = \"step\", before = 0, after = 1, at_ms = 5 }"))
+            .replace("\"input\"", "\"output\"");
+        assert!(rejected(&output_waveform).contains("can't have a waveform"));
+        assert!(rejected(&format!("{}{}", io_system, port("0.DI16", "UInt16", "colour = 1"))).contains("Invalid fieldbus definition"));
+    }
+}
@@ -0,0 +1,490 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::c_string;
+use crate::get_last_error;
+use crate::layout::{self, PortDataType, PortInfo};
+use crate::sys;
+use crate::SystemHandle;
+
+use std::ffi::CStr;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::os::raw::c_char;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+// The handler that is called when a GDS buffer or lock guard
+// can't clean up in Drop. It is shared, so that it can be called without holding the lock.
+type DropErrorHandler = Arc<dyn Fn(&PlcnextError) + Send + Sync>;
+
+lazy_static! {
+    static ref DROP_ERROR_HANDLER: Mutex<Option<DropErrorHandler>> = Mutex::new(None);
+}
+
+/// Sets the handler that is called when a GDS buffer fails to release itself,
+/// or a read or write guard fails to unlock its buffer, when it is dropped.
+/// If no handler is set, the error is logged at the Error level, through the log crate.
+/// Errors from GdsBuffer::release() and the guards' end() methods are
+/// returned to the caller and are not passed to this handler.
+pub fn set_drop_error_handler<H: 'static + Fn(&PlcnextError) + Send + Sync>(handler: Option<H>) {
+    let mut current = DROP_ERROR_HANDLER.lock().unwrap_or_else(|e| e.into_inner());
+    *current = match handler {
+        Some(h) => Some(Arc::new(h)),
+        None => None
+    };
+}
+
+// Passes a drop error to the user's handler, or to the log if there is no handler.
+// The handler is cloned out of the lock before it is called, so that it may
+// set another handler, or drop a guard itself, without deadlocking.
+fn report_drop_error(operation: &str, error: &PlcnextError) {
+    let handler = DROP_ERROR_HANDLER.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match handler {
+        Some(h) => h(error),
+        None => log::error!("{} failed: {}", operation, error)
+    }
+}
+
+/// A handle to the GDS buffer containing a fieldbus port.
+/// The buffer is acquired when the handle is created, and
+/// released when the handle is dropped.
+pub struct GdsBuffer {
+    buffer: *mut sys::TGdsBuffer,
+    info: PortInfo,
+    // The end of the furthest port that has been resolved in this buffer.
+    // The ANSI-C library doesn't report the size of the page, but every port
+    // that it has laid out in the buffer lies within the page.
+    page_len: usize
+}
+
+// The GDS buffer isn't tied to the thread that acquired it.
+// Reads and writes are synchronised by the GDS itself, using BeginRead/EndRead
+// and BeginWrite/EndWrite, and the guards borrow the handle mutably.
+unsafe impl Send for GdsBuffer {}
+
+impl GdsBuffer {
+    /// Gets the GDS buffer containing the named port,
+    /// and the layout of the named port in that buffer.
+    /// The read and write guards give access to exactly the port data in the buffer page.
+    /// * 'system' - The handle returned by load()
+    /// * 'fb_io_system_name' - Name of the fieldbus I/O system, e.g. "Arp.Io.AxlC"
+    /// * 'port_name' - Name of the port, e.g. "Arp.Io.AxlC/0.DI16"
+    pub fn new(_system: &SystemHandle, fb_io_system_name: &str, port_name: &str) -> Result<GdsBuffer> {
+
+        // Create CStrings from inputs, for C compatibility.
+        // The CStrings own the memory, so nothing needs to be freed afterwards.
+        // A name containing a NUL character is returned as a parameter error.
+        let fb_io_system_name = c_string("fb_io_system_name", fb_io_system_name)?;
+        let port_name = c_string("port_name", port_name)?;
+
+        // Assign the pointer to the start of the GDS buffer containing the named port.
+        // The buffer is owned by the handle from here on, so that it is
+        // released on every return path, even if the call fails.
+        let mut gds_buffer = GdsBuffer {
+            buffer: std::ptr::null_mut(),
+            info: PortInfo { offset: 0, size: 0, data_type: PortDataType::Other(0) },
+            page_len: 0
+        };
+        if !unsafe { sys::ArpPlcIo_GetBufferPtrByPortName(fb_io_system_name.as_ptr(), port_name.as_ptr(), &mut gds_buffer.buffer) } {
+            // Log::Error("ArpPlcIo_GetBufferPtrByPortName failed");
+            return Err(PlcnextError::last_error("ArpPlcIo_GetBufferPtrByPortName"));
+        }
+
+        gds_buffer.info = gds_buffer.resolve_port(&port_name)?;
+        Ok(gds_buffer)
+    }
+
+    // Gets the layout of another port in the same GDS buffer.
+    // An error is returned if the port is not in this buffer.
+    pub(crate) fn port_info(&mut self, port_name: &str) -> Result<PortInfo> {
+        let port_name = c_string("port_name", port_name)?;
+        self.resolve_port(&port_name)
+    }
+
+    // Gets the layout of a port in this GDS buffer, and extends the known part of the page to cover it.
+    fn resolve_port(&mut self, port_name: &CStr) -> Result<PortInfo> {
+        // Get the size and data type of the named port from the data layout
+        let mut info = layout::get_port_info(self.buffer, port_name)?;
+
+        // Get the offset to the named port in the GDS buffer
+        if !unsafe { sys::ArpPlcGds_GetVariableOffset(self.buffer, port_name.as_ptr(), &mut info.offset) } {
+            // Log::Error("ArpPlcGds_GetVariableOffset failed");
+            return Err(PlcnextError::last_error("ArpPlcGds_GetVariableOffset"));
+        }
+        let end = info.offset.checked_add(info.size).ok_or_else(|| PlcnextError::system(
+            "ArpPlcGds_GetVariableOffset", "the port lies outside the address space"))?;
+        self.page_len = self.page_len.max(end);
+        Ok(info)
+    }
+
+    /// The offset to the port data in the GDS buffer page.
+    pub fn offset(&self) -> usize {
+        self.info.offset
+    }
+
+    /// The size of the port data in bytes.
+    pub fn size(&self) -> usize {
+        self.info.size
+    }
+
+    /// The data type of the port.
+    pub fn data_type(&self) -> PortDataType {
+        self.info.data_type
+    }
+
+    /// The layout of the port in the GDS buffer page.
+    pub fn info(&self) -> PortInfo {
+        self.info
+    }
+
+    /// Locks the GDS buffer for reading.
+    /// The buffer is unlocked when the returned guard is dropped.
+    pub fn read(&mut self) -> Result<GdsReadGuard<'_>> {
+        // Begin read operation by getting a pointer to the GDS data buffer page
+        // After this call, the GDS buffer will be locked
+        let mut data_buffer_page: *mut c_char = std::ptr::null_mut();
+        if !unsafe { sys::ArpPlcGds_BeginRead(self.buffer, &mut data_buffer_page) } {
+            // Log::Error("ArpPlcGds_BeginRead failed");
+            // Find out what the problem was
+            let error = get_last_error();
+
+            // Try to end the read operation
+            if !unsafe { sys::ArpPlcGds_EndRead(self.buffer) } {
+                // If an error occurs, just log it, but don't return it
+                report_drop_error("ArpPlcGds_EndRead", &PlcnextError::last_error("ArpPlcGds_EndRead"));
+            }
+            return Err(PlcnextError::system("ArpPlcGds_BeginRead", &error));
+        }
+
+        // The guard is created straight away, so that the buffer is unlocked on every return path.
+        let page_len = self.page_len;
+        let mut guard = GdsReadGuard { gds_buffer: self, page: std::ptr::null(), page_len, ended: false };
+        guard.page = page_address("ArpPlcGds_BeginRead", data_buffer_page)?;
+        Ok(guard)
+    }
+
+    /// Locks the GDS buffer for writing.
+    /// The buffer is unlocked when the returned guard is dropped.
+    pub fn write(&mut self) -> Result<GdsWriteGuard<'_>> {
+        // Begin write operation by getting a pointer to the GDS data buffer page
+        // After this call, the GDS buffer will be locked
+        let mut data_buffer_page: *mut c_char = std::ptr::null_mut();
+        if !unsafe { sys::ArpPlcGds_BeginWrite(self.buffer, &mut data_buffer_page) } {
+            // Log::Error("ArpPlcGds_BeginWrite failed");
+            return Err(PlcnextError::last_error("ArpPlcGds_BeginWrite"));
+        }
+
+        // The guard is created straight away, so that the buffer is unlocked on every return path.
+        let page_len = self.page_len;
+        let mut guard = GdsWriteGuard { gds_buffer: self, page: std::ptr::null_mut(), page_len, ended: false };
+        guard.page = page_address("ArpPlcGds_BeginWrite", data_buffer_page)? as *mut u8;
+        Ok(guard)
+    }
+
+    /// Releases the GDS buffer and frees internal resources.
+    /// Use this instead of dropping the handle to find out if the release failed.
+    pub fn release(mut self) -> Result<()> {
+        self.release_buffer()
+    }
+
+    // Releases the buffer, if it hasn't been released already.
+    fn release_buffer(&mut self) -> Result<()> {
+        if self.buffer.is_null() {
+            return Ok(());
+        }
+        let buffer = std::mem::replace(&mut self.buffer, std::ptr::null_mut());
+        if !unsafe { sys::ArpPlcIo_ReleaseGdsBuffer(buffer) } {
+            // Log::Error("ArpPlcIo_ReleaseGdsBuffer failed");
+            return Err(PlcnextError::last_error("ArpPlcIo_ReleaseGdsBuffer"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for GdsBuffer {
+    fn drop(&mut self) {
+        if let Err(error) = self.release_buffer() {
+            report_drop_error("ArpPlcIo_ReleaseGdsBuffer", &error);
+        }
+    }
+}
+
+// Checks the pointer to the GDS buffer page returned by BeginRead or BeginWrite.
+fn page_address(function: &'static str, data_buffer_page: *mut c_char) -> Result<*const u8> {
+    if data_buffer_page.is_null() {
+        return Err(PlcnextError::system(function, "GDS buffer page is null"));
+    }
+    Ok(data_buffer_page as *const u8)
+}
+
+// Checks that a port lies within the known part of a page, before a slice of it is made.
+// A port info from another, larger buffer would otherwise reach past the end of the page.
+fn check_bounds(info: &PortInfo, page_len: usize) {
+    assert!(info.offset <= page_len && info.size <= page_len - info.offset,
+        "Port at offset {} with {} bytes is outside the {} known bytes of the GDS buffer page",
+        info.offset, info.size, page_len);
+}
+
+/// A lock on a GDS buffer for reading.
+/// Dereferences to the port data in the locked buffer page.
+/// The buffer is unlocked when the guard is dropped.
+pub struct GdsReadGuard<'a> {
+    gds_buffer: &'a mut GdsBuffer,
+    page: *const u8,
+    // The part of the page that the ports of the buffer are known to lie in.
+    page_len: usize,
+    ended: bool
+}
+
+impl<'a> GdsReadGuard<'a> {
+    /// The port data in the locked buffer page.
+    pub fn as_slice(&self) -> &[u8] {
+        let info = self.gds_buffer.info;
+        self.port(&info)
+    }
+
+    // The data of any port in the locked buffer page.
+    // The port info must come from the same GDS buffer.
+    // Panics if the port doesn't lie within the known part of the page.
+    pub(crate) fn port(&self, info: &PortInfo) -> &[u8] {
+        check_bounds(info, self.page_len);
+        unsafe { slice::from_raw_parts(self.page.add(info.offset), info.size) }
+    }
+
+    /// Unlocks the GDS buffer.
+    /// Use this instead of dropping the guard to find out if the unlock failed.
+    pub fn end(mut self) -> Result<()> {
+        self.end_read()
+    }
+
+    // Ends the read operation, if it hasn't been ended already.
+    fn end_read(&mut self) -> Result<()> {
+        if self.ended {
+            return Ok(());
+        }
+        self.ended = true;
+        if !unsafe { sys::ArpPlcGds_EndRead(self.gds_buffer.buffer) } {
+            // Log::Error("ArpPlcGds_EndRead failed");
+            return Err(PlcnextError::last_error("ArpPlcGds_EndRead"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Deref for GdsReadGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<'a> Drop for GdsReadGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(error) = self.end_read() {
+            report_drop_error("ArpPlcGds_EndRead", &error);
+        }
+    }
+}
+
+/// A lock on a GDS buffer for writing.
+/// Dereferences to the port data in the locked buffer page.
+/// The buffer is unlocked when the guard is dropped.
+pub struct GdsWriteGuard<'a> {
+    gds_buffer: &'a mut GdsBuffer,
+    page: *mut u8,
+    // The part of the page that the ports of the buffer are known to lie in.
+    page_len: usize,
+    ended: bool
+}
+
+impl<'a> GdsWriteGuard<'a> {
+    /// The port data in the locked buffer page.
+    pub fn as_slice(&self) -> &[u8] {
+        let info = self.gds_buffer.info;
+        check_bounds(&info, self.page_len);
+        unsafe { slice::from_raw_parts(self.page.add(info.offset), info.size) }
+    }
+
+    /// The port data in the locked buffer page, for writing.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let info = self.gds_buffer.info;
+        self.port_mut(&info)
+    }
+
+    // The data of any port in the locked buffer page, for writing.
+    // The port info must come from the same GDS buffer.
+    // Panics if the port doesn't lie within the known part of the page.
+    pub(crate) fn port_mut(&mut self, info: &PortInfo) -> &mut [u8] {
+        check_bounds(info, self.page_len);
+        unsafe { slice::from_raw_parts_mut(self.page.add(info.offset), info.size) }
+    }
+
+    /// Unlocks the GDS buffer.
+    /// Use this instead of dropping the guard to find out if the unlock failed.
+    pub fn end(mut self) -> Result<()> {
+        self.end_write()
+    }
+
+    // Ends the write operation, if it hasn't been ended already.
+    fn end_write(&mut self) -> Result<()> {
+        if self.ended {
+            return Ok(());
+        }
+        self.ended = true;
+        if !unsafe { sys::ArpPlcGds_EndWrite(self.gds_buffer.buffer) } {
+            // Log::Error("ArpPlcGds_EndWrite failed");
+            return Err(PlcnextError::last_error("ArpPlcGds_EndWrite"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Deref for GdsWriteGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<'a> DerefMut for GdsWriteGuard<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a> Drop for GdsWriteGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(error) = self.end_write() {
+            report_drop_error("ArpPlcGds_EndWrite", &error);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::simulation::{self, simulation, PortDirection, SimulatedPort};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Configures an input frame with one port, and an output frame with two.
+    fn configure() -> SystemHandle {
+        simulation().configure(&[
+            SimulatedPort::new("Arp.Io.AxlC/0.DI16", PortDirection::Input, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new("Arp.Io.AxlC/1.DO16", PortDirection::Output, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new("Arp.Io.AxlC/2.DO32", PortDirection::Output, PortDataType::UInt32).unwrap()
+        ]).unwrap();
+        SystemHandle::new()
+    }
+
+    #[test]
+    fn ports_in_the_same_page() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let mut buffer = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16").unwrap();
+        let other = buffer.port_info("Arp.Io.AxlC/2.DO32").unwrap();
+        assert_eq!((other.offset, other.size), (2, 4));
+        assert!(buffer.port_info("Arp.Io.AxlC/0.DI16").is_err());
+
+        let mut page = buffer.write().unwrap();
+        page.port_mut(&other).copy_from_slice(&[1, 2, 3, 4]);
+        page.as_mut_slice().copy_from_slice(&[5, 6]);
+        page.end().unwrap();
+        let page = buffer.read().unwrap();
+        assert_eq!(page.port(&other), [1, 2, 3, 4]);
+        assert_eq!(&*page, [5, 6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the 2 known bytes of the GDS buffer page")]
+    fn port_outside_the_page() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let mut output = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/2.DO32").unwrap();
+        let mut input = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16").unwrap();
+        // The info of a port in the larger output page reaches past the end of the input page.
+        let info = output.port_info("Arp.Io.AxlC/2.DO32").unwrap();
+        let page = input.read().unwrap();
+        page.port(&info);
+    }
+
+    #[test]
+    fn drop_error_handler_can_replace_itself() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let _simulation = simulation::exclusive();
+        let system = configure();
+
+        // The handler is called without the handler lock held, so it can remove itself.
+        set_drop_error_handler(Some(|error: &PlcnextError| {
+            assert_eq!(error.to_string(), "ArpPlcIo_ReleaseGdsBuffer failed: Buffer lost");
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            set_drop_error_handler::<fn(&PlcnextError)>(None);
+        }));
+        let buffer = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16").unwrap();
+        simulation().fail_next("ArpPlcIo_ReleaseGdsBuffer", "Buffer lost");
+        drop(buffer);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+        // Without a handler, the error is only logged.
+        let buffer = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16").unwrap();
+        simulation().fail_next("ArpPlcIo_ReleaseGdsBuffer", "Buffer lost");
+        drop(buffer);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn guard_holds_the_lock_until_dropped() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let mut reader = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16").unwrap();
+        let mut writer = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16").unwrap();
+
+        let page = reader.read().unwrap();
+        let (sender, written) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            writer.write().unwrap().as_mut_slice().copy_from_slice(&[1, 2]);
+            sender.send(()).unwrap();
+        });
+        assert!(written.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(&*page, [0, 0]);
+        drop(page);
+        written.recv_timeout(Duration::from_secs(1)).unwrap();
+        thread.join().unwrap();
+        assert_eq!(&*reader.read().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn failed_lock_and_unlock() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let mut buffer = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16").unwrap();
+
+        simulation().fail_next("ArpPlcGds_BeginWrite", "No page");
+        match buffer.write() {
+            Err(PlcnextError::System(e)) => assert_eq!(e.to_string(), "ArpPlcGds_BeginWrite failed: No page"),
+            Err(e) => panic!("Expected a system error, got {}", e),
+            Ok(_) => panic!("Expected a system error")
+        }
+
+        // An error from end() is returned, and not reported again when the guard is dropped.
+        simulation().fail_next("ArpPlcGds_EndWrite", "Still busy");
+        let page = buffer.write().unwrap();
+        match page.end() {
+            Err(PlcnextError::System(e)) => assert_eq!(e.to_string(), "ArpPlcGds_EndWrite failed: Still busy"),
+            other => panic!("Expected a system error, got {:?}", other)
+        }
+
+        // Releasing the buffer unlocks its page, so another buffer can lock it.
+        buffer.release().unwrap();
+        let mut other = GdsBuffer::new(&system, "Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16").unwrap();
+        other.read().unwrap().end().unwrap();
+
+        simulation().fail_next("ArpPlcIo_ReleaseGdsBuffer", "Busy");
+        assert_eq!(other.release().unwrap_err().to_string(), "ArpPlcIo_ReleaseGdsBuffer failed: Busy");
+    }
+}
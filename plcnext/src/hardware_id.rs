@@ -0,0 +1,291 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The unique hardware id of a controller, as returned by get_unique_hardware_id().
+///
+/// The id is displayed as 64 lowercase hex digits, and can also be formatted as base64
+/// with to_base64(). Both formats can be parsed with from_str(). Ids are compared in
+/// constant time, so that comparing an id with a stored one doesn't reveal how many
+/// leading bytes match. With the serde feature, the id is serialized as a hex string
+/// in human-readable formats, and as 32 bytes in binary formats.
+#[derive(Clone, Copy, Eq)]
+pub struct UniqueHardwareId([u8; 32]);
+
+impl UniqueHardwareId {
+    /// Creates an id from its bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> UniqueHardwareId {
+        UniqueHardwareId(bytes)
+    }
+
+    /// The bytes of the id.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The id as 64 lowercase hex digits.
+    pub fn to_hex(&self) -> String {
+        self.to_string()
+    }
+
+    /// The id in standard base64, with padding.
+    pub fn to_base64(&self) -> String {
+        let mut encoded = String::with_capacity(44);
+        for chunk in self.0.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+
+    /// Parses 64 hex digits, in upper or lower case.
+    pub fn from_hex(hex: &str) -> Result<UniqueHardwareId> {
+        let digits = hex.as_bytes();
+        if digits.len() != 64 {
+            return Err(PlcnextError::invalid_value(&format!("A hardware id has 64 hex digits, not {}", digits.len())));
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            *byte = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+        }
+        Ok(UniqueHardwareId(bytes))
+    }
+
+    /// Parses standard base64, with or without padding.
+    pub fn from_base64(base64: &str) -> Result<UniqueHardwareId> {
+        let digits = base64.trim_end_matches('=').as_bytes();
+        if digits.len() != 43 || base64.len() > 44 {
+            return Err(PlcnextError::invalid_value("A hardware id in base64 has 43 characters, plus one '=' of padding"));
+        }
+        let mut bytes = [0u8; 32];
+        let mut n: u32 = 0;
+        for (index, &digit) in digits.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|&c| c == digit).ok_or_else(|| PlcnextError::invalid_value(
+                &format!("'{}' is not a base64 character", digit as char)))?;
+            n = (n << 6) | value as u32;
+            if index % 4 == 3 {
+                let start = index / 4 * 3;
+                bytes[start..start + 3].copy_from_slice(&n.to_be_bytes()[1..]);
+                n = 0;
+            }
+        }
+        // The last 43 - 40 = 3 characters hold 18 bits, of which the last 2 are padding.
+        if n & 0x3 != 0 {
+            return Err(PlcnextError::invalid_value("The base64 hardware id has non-zero padding bits"));
+        }
+        bytes[30..].copy_from_slice(&((n >> 2) as u16).to_be_bytes());
+        Ok(UniqueHardwareId(bytes))
+    }
+}
+
+pub(crate) fn hex_digit(digit: u8) -> Result<u8> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err(PlcnextError::invalid_value(&format!("'{}' is not a hex digit", digit as char)))
+    }
+}
+
+// Every byte is compared, whatever the result, so the time doesn't depend on the data.
+impl PartialEq for UniqueHardwareId {
+    fn eq(&self, other: &UniqueHardwareId) -> bool {
+        let difference = self.0.iter().zip(other.0.iter()).fold(0u8, |d, (a, b)| d | (a ^ b));
+        unsafe { std::ptr::read_volatile(&difference) == 0 }
+    }
+}
+
+// Hashes the same bytes that are compared by eq().
+impl Hash for UniqueHardwareId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Display for UniqueHardwareId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UniqueHardwareId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UniqueHardwareId({})", self)
+    }
+}
+
+/// Parses 64 hex digits, or base64 with or without padding.
+impl FromStr for UniqueHardwareId {
+    type Err = PlcnextError;
+
+    fn from_str(s: &str) -> Result<UniqueHardwareId> {
+        match s.len() {
+            64 => UniqueHardwareId::from_hex(s),
+            43 | 44 => UniqueHardwareId::from_base64(s),
+            other => Err(PlcnextError::invalid_value(&format!(
+                "A hardware id has 64 hex digits or 43 base64 characters, not {} characters", other)))
+        }
+    }
+}
+
+impl From<[u8; 32]> for UniqueHardwareId {
+    fn from(bytes: [u8; 32]) -> UniqueHardwareId {
+        UniqueHardwareId(bytes)
+    }
+}
+
+impl From<UniqueHardwareId> for [u8; 32] {
+    fn from(id: UniqueHardwareId) -> [u8; 32] {
+        id.0
+    }
+}
+
+impl AsRef<[u8]> for UniqueHardwareId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for UniqueHardwareId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for UniqueHardwareId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<UniqueHardwareId, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = UniqueHardwareId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a hardware id as 64 hex digits, base64 or 32 bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> std::result::Result<UniqueHardwareId, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, value: &[u8]) -> std::result::Result<UniqueHardwareId, E> {
+                let mut bytes = [0u8; 32];
+                if value.len() != bytes.len() {
+                    return Err(E::invalid_length(value.len(), &self));
+                }
+                bytes.copy_from_slice(value);
+                Ok(UniqueHardwareId(bytes))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<UniqueHardwareId, A::Error> {
+                let mut bytes = [0u8; 32];
+                for (index, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq.next_element()?.ok_or_else(|| serde::de::Error::invalid_length(index, &self))?;
+                }
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(serde::de::Error::invalid_length(33, &self));
+                }
+                Ok(UniqueHardwareId(bytes))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(Visitor)
+        } else {
+            deserializer.deserialize_bytes(Visitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const BASE64: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn counting_id() -> UniqueHardwareId {
+        let mut bytes = [0u8; 32];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        UniqueHardwareId::from_bytes(bytes)
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let id = counting_id();
+        assert_eq!(id.to_hex(), HEX);
+        assert_eq!(UniqueHardwareId::from_hex(HEX).unwrap(), id);
+        assert_eq!(UniqueHardwareId::from_hex(&HEX.to_uppercase()).unwrap(), id);
+        assert_eq!(HEX.parse::<UniqueHardwareId>().unwrap(), id);
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let id = counting_id();
+        assert_eq!(id.to_base64(), BASE64);
+        assert_eq!(UniqueHardwareId::from_base64(BASE64).unwrap(), id);
+        assert_eq!(UniqueHardwareId::from_base64(BASE64.trim_end_matches('=')).unwrap(), id);
+        assert_eq!(BASE64.parse::<UniqueHardwareId>().unwrap(), id);
+
+        for fill in &[0x00, 0xff, 0x5a] {
+            let id = UniqueHardwareId::from_bytes([*fill; 32]);
+            assert_eq!(UniqueHardwareId::from_base64(&id.to_base64()).unwrap(), id);
+            assert_eq!(id.to_hex().parse::<UniqueHardwareId>().unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn wrong_lengths() {
+        assert!(UniqueHardwareId::from_hex(&HEX[..62]).is_err());
+        assert!(UniqueHardwareId::from_hex(&format!("{}00", HEX)).is_err());
+        assert!(UniqueHardwareId::from_base64(&BASE64[..42]).is_err());
+        assert!(UniqueHardwareId::from_base64(&format!("{}=", BASE64)).is_err());
+        assert!(UniqueHardwareId::from_base64(&format!("A{}", BASE64)).is_err());
+        assert!("".parse::<UniqueHardwareId>().is_err());
+        assert!(HEX[..63].parse::<UniqueHardwareId>().is_err());
+    }
+
+    #[test]
+    fn invalid_characters() {
+        assert!(UniqueHardwareId::from_hex(&HEX.replace('a', "g")).is_err());
+        assert!(UniqueHardwareId::from_base64(&BASE64.replace('A', "-")).is_err());
+        // A non-ASCII character takes two bytes, so the length still fits.
+        let hex = format!("{}é", &HEX[..62]);
+        assert_eq!(hex.len(), 64);
+        assert!(UniqueHardwareId::from_hex(&hex).is_err());
+        assert!(hex.parse::<UniqueHardwareId>().is_err());
+        let base64 = format!("{}é", &BASE64[..41]);
+        assert_eq!(base64.len(), 43);
+        assert!(UniqueHardwareId::from_base64(&base64).is_err());
+        assert!(base64.parse::<UniqueHardwareId>().is_err());
+    }
+
+    #[test]
+    fn non_zero_padding_bits() {
+        // '9' is '8' with the lowest bit set, which is one of the two padding bits.
+        let base64 = BASE64.replace("Hh8=", "Hh9=");
+        assert!(UniqueHardwareId::from_base64(&base64).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::batch::{PortBatch, PortSnapshot};
+use crate::error::Result;
+use crate::SystemHandle;
+
+/// A struct whose fields are mapped onto a set of GDS ports,
+/// so that the whole struct can be moved in and out of the GDS at once.
+/// This is usually derived with #[derive(ProcessImage)], where each field
+/// carries the name of its port, e.g. #[port("Arp.Io.AxlC/0.DI16")].
+///
+/// The ports are read and written through a PortBatch, so each GDS buffer
+/// is locked once, and the fields are a consistent snapshot of the ports.
+pub trait ProcessImage {
+    /// The ports that are read into the struct, as pairs of fieldbus I/O system name and port name.
+    const INPUT_PORTS: &'static [(&'static str, &'static str)];
+
+    /// The ports that the struct is written to, as pairs of fieldbus I/O system name and port name.
+    const OUTPUT_PORTS: &'static [(&'static str, &'static str)];
+
+    /// Decodes the fields from a snapshot of the input ports.
+    /// * 'batch' - A batch of INPUT_PORTS, in that order
+    /// * 'snapshot' - A snapshot read by 'batch'
+    fn load(&mut self, batch: &PortBatch, snapshot: &PortSnapshot) -> Result<()>;
+
+    /// Encodes the fields into a snapshot of the output ports.
+    /// * 'batch' - A batch of OUTPUT_PORTS, in that order
+    /// * 'snapshot' - A snapshot created by 'batch'
+    fn store(&self, batch: &PortBatch, snapshot: &mut PortSnapshot) -> Result<()>;
+
+    /// Reads all input ports into the struct.
+    /// The ports are looked up in the port registry on every call, so a cyclic task
+    /// should keep a PortBatch of INPUT_PORTS and call load() instead, as CyclicTask does.
+    /// * 'system' - The handle returned by load()
+    fn read_all(&mut self, system: &SystemHandle) -> Result<()> {
+        let batch = PortBatch::resolve(system, Self::INPUT_PORTS)?;
+        let snapshot = batch.read()?;
+        self.load(&batch, &snapshot)
+    }
+
+    /// Writes the struct to all output ports.
+    /// Nothing is written if any field can't be stored in its port.
+    /// Like read_all(), this looks up the ports on every call.
+    /// * 'system' - The handle returned by load()
+    fn write_all(&self, system: &SystemHandle) -> Result<()> {
+        let batch = PortBatch::resolve(system, Self::OUTPUT_PORTS)?;
+        let mut snapshot = batch.snapshot();
+        self.store(&batch, &mut snapshot)?;
+        batch.write_snapshot(&snapshot)
+    }
+}
+
+// The batch of an image's ports, with a snapshot that is reused in every cycle.
+// The ports are resolved on first use, and again once their handles have been invalidated,
+// e.g. after the PLC was reset.
+pub(crate) struct ImageBatch {
+    ports: &'static [(&'static str, &'static str)],
+    resolved: Option<(PortBatch, PortSnapshot)>
+}
+
+impl ImageBatch {
+    pub(crate) fn new(ports: &'static [(&'static str, &'static str)]) -> ImageBatch {
+        ImageBatch { ports, resolved: None }
+    }
+
+    // Reads the ports into an image.
+    pub(crate) fn read<I: ProcessImage>(&mut self, system: &SystemHandle, image: &mut I) -> Result<()> {
+        let (batch, snapshot) = self.resolve(system)?;
+        batch.read_into(snapshot)?;
+        image.load(batch, snapshot)
+    }
+
+    // Writes an image to the ports.
+    pub(crate) fn write<O: ProcessImage>(&mut self, system: &SystemHandle, image: &O) -> Result<()> {
+        let (batch, snapshot) = self.resolve(system)?;
+        image.store(batch, snapshot)?;
+        batch.write_snapshot(snapshot)
+    }
+
+    fn resolve(&mut self, system: &SystemHandle) -> Result<&mut (PortBatch, PortSnapshot)> {
+        let stale = match &self.resolved {
+            Some((batch, _)) => batch.ports().iter().any(|port| !port.is_valid()),
+            None => true
+        };
+        if stale {
+            let batch = PortBatch::resolve(system, self.ports)?;
+            let snapshot = batch.snapshot();
+            self.resolved = Some((batch, snapshot));
+        }
+        Ok(self.resolved.as_mut().unwrap())
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::layout::PortDataType;
+    use crate::simulation::{self, simulation, PortDirection, SimulatedPort};
+    use crate::PlcOperation;
+
+    use std::sync::Arc;
+
+    // What #[derive(ProcessImage)] generates, which can't be used inside this crate.
+    #[derive(Default)]
+    struct Io {
+        inputs: u16,
+        outputs: u16
+    }
+
+    impl ProcessImage for Io {
+        const INPUT_PORTS: &'static [(&'static str, &'static str)] = &[("Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16")];
+        const OUTPUT_PORTS: &'static [(&'static str, &'static str)] = &[("Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16")];
+
+        fn load(&mut self, batch: &PortBatch, snapshot: &PortSnapshot) -> Result<()> {
+            self.inputs = batch.value(snapshot, 0)?;
+            Ok(())
+        }
+
+        fn store(&self, batch: &PortBatch, snapshot: &mut PortSnapshot) -> Result<()> {
+            batch.set_value(snapshot, 0, &self.outputs)
+        }
+    }
+
+    fn configure() -> SystemHandle {
+        simulation().configure(&[
+            SimulatedPort::new("Arp.Io.AxlC/0.DI16", PortDirection::Input, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new("Arp.Io.AxlC/1.DO16", PortDirection::Output, PortDataType::UInt16).unwrap()
+        ]).unwrap();
+        simulation().trigger(PlcOperation::Unload);
+        SystemHandle::new()
+    }
+
+    #[test]
+    fn read_all_and_write_all() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        simulation().set_input("Arp.Io.AxlC/0.DI16", &0x0102u16.to_ne_bytes()).unwrap();
+        crate::read_from_axio_to_gds(&system, 100).unwrap();
+
+        let mut io = Io::default();
+        io.read_all(&system).unwrap();
+        assert_eq!(io.inputs, 0x0102);
+        io.outputs = io.inputs + 1;
+        io.write_all(&system).unwrap();
+        crate::write_from_gds_to_axio(&system, 100).unwrap();
+        assert_eq!(simulation().output("Arp.Io.AxlC/1.DO16").unwrap(), 0x0103u16.to_ne_bytes());
+    }
+
+    #[test]
+    fn image_batch_is_resolved_again_after_reset() {
+        let _simulation = simulation::exclusive();
+        let system = configure();
+        let mut inputs = ImageBatch::new(Io::INPUT_PORTS);
+        let mut outputs = ImageBatch::new(Io::OUTPUT_PORTS);
+        let mut io = Io { inputs: 0, outputs: 5 };
+        outputs.write(&system, &io).unwrap();
+        let first = outputs.resolved.as_ref().unwrap().0.ports()[0].clone();
+
+        // The batch is kept while its ports are valid.
+        outputs.write(&system, &io).unwrap();
+        assert!(Arc::ptr_eq(&first, &outputs.resolved.as_ref().unwrap().0.ports()[0]));
+
+        simulation().trigger(PlcOperation::Reset);
+        io.outputs = 6;
+        outputs.write(&system, &io).unwrap();
+        assert!(!Arc::ptr_eq(&first, &outputs.resolved.as_ref().unwrap().0.ports()[0]));
+        inputs.read(&system, &mut io).unwrap();
+
+        let mut value = [0u8; 2];
+        crate::read_port_data(&system, "Arp.Io.AxlC/1.DO16", &mut value).unwrap();
+        assert_eq!(value, 6u16.to_ne_bytes());
+    }
+}
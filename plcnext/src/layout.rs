@@ -0,0 +1,110 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::sys;
+
+use std::ffi::CStr;
+
+/// The data type of a port, as reported by the GDS data layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortDataType {
+    Bit,
+    Boolean,
+    UInt8,
+    Int8,
+    Char8,
+    Char16,
+    UInt16,
+    Int16,
+    UInt32,
+    Int32,
+    UInt64,
+    Int64,
+    Float32,
+    Float64,
+    IecTime,
+    IecTime64,
+    StaticString,
+    IecString,
+    /// A data type code that isn't known to this crate.
+    Other(u32)
+}
+
+impl PortDataType {
+    // Converts the data type code from the ANSI-C library,
+    // which uses the same values as Arp::DataType.
+    fn from_raw(data_type: u32) -> PortDataType {
+        match data_type {
+            2 => PortDataType::Bit,
+            3 => PortDataType::Boolean,
+            4 => PortDataType::UInt8,
+            5 => PortDataType::Int8,
+            6 => PortDataType::Char8,
+            7 => PortDataType::Char16,
+            8 => PortDataType::UInt16,
+            9 => PortDataType::Int16,
+            10 => PortDataType::UInt32,
+            11 => PortDataType::Int32,
+            12 => PortDataType::UInt64,
+            13 => PortDataType::Int64,
+            14 => PortDataType::Float32,
+            15 => PortDataType::Float64,
+            34 => PortDataType::IecTime,
+            35 => PortDataType::IecTime64,
+            42 => PortDataType::StaticString,
+            43 => PortDataType::IecString,
+            other => PortDataType::Other(other)
+        }
+    }
+
+    // Converts the data type into the code used by the ANSI-C library.
+    #[cfg(feature = "simulation")]
+    pub(crate) fn to_raw(self) -> u32 {
+        match self {
+            PortDataType::Bit => 2,
+            PortDataType::Boolean => 3,
+            PortDataType::UInt8 => 4,
+            PortDataType::Int8 => 5,
+            PortDataType::Char8 => 6,
+            PortDataType::Char16 => 7,
+            PortDataType::UInt16 => 8,
+            PortDataType::Int16 => 9,
+            PortDataType::UInt32 => 10,
+            PortDataType::Int32 => 11,
+            PortDataType::UInt64 => 12,
+            PortDataType::Int64 => 13,
+            PortDataType::Float32 => 14,
+            PortDataType::Float64 => 15,
+            PortDataType::IecTime => 34,
+            PortDataType::IecTime64 => 35,
+            PortDataType::StaticString => 42,
+            PortDataType::IecString => 43,
+            PortDataType::Other(other) => other
+        }
+    }
+}
+
+/// The layout of a port in its GDS buffer page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortInfo {
+    /// The offset to the port data in the GDS buffer page.
+    pub offset: usize,
+    /// The size of the port data in bytes.
+    pub size: usize,
+    /// The data type of the port.
+    pub data_type: PortDataType
+}
+
+// Gets the layout of the named port from the GDS data layout.
+pub(crate) fn get_port_info(gds_buffer: *mut sys::TGdsBuffer, port_name: &CStr) -> Result<PortInfo> {
+    let mut layout_info: sys::TDataLayoutInfo = unsafe { std::mem::zeroed() };
+    if !unsafe { sys::ArpPlcGds_GetDataLayoutInfo(gds_buffer, port_name.as_ptr(), &mut layout_info) } {
+        // Log::Error("ArpPlcGds_GetDataLayoutInfo failed");
+        return Err(PlcnextError::last_error("ArpPlcGds_GetDataLayoutInfo"));
+    }
+
+    Ok(PortInfo {
+        offset: layout_info.Offset as usize,
+        size: layout_info.Size as usize,
+        data_type: PortDataType::from_raw(layout_info.DataType as u32)
+    })
+}
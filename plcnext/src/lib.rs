@@ -1,18 +1,116 @@
+#[macro_use]
+extern crate lazy_static;
+
 // Include plcnext services
+mod batch;
+mod cyclic;
 mod error;
-
-use error::Result;
-use error::PlcnextError;
+mod events;
+mod fault;
+#[cfg(feature = "simulation")]
+mod fieldbus;
+mod gds;
+mod hardware_id;
+mod image;
+mod layout;
+#[cfg(feature = "licensing")]
+mod licensing;
+mod port_name;
+mod realtime;
+mod recorder;
+mod registry;
+#[cfg(feature = "simulation")]
+mod simulation;
+mod state;
+mod sys;
+mod timing;
+mod value;
+#[cfg(feature = "simulation")]
+mod waveform;
+
+pub use batch::PortBatch;
+pub use batch::PortSnapshot;
+pub use cyclic::CyclicTask;
+pub use cyclic::CyclicTaskHandle;
+pub use cyclic::StopHandle;
+pub use events::PlcEvent;
+pub use events::SubscriptionId;
+pub use events::operations;
+#[cfg(feature = "stream")]
+pub use events::operation_stream;
+pub use events::subscribe;
+pub use events::unsubscribe;
+pub use fault::FaultPolicy;
+pub use fault::FaultReason;
+pub use fault::SubstituteValue;
+#[cfg(feature = "simulation")]
+pub use fieldbus::SimulatedFieldbus;
+pub use gds::GdsBuffer;
+pub use gds::GdsReadGuard;
+pub use gds::GdsWriteGuard;
+pub use gds::set_drop_error_handler;
+pub use hardware_id::UniqueHardwareId;
+#[cfg(feature = "licensing")]
+pub use licensing::License;
+#[cfg(feature = "licensing")]
+pub use licensing::LicenseError;
+pub use realtime::RealtimeConfig;
+pub use realtime::RealtimeError;
+pub use realtime::RealtimeSetting;
+pub use recorder::Record;
+pub use recorder::RecordKind;
+pub use recorder::RecordedLog;
+pub use recorder::Recorder;
+pub use recorder::recorder;
+pub use registry::PortRegistry;
+pub use registry::PortHandle;
+pub use registry::port_registry;
+#[cfg(feature = "simulation")]
+pub use simulation::PortDirection;
+#[cfg(feature = "simulation")]
+pub use simulation::SimulatedPort;
+#[cfg(feature = "simulation")]
+pub use simulation::Simulation;
+#[cfg(feature = "simulation")]
+pub use simulation::simulation;
+pub use state::PlcState;
+pub use state::PlcStateTracker;
+pub use state::IllegalTransition;
+pub use state::UnknownOperation;
+pub use state::plc_state;
+pub use timing::CycleStats;
+pub use timing::CycleStatsHandle;
+pub use timing::PhaseStats;
+pub use timing::JitterHistogram;
+pub use timing::JITTER_BUCKETS;
+pub use value::PortValue;
+pub use value::BigEndian;
+pub use value::LittleEndian;
+pub use value::IecTime;
+pub use value::read_port;
+pub use value::write_port;
+#[cfg(feature = "simulation")]
+pub use waveform::Waveform;
+pub use image::ProcessImage;
+pub use plcnext_derive::ProcessImage;
+
+pub use error::PlcnextError;
+pub use error::SystemError;
+pub use error::ParameterError;
+pub use error::LifecycleError;
+pub use error::Result;
+pub use layout::PortDataType;
+pub use layout::PortInfo;
+pub use port_name::PortName;
 
 use std::ffi::CStr;
 use std::ffi::CString;
-use std::os::raw::c_char;
 use std::os::raw::c_ulong;
-use std::slice;
-use std::sync::{Once, ONCE_INIT};
+use std::sync::{Mutex, Once};
 
 const MAX_ERROR_LENGTH: usize = 512;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PlcOperation {
     None = 0,
     Load = 1,
@@ -26,290 +124,250 @@ pub enum PlcOperation {
     Unknown = 99
 }
 
-static mut CALLBACK: Option<Box<FnMut(PlcOperation)>> = None;
-
-// The callback function for the plcnext-sys crate
-extern fn handle_event(operation: plcnext_sys::PlcOperation) {
+// The callback function for the plcnext-sys crate, or the simulation
+extern "C" fn handle_event(operation: sys::PlcOperation) {
     // Pass the operation straight through to our client
     // TODO: Use the num_enum crate to convert the primitive into our enum
-    // The Box around callback is immutable so we can't borrow it for the match.
-    // Instead, we must get a mutable reference using as_mut().
-    // We could also write the compare as:
-    //     if let Some(ref mut cb) = callback
-    unsafe { if let Some(cb) = CALLBACK.as_mut() {
-        cb (match operation {
-            plcnext_sys::PlcOperation_PlcOperation_Load => PlcOperation::Load,
-            plcnext_sys::PlcOperation_PlcOperation_Setup => PlcOperation::Setup,
-            plcnext_sys::PlcOperation_PlcOperation_StartCold => PlcOperation::StartCold,
-            plcnext_sys::PlcOperation_PlcOperation_StartWarm => PlcOperation::StartWarm,
-            plcnext_sys::PlcOperation_PlcOperation_StartHot => PlcOperation::StartHot,
-            plcnext_sys::PlcOperation_PlcOperation_Stop => PlcOperation::Stop,
-            plcnext_sys::PlcOperation_PlcOperation_Reset => PlcOperation::Reset,
-            plcnext_sys::PlcOperation_PlcOperation_Unload => PlcOperation::Unload,
-            plcnext_sys::PlcOperation_PlcOperation_None => PlcOperation::None,
-            _ => PlcOperation::Unknown
-        });
-}}}
+    // The cast is only needed with the simulation, where the code is a c_int.
+    #[allow(clippy::unnecessary_cast)]
+    let code = operation as u32;
+    let operation = match operation {
+        sys::PlcOperation_PlcOperation_Load => PlcOperation::Load,
+        sys::PlcOperation_PlcOperation_Setup => PlcOperation::Setup,
+        sys::PlcOperation_PlcOperation_StartCold => PlcOperation::StartCold,
+        sys::PlcOperation_PlcOperation_StartWarm => PlcOperation::StartWarm,
+        sys::PlcOperation_PlcOperation_StartHot => PlcOperation::StartHot,
+        sys::PlcOperation_PlcOperation_Stop => PlcOperation::Stop,
+        sys::PlcOperation_PlcOperation_Reset => PlcOperation::Reset,
+        sys::PlcOperation_PlcOperation_Unload => PlcOperation::Unload,
+        sys::PlcOperation_PlcOperation_None => PlcOperation::None,
+        _ => PlcOperation::Unknown
+    };
+
+    // Cached port handles don't survive a reset or unload, so they are invalidated
+    // after the subscribers have had a chance to handle the operation.
+    let invalidate_ports = matches!(operation, PlcOperation::Reset | PlcOperation::Unload);
+
+    // The state tracker is updated first, so that subscribers see the new state.
+    // Unknown operation codes are recorded by the tracker.
+    state::track(code, operation);
+
+    // Pass the operation to every subscriber.
+    // Panics in subscribers are caught, so they don't unwind into the ANSI-C library.
+    events::dispatch(operation);
+
+    if invalidate_ports {
+        registry::invalidate_port_registry();
+    }
+}
 
-// The handler can be set before the load function is called,
-// so that the user can receive all events as the system is loaded.
-// The user's event handler must have static lifetime because it is
-// boxed and stored in the static System instance.
-pub fn set_handler<CB: 'static + FnMut(PlcOperation)>(handler: Option<CB>) {
-    // Set or reset our own callback function,
-    // and also save the user's event handler.
-    unsafe {
-        CALLBACK = match handler {
-            Some(cb) => {
-                plcnext_sys::ArpPlcDomain_SetHandler(Some(handle_event));
-                Some(Box::new(cb))
-            },
-            None => {
-                plcnext_sys::ArpPlcDomain_SetHandler(None);
-                None
-}}}}
-
-// Load the ARP system module.
-// This must be called  before any system methods,
-// so this is the only way to get a reference
-// to the SYSTEM singleton.
-// Function parameters will be ignored on the second and subsequent calls, but 
-// a reference to SYSTEM will always be returned.
-pub fn load(arp_binary_dir: &str , application_name: &str, acf_settings_path: &str) {
-    // The system must only ever be initialised once
-    static INIT: Once = ONCE_INIT;
-    INIT.call_once(|| {
-        // Create CStrings from inputs, for C compatibility
-        let arp_binary_dir = CString::new(arp_binary_dir).expect("CString::new(arp_binary_dir) failed");
-        let application_name = CString::new(application_name).expect("CString::new(application_name) failed");
-        let acf_settings_path = CString::new(acf_settings_path).expect("CString::new(acf_settings_path) failed");
-
-        // Create pointers to strings
-        let raw_arp_binary_dir = arp_binary_dir.into_raw();
-        let raw_application_name = application_name.into_raw();
-        let raw_acf_settings_path = acf_settings_path.into_raw();
-
-        // Call the C function
-        unsafe {
-            let _result = plcnext_sys::ArpSystemModule_Load(raw_arp_binary_dir, raw_application_name, raw_acf_settings_path);
-
-            // Retake pointers to free memory
-            let _ = CString::from_raw(raw_arp_binary_dir);
-            let _ = CString::from_raw(raw_application_name);
-            let _ = CString::from_raw(raw_acf_settings_path);
-
-            // TODO: Evaluate result and return error if necessary
-            // result
-        }
+// Registers our own callback function with the plcnext-sys crate.
+// This is done once, and the callback stays registered even when all
+// subscribers are removed, because the port registry also depends on it.
+pub(crate) fn install_event_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        unsafe { sys::ArpPlcDomain_SetHandler(Some(handle_event)); }
     });
 }
 
-/// Gets the unique hardware id.
-/// If an internal error occurs during the operation, an error will be returned.
-/// In this case, get the last error message with get_last_error().
-pub fn get_unique_hardware_id() -> [u8; 32] {
-    let mut _success : bool = false;
-    let mut out_id : [u8; 32] = [0 ;32];
-    unsafe {
-        _success = plcnext_sys::ArpPlcDevice_GetUniqueHardwareId(out_id.as_mut_ptr());
-    }
-    // TODO: Error check on the return variable.
-    out_id
+lazy_static! {
+    // The subscription made by set_handler(), if any.
+    static ref HANDLER_SUBSCRIPTION: Mutex<Option<SubscriptionId>> = Mutex::new(None);
 }
 
-/// Transfers I/O data from the Axioline bus to the GDS.
-/// * 'timeout' - Timeout in milliseconds to wait for the event to be processed.
-///               If zero, the calling thread will block forever.
-pub fn read_from_axio_to_gds(timeout: u32) -> Result<()> {
-    if !unsafe { plcnext_sys::ArpPlcAxio_ReadFromAxioToGds(timeout as c_ulong) } {
-        return Err(PlcnextError::new(&get_last_error()));
+// The handler can be set before the load function is called,
+// so that the user can receive all events as the system is loaded.
+// This sets or replaces a single handler. Handlers registered with subscribe()
+// are independent of this one, and are not replaced.
+pub fn set_handler<CB: 'static + FnMut(PlcOperation) + Send>(handler: Option<CB>) {
+    let mut subscription = HANDLER_SUBSCRIPTION.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(id) = subscription.take() {
+        unsubscribe(id);
     }
-    Ok(())
+    *subscription = handler.map(subscribe);
 }
 
-// TODO: CREATE FUNCTIONS: gds_begin(operation: read|write), gds_end(read|write)
-// Read data from a fieldbus input frame
-// TODO: Extract the fb_io_system_name from the port name.
-// TODO: Remove fb_io_system_name parameter.
-pub fn read_input_data(fb_io_system_name: &str, port_name: &str, value: &mut[u8]) -> Result<()> {
-
-    // TODO: Validate the port name using regex.
-
-    // Get a pointer to the start of the GDS buffer containing the named port,
-    // and the offset to the named port in that buffer
-    let (gds_buffer, offset) = get_gds_port(fb_io_system_name, port_name)?;
-
-    // ======================================
-    // TODO: Replace the below with begin_gds() call
-    // Begin read operation by getting a pointer to the GDS data buffer page
-    // After this call, the GDS buffer will be locked
-    let mut data_buffer_page: *mut c_char = std::ptr::null_mut();
-    if !unsafe { plcnext_sys::ArpPlcGds_BeginRead(gds_buffer, &mut data_buffer_page) } {
-        // Log::Error("ArpPlcGds_BeginRead failed");
-        // Find out what the problem was
-        let error = get_last_error();
-
-        // Try to end the read operation
-        if !unsafe { plcnext_sys::ArpPlcGds_EndRead(gds_buffer) } {
-            // If an error occurs, just log it, but don't return it
-            // Log::Error("ArpPlcGds_BeginRead failed");
-            let _error = get_last_error();
-        }
+/// Proof that the ARP system module has been loaded.
+/// A handle is returned by load(), and every function that accesses the GDS
+/// or the Axioline bus requires one, so that these functions can't be called
+/// before the system has been loaded.
+/// The handle is a zero-sized token that can be freely copied and sent to other threads.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemHandle {
+    // Private, so that a handle can only be created by this crate.
+    _private: ()
+}
 
-        // Try to release the GDS buffer before returning
-        release_gds_buffer(gds_buffer).ok();  // TODO: Handle errors properly.
-        return Err(PlcnextError::new(&error));
+impl SystemHandle {
+    // Only called once the system is known to be loaded.
+    pub(crate) fn new() -> SystemHandle {
+        SystemHandle { _private: () }
     }
+}
 
-    // Adjust the GDS buffer pointer to the port location
-    let data_address = unsafe { data_buffer_page.offset(offset as isize) };
-
-    // ======================================
-
-    // Construct a slice from the GDS buffer pointer, and convert it to &[u8]
-    // TODO: MAKE SURE THE SIZE OF THE SLICE IS THE EXACT SIZE OF THE PORT!
-    // If we can't do this, then we must assume that the port size is the same as the data passed in ...
-    // Or, use the name of the port to guess the size of the slice.
-    // let port_data = unsafe { &mut*( slice::from_raw_parts_mut(data_address, value.len()) as *mut [i8] as *mut [u8] ) };
-    let port_data = unsafe { slice::from_raw_parts(data_address, value.len()) as &[u8] };
-
-    // Copy data from the GDS Buffer
-    value.copy_from_slice(port_data);
+// The parameters passed to the successful call to load().
+struct LoadParameters {
+    arp_binary_dir: String,
+    application_name: String,
+    acf_settings_path: String
+}
 
-    // ======================================
+lazy_static! {
+    // None until the system has been loaded successfully.
+    static ref LOADED: Mutex<Option<LoadParameters>> = Mutex::new(None);
+}
 
-    // ======================================
-    // TODO: Replace the below with end_gds() call
-    // Unlock and release the GDS data buffer page
-    if !unsafe { plcnext_sys::ArpPlcGds_EndRead(gds_buffer) } {
-        // Log::Error("ArpPlcGds_EndRead failed");
-        // Find out what the problem was
-        let error = get_last_error();
+/// Loads the ARP system module.
+/// This must be called before any function that accesses the GDS or the Axioline bus,
+/// and the returned handle must be passed to those functions.
+/// The system is only loaded once. Later calls with the same parameters
+/// return another handle, and later calls with different parameters return an error.
+/// If loading fails, the error carries the reason from the ANSI-C library,
+/// and load() can be called again.
+pub fn load(arp_binary_dir: &str, application_name: &str, acf_settings_path: &str) -> Result<SystemHandle> {
+    // The lock is held while the system is loaded, so that it is only ever loaded once.
+    let mut loaded = LOADED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(first) = loaded.as_ref() {
+        if first.arp_binary_dir != arp_binary_dir
+            || first.application_name != application_name
+            || first.acf_settings_path != acf_settings_path {
+            return Err(LifecycleError::AlreadyLoaded {
+                arp_binary_dir: first.arp_binary_dir.clone(),
+                application_name: first.application_name.clone(),
+                acf_settings_path: first.acf_settings_path.clone()
+            }.into());
+        }
+        return Ok(SystemHandle::new());
+    }
 
-        // Try to release the GDS buffer before returning
-        release_gds_buffer(gds_buffer).ok();  // TODO: Handle errors properly.
-        return Err(PlcnextError::new(&error));
+    // Create CStrings from inputs, for C compatibility.
+    // The CStrings own the memory, so nothing needs to be freed afterwards.
+    let raw_arp_binary_dir = c_string("arp_binary_dir", arp_binary_dir)?;
+    let raw_application_name = c_string("application_name", application_name)?;
+    let raw_acf_settings_path = c_string("acf_settings_path", acf_settings_path)?;
+
+    // Call the C function, which returns zero on success
+    let result = unsafe {
+        sys::ArpSystemModule_Load(raw_arp_binary_dir.as_ptr(), raw_application_name.as_ptr(), raw_acf_settings_path.as_ptr())
+    };
+    if result != 0 {
+        // Log::Error("ArpSystemModule_Load failed");
+        let message = get_last_error();
+        let message = if message.is_empty() {
+            format!("returned {}", result)
+        } else {
+            format!("returned {}: {}", result, message)
+        };
+        return Err(PlcnextError::system("ArpSystemModule_Load", &message));
     }
 
-    // Release the GdsBuffer and free internal resources
-    release_gds_buffer(gds_buffer)
-    // ======================================
+    *loaded = Some(LoadParameters {
+        arp_binary_dir: arp_binary_dir.to_string(),
+        application_name: application_name.to_string(),
+        acf_settings_path: acf_settings_path.to_string()
+    });
+    Ok(SystemHandle::new())
 }
 
-/// Write data to a fieldbus output frame
-// TODO: Add a check that the port exists, and return the size of the port ... then check that size against the size of the value array.
-// Include a note that the first call to this function with a new port_name is expensive, because it retrieves data from the system 
-// about that port, but after that the data is cached and read/writes are quicker.
-pub fn write_output_data(fb_io_system_name: &str, port_name: &str, value: &[u8]) -> Result<()> {
-
-    // We could consider validating the system name and port name here, but we will just pass them through to the 
-    // plcnext-sys library, where (we hope) they will be validated correctly.
-
-    // Get a pointer to the start of the GDS buffer containing the named port,
-    // and the offset to the named port in that buffer
-    let (gds_buffer, offset) = get_gds_port(fb_io_system_name, port_name)?;
-
-    // Begin write operation by getting a pointer to the GDS data buffer page
-    // After this call, the GDS buffer will be locked
-    let mut data_buffer_page: *mut c_char = std::ptr::null_mut();
-    if !unsafe { plcnext_sys::ArpPlcGds_BeginWrite(gds_buffer, &mut data_buffer_page) } {
-        return Err(PlcnextError::new(&get_last_error()));
-    }
-    else {
-        // Adjust the GDS buffer pointer to the port location
-        let data_address = unsafe { data_buffer_page.offset(offset as isize) };
-
-        // Construct a slice from the GDS buffer pointer, and convert it to &mut [u8]
-        // TODO: MAKE SURE THE SIZE OF THE SLICE IS THE EXACT SIZE OF THE PORT!
-        // If we can't do this, then we must assume that the port size is the same as the data passed in ...
-        // let port_data = unsafe { &mut*( slice::from_raw_parts_mut(data_address, value.len()) as *mut [i8] as *mut [u8] ) };
-        let port_data = unsafe { &mut*( slice::from_raw_parts_mut(data_address, value.len()) as *mut [u8] ) };
-
-        // Copy data to the GDS Buffer
-        port_data.copy_from_slice(value);
+/// Gets a handle to the ARP system module, if it has been loaded.
+/// This is for code that can't be passed the handle returned by load().
+pub fn system_handle() -> Result<SystemHandle> {
+    match LOADED.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(_) => Ok(SystemHandle::new()),
+        None => Err(LifecycleError::NotLoaded.into())
     }
+}
 
-    // Unlock and release the GDS data buffer page
-    if !unsafe { plcnext_sys::ArpPlcGds_EndWrite(gds_buffer) } {
-        let error = get_last_error();
-        // Try to release the GDS buffer before returning
-        // before returning the original error
-        release_gds_buffer(gds_buffer).ok();  // TODO: Handle errors properly.
-        return Err(PlcnextError::new(&error));
+/// Gets the unique hardware id of the controller.
+/// If the ANSI-C library fails to get the id, the error contains its last error message.
+pub fn get_unique_hardware_id() -> Result<UniqueHardwareId> {
+    let mut out_id: [u8; 32] = [0; 32];
+    if !unsafe { sys::ArpPlcDevice_GetUniqueHardwareId(out_id.as_mut_ptr()) } {
+        // Log::Error("ArpPlcDevice_GetUniqueHardwareId failed");
+        return Err(PlcnextError::last_error("ArpPlcDevice_GetUniqueHardwareId"));
     }
-
-    // Release the GdsBuffer and free internal resources
-    release_gds_buffer(gds_buffer)
+    Ok(UniqueHardwareId::from_bytes(out_id))
 }
 
-/// Transfers I/O data from the GDS to the Axioline bus.
+/// Transfers I/O data from the Axioline bus to the GDS.
+/// * 'system' - The handle returned by load()
 /// * 'timeout' - Timeout in milliseconds to wait for the event to be processed.
-///               If zero, the calling thread will block forever.
-pub fn write_from_gds_to_axio(timeout: u32) -> Result<()> {
-    if !unsafe { plcnext_sys::ArpPlcAxio_WriteFromGdsToAxio(timeout as c_ulong) } {
-        return Err(PlcnextError::new(&get_last_error()));
+///   If zero, the calling thread will block forever.
+pub fn read_from_axio_to_gds(_system: &SystemHandle, timeout: u32) -> Result<()> {
+    if !unsafe { sys::ArpPlcAxio_ReadFromAxioToGds(timeout as c_ulong) } {
+        return Err(PlcnextError::last_error("ArpPlcAxio_ReadFromAxioToGds"));
     }
     Ok(())
 }
 
-// Gets a pointer to the GDS buffer and the offset to the port data
-fn get_gds_port(fb_io_system_name: &str, port_name: &str)
-    -> Result<(*mut plcnext_sys::TGdsBuffer, usize)> {
-
-    // TODO: Consider keeping a static lookup table of bus/port & pointers to gds buffers
-    // This should (?) speed up reads and writes for multiple calls using the same info.
-    // (but make sure the table is thread safe)
-
-    // Create CStrings from inputs, for C compatibility
-    let fb_io_system_name = CString::new(fb_io_system_name)
-        .expect("CString::new(fb_io_system_name) failed");
-    let port_name = CString::new(port_name)
-        .expect("CString::new(port_name) failed");
-
-    // Create pointers to strings
-    let raw_fb_io_system_name = fb_io_system_name.into_raw();
-    let raw_port_name = port_name.into_raw();
-
-    // TODO: RETAKE POINTERS
-
-    // Create an opaque pointer to the GDS buffer
-    let mut gds_buffer: *mut plcnext_sys::TGdsBuffer = std::ptr::null_mut();
-
-    // Assign the pointer to the start of the GDS buffer containing the named port
-    if !unsafe {plcnext_sys::ArpPlcIo_GetBufferPtrByPortName(raw_fb_io_system_name, raw_port_name, &mut gds_buffer)} {
-        // Log::Error("ArpPlcIo_GetBufferPtrByPortName failed");
-        // Find out what the problem was
-        let error = get_last_error();
-        // Try to release the GDS buffer before returning
-        release_gds_buffer(gds_buffer).ok();  // TODO: Handle errors properly.
-        return Err(PlcnextError::new(&error));
-    }
+// Read data from a fieldbus input frame
+// The first call to this function with a new port_name is expensive, because it retrieves data from the system
+// about that port, but after that the data is cached in the port registry and reads are quicker.
+// See also read_port_data(), which takes the fieldbus I/O system name from the port name.
+pub fn read_input_data(system: &SystemHandle, fb_io_system_name: &str, port_name: &str, value: &mut[u8]) -> Result<()> {
 
-    // Get the offset to the named port in the GDS buffer
-    let mut offset: usize = 0;
-    if !unsafe {plcnext_sys::ArpPlcGds_GetVariableOffset(gds_buffer, raw_port_name, &mut offset)} {
-        // Log::Error("ArpPlcGds_GetVariableOffset failed");
-        // Find out what the problem was
-        let error = get_last_error();
-        // Try to release the GDS buffer before returning
-        release_gds_buffer(gds_buffer).ok();  // TODO: Handle errors properly.
-        return Err(PlcnextError::new(&error));
-    }
+    // Validate the port name before passing it to the system
+    PortName::parse(port_name)?;
 
-    Ok((gds_buffer, offset))
+    // Get the cached handle to the named port, resolving it on the first call.
+    let port = registry::port_registry(system).get(fb_io_system_name, port_name)?;
+
+    // Copy data from the GDS Buffer.
+    // An error is returned if value is not the exact size of the port.
+    port.read(value)
 }
 
-/// Releases the memory used by the handle to the GDS buffer
-fn release_gds_buffer(gds_buffer: *mut plcnext_sys::TGdsBuffer) -> Result<()> {
-    if !gds_buffer.is_null() {
-        if !unsafe { plcnext_sys::ArpPlcIo_ReleaseGdsBuffer(gds_buffer) } {
-            // Log::Error("ArpPlcIo_ReleaseGdsBuffer failed");
-            return Err(PlcnextError::new(&get_last_error()));
-        }
+/// Write data to a fieldbus output frame
+/// The first call to this function with a new port_name is expensive, because it retrieves data from the system
+/// about that port, but after that the data is cached in the port registry and writes are quicker.
+/// See also write_port_data(), which takes the fieldbus I/O system name from the port name.
+pub fn write_output_data(system: &SystemHandle, fb_io_system_name: &str, port_name: &str, value: &[u8]) -> Result<()> {
+
+    // Validate the port name before passing it to the system
+    PortName::parse(port_name)?;
+
+    // Get the cached handle to the named port, resolving it on the first call.
+    let port = registry::port_registry(system).get(fb_io_system_name, port_name)?;
+
+    // Copy data to the GDS Buffer.
+    // An error is returned if value is not the exact size of the port.
+    port.write(value)
+}
+
+/// Read data from a port, e.g. "Arp.Io.AxlC/0.DI16".
+/// The fieldbus I/O system name is taken from the port name.
+/// The length of 'value' must be the size of the port.
+pub fn read_port_data(system: &SystemHandle, port_name: &str, value: &mut[u8]) -> Result<()> {
+    let port_name = PortName::parse(port_name)?;
+    registry::port_registry(system).get_by_name(&port_name)?.read(value)
+}
+
+/// Write data to a port, e.g. "Arp.Io.AxlC/0.DO16".
+/// The fieldbus I/O system name is taken from the port name.
+/// The length of 'value' must be the size of the port.
+pub fn write_port_data(system: &SystemHandle, port_name: &str, value: &[u8]) -> Result<()> {
+    let port_name = PortName::parse(port_name)?;
+    registry::port_registry(system).get_by_name(&port_name)?.write(value)
+}
+
+/// Transfers I/O data from the GDS to the Axioline bus.
+/// * 'system' - The handle returned by load()
+/// * 'timeout' - Timeout in milliseconds to wait for the event to be processed.
+///   If zero, the calling thread will block forever.
+pub fn write_from_gds_to_axio(_system: &SystemHandle, timeout: u32) -> Result<()> {
+    if !unsafe { sys::ArpPlcAxio_WriteFromGdsToAxio(timeout as c_ulong) } {
+        return Err(PlcnextError::last_error("ArpPlcAxio_WriteFromGdsToAxio"));
     }
     Ok(())
 }
 
+// Creates a CString for the ANSI-C library.
+// A NUL character in the value is returned as a parameter error, instead of a panic.
+pub(crate) fn c_string(parameter: &'static str, value: &str) -> Result<CString> {
+    CString::new(value).map_err(|source| ParameterError::InteriorNul { parameter, source }.into())
+}
+
 // TODO: Return Result<String, Err> and handle errors
 // Copies the last error message into the buffer. After this operation the error
 // message is deleted. If there is no error message the buffer will contain only 0x00.
@@ -317,7 +375,44 @@ pub fn get_last_error() -> String {
 
     let mut buffer: [u8; MAX_ERROR_LENGTH] = [0x00; MAX_ERROR_LENGTH];
     unsafe {
-        plcnext_sys::ArpPlc_GetLastError(buffer.as_mut_ptr(), MAX_ERROR_LENGTH as i32);
-        CStr::from_bytes_with_nul_unchecked(&buffer).to_string_lossy().into_owned()
+        sys::ArpPlc_GetLastError(buffer.as_mut_ptr(), MAX_ERROR_LENGTH as i32);
+    }
+    // The message ends at the first NUL. If the library filled the whole buffer, all of it is used.
+    match CStr::from_bytes_until_nul(&buffer) {
+        Ok(message) => message.to_string_lossy().into_owned(),
+        Err(_) => String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_error_messages() {
+        let _simulation = simulation::exclusive();
+        assert_eq!(get_last_error(), "");
+
+        simulation().fail_next("ArpPlcDevice_GetUniqueHardwareId", "No id available");
+        match get_unique_hardware_id() {
+            Err(PlcnextError::System(e)) => {
+                assert_eq!(e.message, "No id available");
+                assert_eq!(e.to_string(), "ArpPlcDevice_GetUniqueHardwareId failed: No id available");
+            },
+            other => panic!("Expected a system error, got {:?}", other)
+        }
+        assert_eq!(get_last_error(), "");
+
+        simulation().fail_next("ArpPlcDevice_GetUniqueHardwareId", "");
+        match get_unique_hardware_id() {
+            Err(PlcnextError::System(e)) => assert_eq!(e.to_string(), "ArpPlcDevice_GetUniqueHardwareId failed"),
+            other => panic!("Expected a system error, got {:?}", other)
+        }
+
+        simulation().fail_next("ArpSystemModule_Load", "");
+        match load("/usr/lib", "test", "/etc/acf.settings") {
+            Err(PlcnextError::System(e)) => assert_eq!(e.message, "returned -1"),
+            other => panic!("Expected a system error, got {:?}", other)
+        }
     }
 }
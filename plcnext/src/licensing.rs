@@ -0,0 +1,424 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::hardware_id::{self, UniqueHardwareId};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::error;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A license that locks an application to one controller.
+///
+/// A license file is text, with one 'key = value' line per field, signed with the
+/// Ed25519 secret key of the vendor:
+///
+/// ```text
+/// device = 3f9a0c...e41b
+/// features = export, remote-access
+/// expires = 2027-12-31
+/// signature = 8c1d47...09aa
+/// ```
+///
+/// The device is the unique hardware id of the controller, in hex. The features are
+/// separated by commas, and may be empty. The expiry date is in UTC, and the license is valid
+/// until the end of that day; "never" means that the license doesn't expire. The signature
+/// covers all lines before the signature line, which must be the last line. Empty lines and
+/// lines starting with '#' are allowed before the signature line, and are signed as well.
+/// Trailing whitespace and the kind of line ends are not signed, so a license still
+/// verifies after its line ends were converted, e.g. to CRLF on Windows.
+///
+/// The application embeds the public key that belongs to the secret key, and checks the
+/// license with verify(). License files are made with sign(), or with the plcnext-license tool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct License {
+    device_id: UniqueHardwareId,
+    features: Vec<String>,
+    expires: Option<String>,
+    expires_at: Option<SystemTime>
+}
+
+impl License {
+    /// Checks a license against the public key, the unique hardware id of this controller
+    /// and the current time.
+    /// * 'text' - The contents of the license file
+    /// * 'public_key' - The Ed25519 public key of the vendor
+    pub fn verify(text: &str, public_key: &[u8; 32]) -> Result<License> {
+        let device_id = crate::get_unique_hardware_id()?;
+        License::verify_for(text, public_key, &device_id, SystemTime::now())
+    }
+
+    /// Reads a license file, and checks it like verify().
+    pub fn load<P: AsRef<Path>>(path: P, public_key: &[u8; 32]) -> Result<License> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| PlcnextError::invalid_value(
+            &format!("Failed to read {}: {}", path.display(), e)))?;
+        License::verify(&text, public_key)
+    }
+
+    /// Checks a license against the public key, a given hardware id and a given time.
+    /// The signature is checked first, so that nothing in the license is used before it's known to be genuine.
+    /// * 'text' - The contents of the license file
+    /// * 'public_key' - The Ed25519 public key of the vendor
+    /// * 'device_id' - The hardware id that the license must be for
+    /// * 'now' - The time at which the license must not have expired
+    pub fn verify_for(text: &str, public_key: &[u8; 32], device_id: &UniqueHardwareId, now: SystemTime) -> Result<License> {
+        let (signed, signature) = split_signature(text)?;
+        let public_key = VerifyingKey::from_bytes(public_key).map_err(|_| PlcnextError::invalid_value(
+            "The public key is not a valid Ed25519 key"))?;
+        if public_key.verify_strict(canonical(signed).as_bytes(), &Signature::from_bytes(&signature)).is_err() {
+            return Err(LicenseError::InvalidSignature.into());
+        }
+
+        let license = parse(signed)?;
+        if license.device_id != *device_id {
+            return Err(LicenseError::WrongDevice { licensed: license.device_id, actual: *device_id }.into());
+        }
+        if license.is_expired_at(now) {
+            let expires = license.expires.clone().unwrap_or_default();
+            return Err(LicenseError::Expired { expires }.into());
+        }
+        Ok(license)
+    }
+
+    /// Makes a signed license file.
+    /// * 'device_id' - The unique hardware id of the controller
+    /// * 'features' - The licensed features. A feature name consists of letters, digits, '-', '_', '.' and ':'.
+    /// * 'expires' - The last day of the license, as "YYYY-MM-DD" in UTC, or None if it doesn't expire
+    /// * 'secret_key' - The Ed25519 secret key of the vendor
+    pub fn sign(device_id: &UniqueHardwareId, features: &[&str], expires: Option<&str>, secret_key: &[u8; 32]) -> Result<String> {
+        for feature in features {
+            if feature.is_empty() || !feature.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)) {
+                return Err(PlcnextError::invalid_value(&format!("'{}' is not a valid feature name", feature)));
+            }
+        }
+        if let Some(date) = expires {
+            end_of_day(date)?;
+        }
+
+        let mut text = format!("device = {}\nfeatures = {}\nexpires = {}\n",
+            device_id, features.join(", "), expires.unwrap_or("never"));
+        let signature = SigningKey::from_bytes(secret_key).sign(canonical(&text).as_bytes());
+        text.push_str("signature = ");
+        for byte in signature.to_bytes().iter() {
+            text.push_str(&format!("{:02x}", byte));
+        }
+        text.push('\n');
+        Ok(text)
+    }
+
+    /// The public key that belongs to a secret key, to embed in the application.
+    pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+        SigningKey::from_bytes(secret_key).verifying_key().to_bytes()
+    }
+
+    /// Parses a public or secret key of 64 hex digits, in upper or lower case,
+    /// e.g. the public key printed by the plcnext-license tool.
+    pub fn parse_key(hex: &str) -> Result<[u8; 32]> {
+        let digits = hex.as_bytes();
+        if digits.len() != 64 {
+            return Err(PlcnextError::invalid_value(&format!("A key has 64 hex digits, not {}", digits.len())));
+        }
+        let mut key = [0u8; 32];
+        for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+            *byte = (hardware_id::hex_digit(pair[0])? << 4) | hardware_id::hex_digit(pair[1])?;
+        }
+        Ok(key)
+    }
+
+    /// The hardware id of the controller that the license is for.
+    pub fn device_id(&self) -> &UniqueHardwareId {
+        &self.device_id
+    }
+
+    /// The licensed features, in the order of the license file.
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// Whether a feature is licensed.
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.iter().any(|feature| feature == name)
+    }
+
+    /// The last day of the license, as "YYYY-MM-DD" in UTC, or None if it doesn't expire.
+    pub fn expires(&self) -> Option<&str> {
+        self.expires.as_deref()
+    }
+
+    /// Whether the license has expired by now.
+    /// verify() only checks this once, so long-running applications can call this again later.
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(SystemTime::now())
+    }
+
+    /// Whether the license has expired at the given time.
+    pub fn is_expired_at(&self, time: SystemTime) -> bool {
+        matches!(self.expires_at, Some(expires_at) if time >= expires_at)
+    }
+}
+
+// Splits a license into the signed text, up to the signature line, and the signature.
+fn split_signature(text: &str) -> Result<(&str, [u8; 64])> {
+    let body = text.trim_end();
+    let start = body.rfind('\n').map_or(0, |index| index + 1);
+    let value = match field(&body[start..]) {
+        Some(("signature", value)) => value,
+        _ => return Err(malformed("The last line is not the signature"))
+    };
+    let digits = value.as_bytes();
+    if digits.len() != 128 {
+        return Err(malformed(&format!("The signature has 128 hex digits, not {}", digits.len())));
+    }
+    let mut signature = [0u8; 64];
+    for (byte, pair) in signature.iter_mut().zip(digits.chunks(2)) {
+        *byte = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+    }
+    Ok((&text[..start], signature))
+}
+
+// The form of the text before the signature line that is signed: every line without
+// trailing whitespace, ended by '\n', whatever line ends the file has.
+fn canonical(signed: &str) -> String {
+    let mut text = String::with_capacity(signed.len());
+    for line in signed.lines() {
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+// Reads the fields of the signed text.
+fn parse(signed: &str) -> Result<License> {
+    let mut device_id = None;
+    let mut features = None;
+    let mut expires = None;
+    for (index, line) in signed.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (slot, value) = match field(line) {
+            Some(("device", value)) => (&mut device_id, value),
+            Some(("features", value)) => (&mut features, value),
+            Some(("expires", value)) => (&mut expires, value),
+            Some((key, _)) => return Err(malformed(&format!("Line {} has an unknown field '{}'", index + 1, key))),
+            None => return Err(malformed(&format!("Line {} is not 'key = value': {}", index + 1, line)))
+        };
+        if slot.replace(value).is_some() {
+            return Err(malformed(&format!("Line {} repeats a field", index + 1)));
+        }
+    }
+
+    let device_id = device_id.ok_or_else(|| malformed("The device field is missing"))?;
+    let device_id = device_id.parse().map_err(|e| malformed(&format!("Invalid device: {}", e)))?;
+    let features = features.ok_or_else(|| malformed("The features field is missing"))?
+        .split(',')
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+        .map(str::to_string)
+        .collect();
+    let (expires, expires_at) = match expires.ok_or_else(|| malformed("The expires field is missing"))? {
+        "never" => (None, None),
+        date => (Some(date.to_string()), Some(end_of_day(date).map_err(|e| malformed(&e.to_string()))?))
+    };
+    Ok(License { device_id, features, expires, expires_at })
+}
+
+fn field(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next()?.trim();
+    let value = parts.next()?.trim();
+    Some((key, value))
+}
+
+fn hex_digit(digit: u8) -> Result<u8> {
+    hardware_id::hex_digit(digit).map_err(|e| malformed(&format!("Invalid signature: {}", e)))
+}
+
+fn malformed(reason: &str) -> PlcnextError {
+    LicenseError::Malformed(reason.to_string()).into()
+}
+
+// The end of a "YYYY-MM-DD" day in UTC.
+fn end_of_day(date: &str) -> Result<SystemTime> {
+    let invalid = || PlcnextError::invalid_value(&format!("'{}' is not a date in the form YYYY-MM-DD", date));
+    let parts: Vec<&str> = date.split('-').collect();
+    let (year, month, day) = match parts.as_slice() {
+        [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => (
+            year.parse::<u64>().map_err(|_| invalid())?,
+            month.parse::<u64>().map_err(|_| invalid())?,
+            day.parse::<u64>().map_err(|_| invalid())?
+        ),
+        _ => return Err(invalid())
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err(invalid())
+    };
+    if year < 1970 || day < 1 || day > days_in_month {
+        return Err(invalid());
+    }
+
+    // Days since 1970-01-01, counting years from March so that the leap day comes last.
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year - 719_468;
+    Ok(UNIX_EPOCH + Duration::from_secs((days + 1) * SECONDS_PER_DAY))
+}
+
+/// Why a license was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LicenseError {
+    /// The license file is not in the expected format.
+    Malformed(String),
+    /// The signature doesn't match the license and the public key.
+    /// Either the license was changed, or it was signed with a different key.
+    InvalidSignature,
+    /// The license is for a different controller.
+    WrongDevice { licensed: UniqueHardwareId, actual: UniqueHardwareId },
+    /// The license expired at the end of the given day.
+    Expired { expires: String }
+}
+
+impl fmt::Display for LicenseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LicenseError::Malformed(reason) => write!(f, "Malformed license: {}", reason),
+            LicenseError::InvalidSignature => write!(f, "The license signature is not valid"),
+            LicenseError::WrongDevice { licensed, actual } =>
+                write!(f, "The license is for device {}, not for this device {}", licensed, actual),
+            LicenseError::Expired { expires } => write!(f, "The license expired on {}", expires)
+        }
+    }
+}
+
+impl error::Error for LicenseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET_KEY: [u8; 32] = [7; 32];
+
+    fn seconds(date: &str) -> u64 {
+        end_of_day(date).unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn device() -> UniqueHardwareId {
+        UniqueHardwareId::from_bytes([9; 32])
+    }
+
+    fn verify(text: &str, now: SystemTime) -> Result<License> {
+        License::verify_for(text, &License::public_key(&SECRET_KEY), &device(), now)
+    }
+
+    fn license_error(result: Result<License>) -> LicenseError {
+        match result {
+            Err(PlcnextError::License(error)) => error,
+            other => panic!("expected a license error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn end_of_day_counts_leap_days() {
+        assert_eq!(seconds("1970-01-01"), 86_400);
+        assert_eq!(seconds("2000-02-29"), 951_868_800);
+        assert_eq!(seconds("2024-02-29"), 1_709_251_200);
+        assert_eq!(seconds("2024-02-28") + 86_400, seconds("2024-02-29"));
+        assert_eq!(seconds("2024-02-29") + 86_400, seconds("2024-03-01"));
+        assert_eq!(seconds("2027-12-31"), 1_830_297_600);
+        assert_eq!(seconds("2100-02-28"), 4_107_542_400);
+    }
+
+    #[test]
+    fn end_of_day_rejects_invalid_dates() {
+        for date in &["2100-02-29", "2023-02-29", "2024-04-31", "2024-13-01", "2024-00-10", "2024-01-00",
+                      "1969-12-31", "2024-2-29", "24-02-29", "2024/02/29", "2024-02-29-01", "never", ""] {
+            assert!(end_of_day(date).is_err(), "{} should be rejected", date);
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let text = License::sign(&device(), &["export", "remote-access"], Some("2027-12-31"), &SECRET_KEY).unwrap();
+        let license = verify(&text, UNIX_EPOCH).unwrap();
+        assert_eq!(license.device_id(), &device());
+        assert_eq!(license.features(), ["export", "remote-access"]);
+        assert!(license.has_feature("export") && !license.has_feature("import"));
+        assert_eq!(license.expires(), Some("2027-12-31"));
+
+        let text = License::sign(&device(), &[], None, &SECRET_KEY).unwrap();
+        let license = verify(&text, SystemTime::now()).unwrap();
+        assert!(license.features().is_empty());
+        assert_eq!(license.expires(), None);
+        assert!(!license.is_expired());
+    }
+
+    #[test]
+    fn line_ends_and_trailing_whitespace_are_not_signed() {
+        let text = License::sign(&device(), &[], Some("2027-12-31"), &SECRET_KEY).unwrap();
+        assert!(verify(&text.replace('\n', "\r\n"), UNIX_EPOCH).is_ok());
+        assert!(verify(&text.replace('\n', " \t\n"), UNIX_EPOCH).is_ok());
+    }
+
+    #[test]
+    fn tampered_license() {
+        let text = License::sign(&device(), &["export"], Some("2027-12-31"), &SECRET_KEY).unwrap();
+        for tampered in &[
+            text.replace("export", "import"),
+            text.replace("2027-12-31", "2099-12-31"),
+            format!("# customer 42\n{}", text)
+        ] {
+            assert_eq!(license_error(verify(tampered, UNIX_EPOCH)), LicenseError::InvalidSignature);
+        }
+
+        let other_key = License::sign(&device(), &["export"], Some("2027-12-31"), &[8; 32]).unwrap();
+        assert_eq!(license_error(verify(&other_key, UNIX_EPOCH)), LicenseError::InvalidSignature);
+    }
+
+    #[test]
+    fn wrong_device() {
+        let other = UniqueHardwareId::from_bytes([1; 32]);
+        let text = License::sign(&other, &[], None, &SECRET_KEY).unwrap();
+        assert_eq!(license_error(verify(&text, UNIX_EPOCH)), LicenseError::WrongDevice { licensed: other, actual: device() });
+    }
+
+    #[test]
+    fn expires_at_the_end_of_the_day() {
+        let text = License::sign(&device(), &[], Some("2027-12-31"), &SECRET_KEY).unwrap();
+        let end = UNIX_EPOCH + Duration::from_secs(1_830_297_600);
+        let license = verify(&text, end - Duration::from_nanos(1)).unwrap();
+        assert!(!license.is_expired_at(end - Duration::from_nanos(1)));
+        assert!(license.is_expired_at(end));
+        assert_eq!(license_error(verify(&text, end)), LicenseError::Expired { expires: "2027-12-31".to_string() });
+    }
+
+    #[test]
+    fn malformed_license() {
+        assert!(matches!(license_error(verify("device = x\n", UNIX_EPOCH)), LicenseError::Malformed(_)));
+        let text = License::sign(&device(), &[], None, &SECRET_KEY).unwrap();
+        let short = &text[..text.len() - 3];
+        assert!(matches!(license_error(verify(short, UNIX_EPOCH)), LicenseError::Malformed(_)));
+        assert!(License::sign(&device(), &["two words"], None, &SECRET_KEY).is_err());
+        assert!(License::sign(&device(), &[], Some("2023-02-29"), &SECRET_KEY).is_err());
+    }
+
+    #[test]
+    fn parse_key() {
+        let public_key = License::public_key(&SECRET_KEY);
+        let hex: String = public_key.iter().map(|byte| format!("{:02X}", byte)).collect();
+        assert_eq!(License::parse_key(&hex).unwrap(), public_key);
+        assert_eq!(License::parse_key(&hex.to_lowercase()).unwrap(), public_key);
+        assert!(License::parse_key(&hex[2..]).is_err());
+        // Each digit is checked on its own, so a sign is not accepted in place of a digit.
+        assert!(License::parse_key(&format!("+{}", &hex[1..])).is_err());
+        assert!(License::parse_key(&format!("ä{}", &hex[2..])).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A validated PLCnext port name.
+///
+/// A port name is the name of the component that owns the port, followed by a '/'
+/// and the path to the port within that component, for example:
+/// * Axioline: "Arp.Io.AxlC/0.~DI8" or "Arp.Io.AxlC/0.DI16"
+/// * Profinet: "Arp.Io.PnC/axc-f-2152.1.~DI8"
+/// * IEC 61131-3 program instance: "Arp.Plc.Eclr/MainInstance.Values[3]"
+///
+/// For fieldbus ports, the component name is the name of the fieldbus I/O system.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PortName {
+    name: String,
+    // The index of the '/' between the component name and the port path
+    separator: usize
+}
+
+impl PortName {
+    /// Parses and validates a port name.
+    /// If the name is invalid, the error names the offending segment.
+    pub fn parse(name: &str) -> Result<PortName> {
+        let separator = match name.find('/') {
+            Some(index) => index,
+            None => return Err(PlcnextError::invalid_port_name(name, name, "expected '<component>/<port path>'"))
+        };
+        let (component, path) = (&name[..separator], &name[separator + 1..]);
+
+        for (position, segment) in component.split('.').enumerate() {
+            validate_component_segment(name, position, segment)?;
+        }
+        for (position, segment) in path.split('.').enumerate() {
+            validate_path_segment(name, position, segment)?;
+        }
+
+        Ok(PortName { name: name.to_string(), separator })
+    }
+
+    /// The name of the component that owns the port.
+    /// For fieldbus ports this is the fieldbus I/O system name, e.g. "Arp.Io.AxlC".
+    pub fn fb_io_system_name(&self) -> &str {
+        &self.name[..self.separator]
+    }
+
+    /// The path to the port within its component, e.g. "0.DI16".
+    pub fn port_path(&self) -> &str {
+        &self.name[self.separator + 1..]
+    }
+
+    /// The segments of the port path, e.g. "0" and "DI16".
+    pub fn segments(&self) -> std::str::Split<'_, char> {
+        self.port_path().split('.')
+    }
+
+    /// The full port name, e.g. "Arp.Io.AxlC/0.DI16".
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+// A component segment is an identifier, e.g. "Arp", "Io" or "AxlC".
+fn validate_component_segment(name: &str, position: usize, segment: &str) -> Result<()> {
+    if segment.is_empty() {
+        return Err(PlcnextError::invalid_port_name(name, segment, &format!("component segment {} is empty", position + 1)));
+    }
+    if let Some(c) = segment.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_')) {
+        return Err(PlcnextError::invalid_port_name(name, segment, &format!("invalid character '{}' in component segment", c)));
+    }
+    Ok(())
+}
+
+// A path segment is a module number, device name or variable name, optionally
+// prefixed with '~' for generic process data, and optionally followed by array indices,
+// e.g. "0", "axc-f-2152", "~DI8" or "Values[3]".
+fn validate_path_segment(name: &str, position: usize, segment: &str) -> Result<()> {
+    let base = match segment.find('[') {
+        Some(index) => {
+            validate_indices(name, segment, &segment[index..])?;
+            &segment[..index]
+        },
+        None => segment
+    };
+    let base = base.strip_prefix('~').unwrap_or(base);
+    if base.is_empty() {
+        return Err(PlcnextError::invalid_port_name(name, segment, &format!("port path segment {} is empty", position + 1)));
+    }
+    if let Some(c) = base.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')) {
+        return Err(PlcnextError::invalid_port_name(name, segment, &format!("invalid character '{}' in port path segment", c)));
+    }
+    Ok(())
+}
+
+// Array indices are one or more non-negative integers in brackets, e.g. "[3]" or "[1][2]".
+fn validate_indices(name: &str, segment: &str, indices: &str) -> Result<()> {
+    let mut rest = indices;
+    while !rest.is_empty() {
+        let close = match (rest.starts_with('['), rest.find(']')) {
+            (true, Some(close)) => close,
+            _ => return Err(PlcnextError::invalid_port_name(name, segment, "malformed array index"))
+        };
+        let index = &rest[1..close];
+        if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+            return Err(PlcnextError::invalid_port_name(name, segment, &format!("invalid array index '{}'", index)));
+        }
+        rest = &rest[close + 1..];
+    }
+    Ok(())
+}
+
+impl FromStr for PortName {
+    type Err = PlcnextError;
+
+    fn from_str(name: &str) -> Result<PortName> {
+        PortName::parse(name)
+    }
+}
+
+impl fmt::Display for PortName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl AsRef<str> for PortName {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ParameterError;
+
+    // The segment named by the error for an invalid port name.
+    fn invalid_segment(name: &str) -> String {
+        match PortName::parse(name) {
+            Err(PlcnextError::Parameter(ParameterError::InvalidPortName { segment, .. })) => segment,
+            other => panic!("{} should be invalid, got {:?}", name, other)
+        }
+    }
+
+    #[test]
+    fn fieldbus_port_names() {
+        let name = PortName::parse("Arp.Io.AxlC/0.~DI8").unwrap();
+        assert_eq!(name.fb_io_system_name(), "Arp.Io.AxlC");
+        assert_eq!(name.port_path(), "0.~DI8");
+        assert_eq!(name.segments().collect::<Vec<_>>(), vec!["0", "~DI8"]);
+        assert_eq!(name.to_string(), "Arp.Io.AxlC/0.~DI8");
+
+        let name: PortName = "Arp.Io.PnC/axc-f-2152.1.~DI8".parse().unwrap();
+        assert_eq!(name.fb_io_system_name(), "Arp.Io.PnC");
+    }
+
+    #[test]
+    fn nested_structs_and_array_indices() {
+        for name in &[
+            "Arp.Plc.Eclr/MainInstance.Values[3]",
+            "Arp.Plc.Eclr/MainInstance.Motor.Drive.Speed",
+            "Arp.Plc.Eclr/MainInstance.Motors[2].Speed",
+            "Arp.Plc.Eclr/MainInstance.Matrix[1][20].Cells[0]"
+        ] {
+            let parsed = PortName::parse(name).unwrap();
+            assert_eq!(parsed.as_str(), *name);
+            assert_eq!(parsed.fb_io_system_name(), "Arp.Plc.Eclr");
+        }
+        let name = PortName::parse("Arp.Plc.Eclr/MainInstance.Motors[2].Speed").unwrap();
+        assert_eq!(name.segments().collect::<Vec<_>>(), vec!["MainInstance", "Motors[2]", "Speed"]);
+    }
+
+    #[test]
+    fn malformed_names() {
+        assert_eq!(invalid_segment("Arp.Io.AxlC"), "Arp.Io.AxlC");
+        assert_eq!(invalid_segment("Arp..AxlC/0.DI16"), "");
+        assert_eq!(invalid_segment("Arp.Io.Axl C/0.DI16"), "Axl C");
+        assert_eq!(invalid_segment("Arp.Io.AxlC/"), "");
+        assert_eq!(invalid_segment("Arp.Io.AxlC/0..DI16"), "");
+        assert_eq!(invalid_segment("Arp.Io.AxlC/0.~"), "~");
+        assert_eq!(invalid_segment("Arp.Io.AxlC/0.DI$16"), "DI$16");
+        assert_eq!(invalid_segment("Arp.Io.AxlC/0/DI16"), "0/DI16");
+    }
+
+    #[test]
+    fn malformed_array_indices() {
+        for segment in &["Values[", "Values[]", "Values[a]", "Values[-1]", "Values[1]x", "Values[1]]", "[3]"] {
+            let name = format!("Arp.Plc.Eclr/MainInstance.{}", segment);
+            assert_eq!(invalid_segment(&name), *segment);
+        }
+    }
+}
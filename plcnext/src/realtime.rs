@@ -0,0 +1,308 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::thread::{self, JoinHandle};
+
+// The margin between the pre-faulted part of a stack and the full stack
+// of a thread started by RealtimeConfig::spawn(). It holds the frames above
+// prefault_stack(), the guard page and the thread-local storage.
+pub(crate) const STACK_MARGIN: usize = 64 * 1024;
+
+/// A real-time setting that can be applied to a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RealtimeSetting {
+    /// Locking all current and future memory of the process into RAM, with mlockall().
+    LockedMemory,
+    /// Binding the thread to a set of CPUs.
+    CpuAffinity,
+    /// The SCHED_FIFO scheduling policy and priority.
+    FifoPriority,
+    /// Pre-faulting the thread's stack, which needs the bounds of the stack.
+    PrefaultedStack
+}
+
+impl fmt::Display for RealtimeSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RealtimeSetting::LockedMemory => write!(f, "memory locking"),
+            RealtimeSetting::CpuAffinity => write!(f, "CPU affinity"),
+            RealtimeSetting::FifoPriority => write!(f, "SCHED_FIFO priority"),
+            RealtimeSetting::PrefaultedStack => write!(f, "pre-faulted stack")
+        }
+    }
+}
+
+/// A real-time setting could not be applied.
+#[derive(Debug)]
+pub struct RealtimeError {
+    /// The setting that failed.
+    pub setting: RealtimeSetting,
+    /// The error from the operating system.
+    pub source: io::Error
+}
+
+impl RealtimeError {
+    // The capability or resource limit that is missing, if that is the likely cause.
+    fn hint(&self) -> Option<&'static str> {
+        if self.source.kind() != io::ErrorKind::PermissionDenied && self.source.raw_os_error() != Some(libc::ENOMEM) {
+            return None;
+        }
+        match self.setting {
+            RealtimeSetting::LockedMemory =>
+                Some("the process needs the CAP_IPC_LOCK capability, or a larger RLIMIT_MEMLOCK (ulimit -l)"),
+            RealtimeSetting::FifoPriority =>
+                Some("the process needs the CAP_SYS_NICE capability, or a large enough RLIMIT_RTPRIO (ulimit -r)"),
+            RealtimeSetting::CpuAffinity | RealtimeSetting::PrefaultedStack => None
+        }
+    }
+}
+
+impl fmt::Display for RealtimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to set {}: {}", self.setting, self.source)?;
+        if let Some(hint) = self.hint() {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+// The message already includes the message of the OS error, so source() skips it.
+impl error::Error for RealtimeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source.source()
+    }
+}
+
+/// Real-time settings for the thread that runs the I/O loop.
+///
+/// apply() configures the calling thread. Threads that it starts afterwards
+/// inherit the scheduling policy, priority and CPU affinity, and memory locking
+/// applies to the whole process, but each thread must pre-fault its own stack.
+/// spawn() starts a thread that applies all settings itself.
+///
+/// Each setting is optional, so the settings that don't need privileges,
+/// e.g. CPU affinity, can be used on a development machine.
+#[derive(Debug, Clone, Default)]
+pub struct RealtimeConfig {
+    fifo_priority: Option<i32>,
+    cpus: Option<Vec<usize>>,
+    lock_memory: bool,
+    prefault_stack: Option<usize>
+}
+
+impl RealtimeConfig {
+    /// Creates a configuration that doesn't change anything.
+    pub fn new() -> RealtimeConfig {
+        RealtimeConfig::default()
+    }
+
+    /// Runs the thread with the SCHED_FIFO scheduling policy.
+    /// * 'priority' - From 1 (lowest) to 99 (highest) on Linux
+    pub fn with_fifo_priority(mut self, priority: i32) -> RealtimeConfig {
+        self.fifo_priority = Some(priority);
+        self
+    }
+
+    /// Binds the thread to the given CPUs, e.g. to a core that is isolated with isolcpus.
+    /// * 'cpus' - The numbers of the CPUs, counting from 0
+    pub fn with_cpu_affinity(mut self, cpus: &[usize]) -> RealtimeConfig {
+        self.cpus = Some(cpus.to_vec());
+        self
+    }
+
+    /// Locks all current and future memory of the process into RAM,
+    /// so that the I/O loop is never delayed by a page fault.
+    pub fn with_locked_memory(mut self) -> RealtimeConfig {
+        self.lock_memory = true;
+        self
+    }
+
+    /// Touches the given number of bytes of the thread's stack,
+    /// so that the stack is mapped before the I/O loop starts.
+    /// This must be less than the size of the thread's stack,
+    /// otherwise apply() returns an error without touching the stack.
+    pub fn with_prefaulted_stack(mut self, bytes: usize) -> RealtimeConfig {
+        self.prefault_stack = Some(bytes);
+        self
+    }
+
+    // The number of bytes of stack to pre-fault, if any.
+    pub(crate) fn prefault_stack(&self) -> Option<usize> {
+        self.prefault_stack
+    }
+
+    /// Applies the settings to the calling thread.
+    /// Memory is locked first, so that the pre-faulted stack stays in RAM,
+    /// and the priority is set last. The first setting that fails is returned as
+    /// an error, and the settings that were applied before it are not undone.
+    pub fn apply(&self) -> Result<()> {
+        if self.lock_memory {
+            lock_memory()?;
+        }
+        if let Some(cpus) = &self.cpus {
+            set_cpu_affinity(cpus)?;
+        }
+        if let Some(bytes) = self.prefault_stack {
+            prefault_stack(bytes)?;
+        }
+        if let Some(priority) = self.fifo_priority {
+            set_fifo_priority(priority)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a thread that applies the settings and then calls 'f'.
+    /// If pre-faulting is enabled, the thread's stack is made large enough.
+    /// If a setting fails, 'f' is not called, and the error is returned by join().
+    pub fn spawn<F, T>(&self, name: &str, f: F) -> io::Result<JoinHandle<Result<T>>>
+        where F: 'static + FnOnce() -> T + Send, T: 'static + Send {
+
+        let config = self.clone();
+        let mut builder = thread::Builder::new().name(name.to_string());
+        if let Some(bytes) = self.prefault_stack {
+            builder = builder.stack_size(bytes + STACK_MARGIN);
+        }
+        builder.spawn(move || {
+            config.apply()?;
+            Ok(f())
+        })
+    }
+}
+
+fn lock_memory() -> Result<()> {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(realtime_error(RealtimeSetting::LockedMemory, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn set_cpu_affinity(cpus: &[usize]) -> Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(PlcnextError::invalid_value(&format!("CPU {} is out of range", cpu)));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // A pid of 0 means the calling thread.
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(realtime_error(RealtimeSetting::CpuAffinity, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn set_fifo_priority(priority: i32) -> Result<()> {
+    let (min, max) = unsafe { (libc::sched_get_priority_min(libc::SCHED_FIFO), libc::sched_get_priority_max(libc::SCHED_FIFO)) };
+    if priority < min || priority > max {
+        return Err(PlcnextError::invalid_value(&format!(
+            "SCHED_FIFO priority {} is out of range, it must be from {} to {}", priority, min, max)));
+    }
+    let param = libc::sched_param { sched_priority: priority };
+    // pthread functions return the error number instead of setting errno.
+    let result = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if result != 0 {
+        return Err(realtime_error(RealtimeSetting::FifoPriority, io::Error::from_raw_os_error(result)));
+    }
+    Ok(())
+}
+
+// Touches the stack one page at a time, down to 'bytes' below the current frame.
+// The pages are in the part of the stack that the thread hasn't used yet, below the
+// stack pointer, so they are written through a pointer instead of through a local array.
+// That way the reach doesn't depend on how large the frames of a recursion would be,
+// which is much more than their arrays in a debug build.
+// The pointer is checked against the bounds of the stack first, so that it can't
+// reach the guard page or the memory below it.
+#[inline(never)]
+fn prefault_stack(bytes: usize) -> Result<()> {
+    let page = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096
+    };
+    let marker = 0u8;
+    let top = std::hint::black_box(&marker) as *const u8 as usize;
+    let bottom = stack_bottom()?;
+    let available = top.saturating_sub(bottom);
+    if bytes > available {
+        return Err(PlcnextError::invalid_value(&format!(
+            "Can't pre-fault {} bytes of stack, only {} bytes are left below the current frame", bytes, available)));
+    }
+    let mut offset = page;
+    while offset <= bytes {
+        unsafe { std::ptr::write_volatile((top - offset) as *mut u8, 0) };
+        offset += page;
+    }
+    Ok(())
+}
+
+// The lowest address of the calling thread's stack that may be written, above the guard area.
+// For the main thread, this is where the stack can grow to, as limited by RLIMIT_STACK.
+fn stack_bottom() -> Result<usize> {
+    let mut attr: libc::pthread_attr_t = unsafe { std::mem::zeroed() };
+    // pthread functions return the error number instead of setting errno.
+    let result = unsafe { libc::pthread_getattr_np(libc::pthread_self(), &mut attr) };
+    if result != 0 {
+        return Err(realtime_error(RealtimeSetting::PrefaultedStack, io::Error::from_raw_os_error(result)));
+    }
+    let mut address = std::ptr::null_mut();
+    let mut size = 0;
+    let mut guard_size = 0;
+    let result = unsafe {
+        match libc::pthread_attr_getstack(&attr, &mut address, &mut size) {
+            0 => libc::pthread_attr_getguardsize(&attr, &mut guard_size),
+            error => error
+        }
+    };
+    unsafe { libc::pthread_attr_destroy(&mut attr) };
+    if result != 0 {
+        return Err(realtime_error(RealtimeSetting::PrefaultedStack, io::Error::from_raw_os_error(result)));
+    }
+    // glibc includes the guard area in the stack of a thread.
+    Ok(address as usize + guard_size)
+}
+
+fn realtime_error(setting: RealtimeSetting, source: io::Error) -> PlcnextError {
+    PlcnextError::Realtime(RealtimeError { setting, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ParameterError;
+
+    #[test]
+    fn spawn_with_large_prefaulted_stack() {
+        let config = RealtimeConfig::new().with_prefaulted_stack(8 << 20);
+        let handle = config.spawn("prefault", || 42).unwrap();
+        assert_eq!(handle.join().unwrap().unwrap(), 42);
+    }
+
+    #[test]
+    fn error_message_is_reported_once() {
+        let error = realtime_error(RealtimeSetting::FifoPriority, io::Error::from_raw_os_error(libc::EPERM));
+        assert!(error.to_string().contains("Failed to set SCHED_FIFO priority"));
+        assert!(error::Error::source(&error).is_none());
+    }
+
+    #[test]
+    fn prefault_stack_of_current_thread() {
+        prefault_stack(1 << 20).unwrap();
+    }
+
+    #[test]
+    fn prefault_more_than_the_stack() {
+        let handle = thread::Builder::new().stack_size(256 * 1024).spawn(|| prefault_stack(1 << 20)).unwrap();
+        match handle.join().unwrap() {
+            Err(PlcnextError::Parameter(ParameterError::InvalidValue(message))) => assert!(message.contains("Can't pre-fault 1048576 bytes")),
+            other => panic!("Expected an error, got {:?}", other)
+        }
+
+        let config = RealtimeConfig::new().with_prefaulted_stack(1 << 20);
+        let handle = thread::Builder::new().stack_size(256 * 1024).spawn(move || config.apply()).unwrap();
+        assert!(handle.join().unwrap().is_err());
+    }
+}
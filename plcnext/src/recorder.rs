@@ -0,0 +1,479 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// The first bytes of a log file, with the version of the format.
+const MAGIC: &[u8; 8] = b"PLCNREC\x01";
+
+// The size of a record without its data: kind (1), port (2), cycle (8), timestamp (8), size (2).
+const HEADER_SIZE: usize = 21;
+
+/// Whether recorded port data was read from or written to the GDS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordKind {
+    /// The data was read, e.g. by read_input_data() or ProcessImage::read_all().
+    Read,
+    /// The data was written, e.g. by write_output_data() or ProcessImage::write_all().
+    Write
+}
+
+impl RecordKind {
+    fn from_raw(kind: u8) -> Option<RecordKind> {
+        match kind {
+            0 => Some(RecordKind::Read),
+            1 => Some(RecordKind::Write),
+            _ => None
+        }
+    }
+
+    fn to_raw(self) -> u8 {
+        match self {
+            RecordKind::Read => 0,
+            RecordKind::Write => 1
+        }
+    }
+}
+
+// The recorded data, as a ring of encoded records.
+// Ports are numbered in the order in which they are first recorded,
+// and the numbers are kept when the records that use them are dropped.
+struct Ring {
+    capacity: usize,
+    bytes: VecDeque<u8>,
+    // Port name -> fieldbus I/O system name and number.
+    // Keyed by port name, so that a lookup doesn't allocate.
+    port_numbers: HashMap<String, Vec<(String, u16)>>,
+    ports: Vec<(String, String)>,
+    start: Instant,
+    dropped: u64
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Ring {
+        Ring {
+            capacity,
+            bytes: VecDeque::with_capacity(capacity),
+            port_numbers: HashMap::new(),
+            ports: Vec::new(),
+            start: Instant::now(),
+            dropped: 0
+        }
+    }
+
+    fn port_number(&mut self, fb_io_system_name: &str, port_name: &str) -> Option<u16> {
+        if let Some(numbers) = self.port_numbers.get(port_name) {
+            if let Some(&(_, number)) = numbers.iter().find(|(fb, _)| fb == fb_io_system_name) {
+                return Some(number);
+            }
+        }
+        if self.ports.len() > u16::MAX as usize {
+            return None;
+        }
+        let number = self.ports.len() as u16;
+        self.ports.push((fb_io_system_name.to_string(), port_name.to_string()));
+        self.port_numbers.entry(port_name.to_string()).or_default().push((fb_io_system_name.to_string(), number));
+        Some(number)
+    }
+
+    fn push(&mut self, kind: RecordKind, fb_io_system_name: &str, port_name: &str, cycle: u64, data: &[u8]) {
+        let size = HEADER_SIZE + data.len();
+        let number = match self.port_number(fb_io_system_name, port_name) {
+            Some(number) if size <= self.capacity && data.len() <= u16::MAX as usize => number,
+            _ => {
+                self.dropped += 1;
+                return;
+            }
+        };
+
+        // The oldest records make room for the new one.
+        while self.bytes.len() + size > self.capacity {
+            let oldest = HEADER_SIZE + u16::from_le_bytes([self.bytes[19], self.bytes[20]]) as usize;
+            self.bytes.drain(..oldest);
+            self.dropped += 1;
+        }
+
+        let timestamp = self.start.elapsed().as_nanos() as u64;
+        self.bytes.push_back(kind.to_raw());
+        self.bytes.extend(&number.to_le_bytes());
+        self.bytes.extend(&cycle.to_le_bytes());
+        self.bytes.extend(&timestamp.to_le_bytes());
+        self.bytes.extend(&(data.len() as u16).to_le_bytes());
+        self.bytes.extend(data);
+    }
+}
+
+/// Records the port data that is read from and written to the GDS, in a ring buffer.
+///
+/// Every value that goes through a PortHandle, a PortBatch or a ProcessImage is recorded,
+/// which includes read_input_data(), write_output_data() and CyclicTask.
+/// Each record holds the cycle number, the time since recording started, the port and
+/// the data. The buffer has a fixed size, and once it is full, the oldest records are dropped,
+/// so the recorder can be left running to capture the cycles before a problem in the field.
+///
+/// A recording costs a lock and a copy of the port data. While the recorder is stopped,
+/// it costs a single atomic load.
+pub struct Recorder {
+    recording: AtomicBool,
+    cycle: AtomicU64,
+    ring: Mutex<Ring>
+}
+
+lazy_static! {
+    static ref RECORDER: Recorder = Recorder {
+        recording: AtomicBool::new(false),
+        cycle: AtomicU64::new(0),
+        ring: Mutex::new(Ring::new(0))
+    };
+}
+
+/// Gets the recorder for port data.
+pub fn recorder() -> &'static Recorder {
+    &RECORDER
+}
+
+impl Recorder {
+    /// Clears the buffer and starts recording.
+    /// * 'capacity' - The size of the buffer in bytes. Each record takes 21 bytes plus its data.
+    pub fn start(&self, capacity: usize) {
+        // The buffer is allocated before the lock is taken, so that recording isn't held up.
+        let ring = Ring::new(capacity);
+        *self.lock() = ring;
+        self.recording.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops recording. The records are kept until the next start().
+    pub fn stop(&self) {
+        self.recording.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns true while the recorder is recording.
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Moves on to the next cycle, and returns its number.
+    /// CyclicTask does this at the start of every cycle. Applications that run
+    /// their own loop can do it themselves, so that the records are grouped by cycle.
+    pub fn next_cycle(&self) -> u64 {
+        self.cycle.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The number of the current cycle, which starts at zero.
+    pub fn cycle(&self) -> u64 {
+        self.cycle.load(Ordering::Relaxed)
+    }
+
+    /// The number of records that were dropped since recording started, because the
+    /// buffer was full, or because a record was bigger than the whole buffer.
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+
+    /// A copy of the records in the buffer, oldest first.
+    pub fn snapshot(&self) -> RecordedLog {
+        let ring = self.lock();
+        // The records are decoded after the lock is released.
+        let ports = ring.ports.clone();
+        let bytes: Vec<u8> = ring.bytes.iter().cloned().collect();
+        drop(ring);
+        RecordedLog::decode(ports, &bytes).expect("the ring buffer only holds complete records")
+    }
+
+    // Records port data, if the recorder is recording.
+    pub(crate) fn record(&self, kind: RecordKind, fb_io_system_name: &str, port_name: &str, data: &[u8]) {
+        if !self.is_recording() {
+            return;
+        }
+        let cycle = self.cycle();
+        self.lock().push(kind, fb_io_system_name, port_name, cycle, data);
+    }
+
+    // The ring buffer is consistent after every push, so a poisoned lock is simply taken over.
+    fn lock(&self) -> MutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One port value in a RecordedLog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Whether the data was read or written.
+    pub kind: RecordKind,
+    /// The cycle in which the data was read or written.
+    pub cycle: u64,
+    /// The time since recording started.
+    pub timestamp: Duration,
+    /// Name of the fieldbus I/O system, e.g. "Arp.Io.AxlC".
+    pub fb_io_system_name: String,
+    /// Name of the port, e.g. "Arp.Io.AxlC/0.DI16".
+    pub port_name: String,
+    /// The port data.
+    pub data: Vec<u8>
+}
+
+/// Records taken from the Recorder, or read from a log file.
+///
+/// A log file starts with "PLCNREC" and a version byte, followed by the port table,
+/// a u16 count and then each port as a u16 length and the fieldbus I/O system name,
+/// and a u16 length and the port name. Then come the size of the records in bytes as a u64,
+/// and the records themselves, each with its kind (u8, 0 for read and 1 for write),
+/// port number (u16), cycle (u64), timestamp in nanoseconds (u64), size (u16) and data.
+/// All numbers are little-endian.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RecordedLog {
+    records: Vec<Record>
+}
+
+impl RecordedLog {
+    fn decode(ports: Vec<(String, String)>, mut bytes: &[u8]) -> io::Result<RecordedLog> {
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            if bytes.len() < HEADER_SIZE {
+                return Err(invalid_data("truncated record"));
+            }
+            let kind = RecordKind::from_raw(bytes[0]).ok_or_else(|| invalid_data("invalid record kind"))?;
+            let number = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+            let cycle = u64::from_le_bytes(array(&bytes[3..11]));
+            let timestamp = u64::from_le_bytes(array(&bytes[11..19]));
+            let size = u16::from_le_bytes([bytes[19], bytes[20]]) as usize;
+            if bytes.len() < HEADER_SIZE + size {
+                return Err(invalid_data("truncated record"));
+            }
+            let (fb_io_system_name, port_name) = ports.get(number).ok_or_else(|| invalid_data("invalid port number"))?;
+            records.push(Record {
+                kind,
+                cycle,
+                timestamp: Duration::from_nanos(timestamp),
+                fb_io_system_name: fb_io_system_name.clone(),
+                port_name: port_name.clone(),
+                data: bytes[HEADER_SIZE..HEADER_SIZE + size].to_vec()
+            });
+            bytes = &bytes[HEADER_SIZE + size..];
+        }
+        Ok(RecordedLog { records })
+    }
+
+    /// Reads a log in the binary format.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<RecordedLog> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a recorded log"));
+        }
+        let count = read_u16(&mut reader)?;
+        let mut ports = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let fb_io_system_name = read_string(&mut reader)?;
+            let port_name = read_string(&mut reader)?;
+            ports.push((fb_io_system_name, port_name));
+        }
+        let mut size = [0u8; 8];
+        reader.read_exact(&mut size)?;
+        let mut bytes = Vec::new();
+        reader.take(u64::from_le_bytes(size)).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != u64::from_le_bytes(size) {
+            return Err(invalid_data("truncated log"));
+        }
+        RecordedLog::decode(ports, &bytes)
+    }
+
+    /// Writes the log in the binary format.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut ports: Vec<(&str, &str)> = Vec::new();
+        let mut bytes = Vec::new();
+        for record in &self.records {
+            let key = (record.fb_io_system_name.as_str(), record.port_name.as_str());
+            let number = match ports.iter().position(|&p| p == key) {
+                Some(number) => number,
+                None => {
+                    ports.push(key);
+                    ports.len() - 1
+                }
+            };
+            if number > u16::MAX as usize || record.data.len() > u16::MAX as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many ports, or a record is too big"));
+            }
+            bytes.push(record.kind.to_raw());
+            bytes.extend_from_slice(&(number as u16).to_le_bytes());
+            bytes.extend_from_slice(&record.cycle.to_le_bytes());
+            bytes.extend_from_slice(&(record.timestamp.as_nanos() as u64).to_le_bytes());
+            bytes.extend_from_slice(&(record.data.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(&record.data);
+        }
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(ports.len() as u16).to_le_bytes())?;
+        for (fb_io_system_name, port_name) in ports {
+            write_string(&mut writer, fb_io_system_name)?;
+            write_string(&mut writer, port_name)?;
+        }
+        writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        writer.write_all(&bytes)
+    }
+
+    /// All records, oldest first.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// The numbers of the cycles in the log, in the order they were recorded.
+    pub fn cycles(&self) -> Vec<u64> {
+        let mut cycles: Vec<u64> = Vec::new();
+        for record in &self.records {
+            if cycles.last() != Some(&record.cycle) {
+                cycles.push(record.cycle);
+            }
+        }
+        cycles
+    }
+
+    /// The records of one cycle and kind, in the order they were recorded.
+    pub fn records_in_cycle(&self, cycle: u64, kind: RecordKind) -> impl Iterator<Item = &Record> {
+        self.records.iter().filter(move |r| r.cycle == cycle && r.kind == kind)
+    }
+}
+
+#[cfg(feature = "simulation")]
+impl RecordedLog {
+    /// A port table for the simulation with every port in the log.
+    /// Ports that were written are outputs, and all others are inputs.
+    /// The data type isn't recorded, so every port has the size of its data and an unknown data type.
+    pub fn simulated_ports(&self) -> crate::error::Result<Vec<crate::simulation::SimulatedPort>> {
+        use crate::simulation::{PortDirection, SimulatedPort};
+        use crate::layout::PortDataType;
+
+        let mut ports: Vec<SimulatedPort> = Vec::new();
+        for record in &self.records {
+            let direction = match record.kind {
+                RecordKind::Read => PortDirection::Input,
+                RecordKind::Write => PortDirection::Output
+            };
+            match ports.iter().position(|p| p.name().as_str() == record.port_name) {
+                Some(index) if direction == PortDirection::Output && ports[index].direction() == PortDirection::Input =>
+                    ports[index] = SimulatedPort::new(&record.port_name, direction, PortDataType::Other(0))?.with_size(record.data.len()),
+                Some(_) => {},
+                None => ports.push(SimulatedPort::new(&record.port_name, direction, PortDataType::Other(0))?.with_size(record.data.len()))
+            }
+        }
+        Ok(ports)
+    }
+
+    /// Sets every port that was read in a cycle to the value that was first read from it
+    /// in that cycle, both in the GDS and on the bus of the simulation.
+    /// Reading the inputs then gives the application the same data byte for byte,
+    /// and its outputs can be compared with the records of the same kind in the log.
+    pub fn replay_inputs(&self, cycle: u64) -> crate::error::Result<()> {
+        let mut replayed: Vec<&str> = Vec::new();
+        for record in self.records_in_cycle(cycle, RecordKind::Read) {
+            if !replayed.contains(&record.port_name.as_str()) {
+                crate::simulation::simulation().initialize(&record.port_name, &record.data)?;
+                replayed.push(&record.port_name);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn array(bytes: &[u8]) -> [u8; 8] {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(bytes);
+    array
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut bytes = vec![0u8; read_u16(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("invalid port name"))
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_all(&(value.len() as u16).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes the records in a ring, like Recorder::snapshot() does.
+    fn records(ring: &Ring) -> RecordedLog {
+        let bytes: Vec<u8> = ring.bytes.iter().cloned().collect();
+        RecordedLog::decode(ring.ports.clone(), &bytes).unwrap()
+    }
+
+    fn sample() -> RecordedLog {
+        let mut ring = Ring::new(1024);
+        ring.push(RecordKind::Read, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16", 1, &[1, 2]);
+        ring.push(RecordKind::Write, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DO16", 1, &[3, 4]);
+        ring.push(RecordKind::Read, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16", 2, &[5, 6]);
+        ring.push(RecordKind::Read, "Arp.Io.PnC", "Arp.Io.PnC/axc-f-2152.1.~DI8", 2, &[]);
+        records(&ring)
+    }
+
+    fn encode(log: &RecordedLog) -> Vec<u8> {
+        let mut file = Vec::new();
+        log.write_to(&mut file).unwrap();
+        file
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let log = sample();
+        assert_eq!(log.records().len(), 4);
+        assert_eq!(log.cycles(), vec![1, 2]);
+        assert_eq!(log.records_in_cycle(2, RecordKind::Read).map(|r| r.data.clone()).collect::<Vec<_>>(), vec![vec![5, 6], vec![]]);
+
+        let file = encode(&log);
+        assert!(file.starts_with(MAGIC));
+        assert_eq!(RecordedLog::read_from(&file[..]).unwrap(), log);
+        assert_eq!(RecordedLog::read_from(&encode(&RecordedLog::default())[..]).unwrap(), RecordedLog::default());
+    }
+
+    #[test]
+    fn truncated_file() {
+        let file = encode(&sample());
+        for length in 0..file.len() {
+            assert!(RecordedLog::read_from(&file[..length]).is_err(), "a file cut at {} bytes was read", length);
+        }
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let mut file = encode(&sample());
+        file[0] = b'X';
+        assert_eq!(RecordedLog::read_from(&file[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // The kind of the first record, after the magic, the port table and the size of the records.
+        let mut file = encode(&sample());
+        let first_record = file.len() - 4 * HEADER_SIZE - 6;
+        file[first_record] = 7;
+        assert_eq!(RecordedLog::read_from(&file[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn full_ring_drops_the_oldest_records() {
+        let mut ring = Ring::new(3 * (HEADER_SIZE + 2));
+        for cycle in 0..5 {
+            ring.push(RecordKind::Read, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16", cycle, &[cycle as u8, 0]);
+        }
+        assert_eq!(ring.dropped, 2);
+        assert_eq!(records(&ring).cycles(), vec![2, 3, 4]);
+
+        // A record that is bigger than the whole ring is dropped, and the others are kept.
+        ring.push(RecordKind::Read, "Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16", 5, &[0; 100]);
+        assert_eq!(ring.dropped, 3);
+        assert_eq!(records(&ring).records().len(), 3);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::error::LifecycleError;
+use crate::gds::GdsBuffer;
+use crate::layout::{PortDataType, PortInfo};
+use crate::port_name::PortName;
+use crate::recorder::{recorder, RecordKind};
+use crate::SystemHandle;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// A GDS buffer that is shared by all ports in the same I/O frame.
+pub(crate) type SharedBuffer = Arc<Mutex<GdsBuffer>>;
+
+lazy_static! {
+    static ref PORT_REGISTRY: PortRegistry = PortRegistry { ports: Mutex::new(HashMap::new()) };
+}
+
+/// Gets the port registry used by read_input_data and write_output_data.
+/// All handles in the registry are invalidated when the PLC is reset or unloaded.
+/// * 'system' - The handle returned by load()
+pub fn port_registry(_system: &SystemHandle) -> &'static PortRegistry {
+    crate::install_event_handler();
+    &PORT_REGISTRY
+}
+
+// Invalidates all handles in the port registry, e.g. when the PLC is reset.
+pub(crate) fn invalidate_port_registry() {
+    PORT_REGISTRY.invalidate();
+}
+
+/// A cache of port handles, keyed by fieldbus I/O system name and port name.
+/// The first request for a port is expensive, because the GDS buffer and
+/// the offset to the port are retrieved from the system. After that,
+/// the same handle is returned to every caller until the registry is invalidated.
+/// Ports in the same GDS buffer share a single buffer handle, so that they
+/// can be read or written together under one lock.
+pub struct PortRegistry {
+    ports: Mutex<HashMap<(String, String), Arc<PortHandle>>>
+}
+
+impl PortRegistry {
+    /// Gets the handle to a port, resolving it if necessary.
+    /// * 'fb_io_system_name' - Name of the fieldbus I/O system, e.g. "Arp.Io.AxlC"
+    /// * 'port_name' - Name of the port, e.g. "Arp.Io.AxlC/0.DI16"
+    pub fn get(&self, fb_io_system_name: &str, port_name: &str) -> Result<Arc<PortHandle>> {
+        let mut ports = self.lock();
+        let key = (fb_io_system_name.to_string(), port_name.to_string());
+        if let Some(handle) = ports.get(&key) {
+            return Ok(handle.clone());
+        }
+
+        // The lock is held while the port is resolved, so that two threads
+        // asking for the same port don't both resolve it.
+        // If the port is in a GDS buffer that is already known, that buffer is shared.
+        // Otherwise a new buffer is acquired for the port.
+        let (gds_buffer, info) = match find_shared_buffer(&ports, fb_io_system_name, port_name) {
+            Some(found) => found,
+            None => {
+                // The registry can only be reached through port_registry(),
+                // so the system has already been loaded.
+                let gds_buffer = GdsBuffer::new(&SystemHandle::new(), fb_io_system_name, port_name)?;
+                let info = gds_buffer.info();
+                (Arc::new(Mutex::new(gds_buffer)), info)
+            }
+        };
+        let handle = Arc::new(PortHandle {
+            fb_io_system_name: key.0.clone(),
+            port_name: key.1.clone(),
+            info,
+            gds_buffer,
+            valid: AtomicBool::new(true)
+        });
+        ports.insert(key, handle.clone());
+        Ok(handle)
+    }
+
+    /// Gets the handle to a port, resolving it if necessary.
+    /// The fieldbus I/O system name is taken from the port name.
+    pub fn get_by_name(&self, port_name: &PortName) -> Result<Arc<PortHandle>> {
+        self.get(port_name.fb_io_system_name(), port_name.as_str())
+    }
+
+    /// Removes a single port from the registry and invalidates its handle.
+    pub fn remove(&self, fb_io_system_name: &str, port_name: &str) {
+        let key = (fb_io_system_name.to_string(), port_name.to_string());
+        let handle = self.lock().remove(&key);
+        if let Some(handle) = handle {
+            handle.invalidate();
+        }
+    }
+
+    /// Removes all ports from the registry and invalidates their handles.
+    /// This waits for reads and writes that are in progress to finish,
+    /// so once it returns, none of the handles accesses its GDS buffer any more.
+    /// GDS buffers are released when the last reference to each handle is dropped.
+    pub fn invalidate(&self) {
+        // The registry isn't locked while waiting, so that other ports can still be resolved.
+        let handles: Vec<_> = self.lock().drain().map(|(_, handle)| handle).collect();
+        for handle in handles {
+            handle.invalidate();
+        }
+    }
+
+    /// The number of ports in the registry.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if there are no ports in the registry.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    // A panic while holding the lock can't leave the map in an inconsistent state,
+    // so a poisoned lock is simply taken over.
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, String), Arc<PortHandle>>> {
+        self.ports.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Looks for the named port in the GDS buffers of ports that are already resolved
+// in the same I/O system. Each buffer is only checked once.
+fn find_shared_buffer(ports: &HashMap<(String, String), Arc<PortHandle>>, fb_io_system_name: &str, port_name: &str)
+    -> Option<(SharedBuffer, PortInfo)> {
+
+    let mut checked: Vec<&SharedBuffer> = Vec::new();
+    for handle in ports.values().filter(|h| h.fb_io_system_name == fb_io_system_name) {
+        if checked.iter().any(|b| Arc::ptr_eq(b, &handle.gds_buffer)) {
+            continue;
+        }
+        checked.push(&handle.gds_buffer);
+
+        // The port info can only be retrieved if the port is in this buffer.
+        let mut gds_buffer = handle.gds_buffer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Ok(info) = gds_buffer.port_info(port_name) {
+            return Some((handle.gds_buffer.clone(), info));
+        }
+    }
+    None
+}
+
+/// A resolved port, which can be shared between threads.
+/// Access to the underlying GDS buffer is serialised by the handle.
+pub struct PortHandle {
+    fb_io_system_name: String,
+    port_name: String,
+    info: PortInfo,
+    gds_buffer: SharedBuffer,
+    valid: AtomicBool
+}
+
+impl PortHandle {
+    /// Name of the fieldbus I/O system containing the port.
+    pub fn fb_io_system_name(&self) -> &str {
+        &self.fb_io_system_name
+    }
+
+    /// Name of the port.
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// The offset to the port data in the GDS buffer page.
+    pub fn offset(&self) -> usize {
+        self.info.offset
+    }
+
+    /// The size of the port data in bytes.
+    pub fn size(&self) -> usize {
+        self.info.size
+    }
+
+    /// The data type of the port.
+    pub fn data_type(&self) -> PortDataType {
+        self.info.data_type
+    }
+
+    /// The layout of the port in the GDS buffer page.
+    pub fn info(&self) -> PortInfo {
+        self.info
+    }
+
+    /// Returns false once the registry has been invalidated,
+    /// e.g. because the PLC has been reset or unloaded.
+    pub fn is_valid(&self) -> bool {
+        self.valid.load(Ordering::SeqCst)
+    }
+
+    /// Calls 'f' with the port data, while the GDS buffer is locked for reading.
+    /// The data is not copied.
+    pub fn with_data<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> Result<R> {
+        let mut gds_buffer = self.buffer()?;
+        let page = gds_buffer.read()?;
+        let result = f(page.port(&self.info));
+        recorder().record(RecordKind::Read, &self.fb_io_system_name, &self.port_name, page.port(&self.info));
+        page.end()?;
+        Ok(result)
+    }
+
+    /// Calls 'f' with the port data, while the GDS buffer is locked for writing.
+    /// The data is not copied.
+    pub fn with_data_mut<R, F: FnOnce(&mut [u8]) -> R>(&self, f: F) -> Result<R> {
+        self.try_with_data_mut(|data| Ok(f(data)))
+    }
+
+    /// Like with_data_mut(), for a closure that can fail.
+    /// The write is only recorded if 'f' returns Ok, so 'f' should not change the data
+    /// before it knows that it will succeed.
+    pub fn try_with_data_mut<R, F: FnOnce(&mut [u8]) -> Result<R>>(&self, f: F) -> Result<R> {
+        let mut gds_buffer = self.buffer()?;
+        let mut page = gds_buffer.write()?;
+        let result = f(page.port_mut(&self.info));
+        if result.is_ok() {
+            recorder().record(RecordKind::Write, &self.fb_io_system_name, &self.port_name, page.port_mut(&self.info));
+        }
+        page.end()?;
+        result
+    }
+
+    /// Copies data from the port.
+    /// The length of 'value' must be the size of the port.
+    pub fn read(&self, value: &mut [u8]) -> Result<()> {
+        self.check_len(value.len())?;
+        self.with_data(|data| value.copy_from_slice(data))
+    }
+
+    /// Copies data to the port.
+    /// The length of 'value' must be the size of the port.
+    pub fn write(&self, value: &[u8]) -> Result<()> {
+        self.check_len(value.len())?;
+        self.with_data_mut(|data| data.copy_from_slice(value))
+    }
+
+    // The GDS buffer containing this port, which may be shared with other ports.
+    pub(crate) fn shared_buffer(&self) -> &SharedBuffer {
+        &self.gds_buffer
+    }
+
+    // Gets exclusive access to the GDS buffer, if the handle is still valid.
+    pub(crate) fn buffer(&self) -> Result<MutexGuard<'_, GdsBuffer>> {
+        let gds_buffer = self.gds_buffer.lock().unwrap_or_else(|e| e.into_inner());
+        // Checked with the lock held. invalidate() takes the same lock, so the handle
+        // can't be invalidated between the check and the end of the access.
+        self.check_valid()?;
+        Ok(gds_buffer)
+    }
+
+    // Marks the handle as invalid, once the access in progress, if any, has finished.
+    fn invalidate(&self) {
+        let _gds_buffer = self.gds_buffer.lock().unwrap_or_else(|e| e.into_inner());
+        self.valid.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn check_valid(&self) -> Result<()> {
+        if !self.is_valid() {
+            return Err(LifecycleError::HandleInvalidated { port_name: self.port_name.clone() }.into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_len(&self, len: usize) -> Result<()> {
+        if len != self.info.size {
+            return Err(PlcnextError::length_mismatch(&self.port_name, self.info.size, len));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "simulation"))]
+mod tests {
+    use super::*;
+    use crate::simulation::{self, simulation, PortDirection, SimulatedPort};
+    use crate::PlcOperation;
+
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const DI16: &str = "Arp.Io.AxlC/0.DI16";
+    const DI8: &str = "Arp.Io.AxlC/1.DI8";
+    const DO16: &str = "Arp.Io.AxlC/2.DO16";
+
+    // Configures two inputs and an output in one I/O system, with an empty registry.
+    fn registry() -> &'static PortRegistry {
+        simulation().configure(&[
+            SimulatedPort::new(DI16, PortDirection::Input, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new(DI8, PortDirection::Input, PortDataType::UInt8).unwrap(),
+            SimulatedPort::new(DO16, PortDirection::Output, PortDataType::UInt16).unwrap()
+        ]).unwrap();
+        simulation().trigger(PlcOperation::Unload);
+        port_registry(&SystemHandle::new())
+    }
+
+    #[test]
+    fn cache_hits() {
+        let _simulation = simulation::exclusive();
+        let registry = registry();
+        assert!(registry.is_empty());
+
+        let port = registry.get("Arp.Io.AxlC", DI16).unwrap();
+        assert!(Arc::ptr_eq(&port, &registry.get("Arp.Io.AxlC", DI16).unwrap()));
+        assert!(Arc::ptr_eq(&port, &registry.get_by_name(&PortName::parse(DI16).unwrap()).unwrap()));
+        assert_eq!(registry.len(), 1);
+
+        // Ports in the same frame share its GDS buffer, and the output frame has its own.
+        let other = registry.get("Arp.Io.AxlC", DI8).unwrap();
+        let output = registry.get("Arp.Io.AxlC", DO16).unwrap();
+        assert!(Arc::ptr_eq(port.shared_buffer(), other.shared_buffer()));
+        assert!(!Arc::ptr_eq(port.shared_buffer(), output.shared_buffer()));
+        assert_eq!((other.offset(), other.size(), other.data_type()), (2, 1, PortDataType::UInt8));
+        assert_eq!(registry.len(), 3);
+
+        assert!(registry.get("Arp.Io.AxlC", "Arp.Io.AxlC/9.DI16").is_err());
+        assert_eq!(registry.len(), 3);
+    }
+
+    #[test]
+    fn invalidated_on_reset_and_unload() {
+        let _simulation = simulation::exclusive();
+        let registry = registry();
+
+        for &operation in &[PlcOperation::Reset, PlcOperation::Unload] {
+            let port = registry.get("Arp.Io.AxlC", DO16).unwrap();
+            port.write(&[1, 2]).unwrap();
+            simulation().trigger(operation);
+            assert!(!port.is_valid());
+            assert!(registry.is_empty());
+            match port.write(&[3, 4]) {
+                Err(PlcnextError::Lifecycle(LifecycleError::HandleInvalidated { port_name })) => assert_eq!(port_name, DO16),
+                other => panic!("Expected an invalidated handle, got {:?}", other)
+            }
+        }
+
+        // Other operations leave the handles alone.
+        let port = registry.get("Arp.Io.AxlC", DO16).unwrap();
+        simulation().trigger(PlcOperation::Stop);
+        assert!(port.is_valid());
+    }
+
+    #[test]
+    fn resolved_again_after_invalidation() {
+        let _simulation = simulation::exclusive();
+        let registry = registry();
+
+        let old = registry.get("Arp.Io.AxlC", DO16).unwrap();
+        old.write(&[5, 6]).unwrap();
+        registry.invalidate();
+        let new = registry.get("Arp.Io.AxlC", DO16).unwrap();
+        assert!(!Arc::ptr_eq(&old, &new));
+        assert!(new.is_valid() && !old.is_valid());
+        let mut value = [0u8; 2];
+        new.read(&mut value).unwrap();
+        assert_eq!(value, [5, 6]);
+
+        registry.remove("Arp.Io.AxlC", DO16);
+        assert!(!new.is_valid());
+        assert!(registry.get("Arp.Io.AxlC", DO16).unwrap().is_valid());
+    }
+
+    #[test]
+    fn invalidate_waits_for_access_in_progress() {
+        let _simulation = simulation::exclusive();
+        let registry = registry();
+        let port = registry.get("Arp.Io.AxlC", DO16).unwrap();
+
+        let (started, wait) = mpsc::channel();
+        let writer = thread::spawn(move || port.with_data_mut(|data| {
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            data.copy_from_slice(&[7, 8]);
+        }));
+        wait.recv().unwrap();
+        let start = Instant::now();
+        registry.invalidate();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        writer.join().unwrap().unwrap();
+    }
+}
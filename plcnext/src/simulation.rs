@@ -0,0 +1,759 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::layout::PortDataType;
+use crate::port_name::PortName;
+use crate::waveform::{self, Waveform};
+use crate::PlcOperation;
+
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The direction of a simulated port, seen from the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortDirection {
+    /// The port is transferred from the bus to the GDS by read_from_axio_to_gds().
+    Input,
+    /// The port is transferred from the GDS to the bus by write_from_gds_to_axio().
+    Output
+}
+
+/// A port in the port table of the simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedPort {
+    name: PortName,
+    direction: PortDirection,
+    data_type: PortDataType,
+    size: usize
+}
+
+impl SimulatedPort {
+    /// Creates a port with the size of its data type.
+    /// Strings and unknown data types have no fixed size, so their size must be set with with_size().
+    /// * 'port_name' - Name of the port, e.g. "Arp.Io.AxlC/0.DI16"
+    /// * 'direction' - Whether the port is an input or an output
+    /// * 'data_type' - The data type reported by the data layout
+    pub fn new(port_name: &str, direction: PortDirection, data_type: PortDataType) -> Result<SimulatedPort> {
+        let name = PortName::parse(port_name)?;
+        Ok(SimulatedPort { name, direction, data_type, size: fixed_size(data_type) })
+    }
+
+    /// Sets the size of the port data in bytes.
+    pub fn with_size(mut self, bytes: usize) -> SimulatedPort {
+        self.size = bytes;
+        self
+    }
+
+    /// The name of the port.
+    pub fn name(&self) -> &PortName {
+        &self.name
+    }
+
+    /// Whether the port is an input or an output.
+    pub fn direction(&self) -> PortDirection {
+        self.direction
+    }
+
+    /// The data type of the port.
+    pub fn data_type(&self) -> PortDataType {
+        self.data_type
+    }
+
+    /// The size of the port data in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+// The size of a data type, or zero if it has no fixed size.
+fn fixed_size(data_type: PortDataType) -> usize {
+    match data_type {
+        PortDataType::Bit | PortDataType::Boolean | PortDataType::UInt8 | PortDataType::Int8 | PortDataType::Char8 => 1,
+        PortDataType::Char16 | PortDataType::UInt16 | PortDataType::Int16 => 2,
+        PortDataType::UInt32 | PortDataType::Int32 | PortDataType::Float32 | PortDataType::IecTime => 4,
+        PortDataType::UInt64 | PortDataType::Int64 | PortDataType::Float64 | PortDataType::IecTime64 => 8,
+        PortDataType::StaticString | PortDataType::IecString | PortDataType::Other(_) => 0
+    }
+}
+
+// The layout of a port in its frame.
+struct FramePort {
+    name: String,
+    offset: usize,
+    size: usize,
+    data_type: PortDataType
+}
+
+// The ports of one direction of one fieldbus I/O system, which share a GDS buffer.
+// The GDS page is what the application reads and writes, and the bus image is
+// what the simulated devices see. The two are copied by the Axioline transfers.
+struct Frame {
+    direction: PortDirection,
+    ports: Vec<FramePort>,
+    page: UnsafeCell<Box<[u8]>>,
+    bus: Mutex<Vec<u8>>,
+    // The buffer that has locked the page, if any. The page is only accessed while it is locked.
+    owner: Mutex<Option<usize>>,
+    unlocked: Condvar
+}
+
+// The page is only accessed by the owner of the lock.
+unsafe impl Sync for Frame {}
+
+// The owner of the page lock during an Axioline transfer.
+const TRANSFER: usize = 0;
+
+impl Frame {
+    fn port(&self, port_name: &str) -> Option<&FramePort> {
+        self.ports.iter().find(|p| p.name == port_name)
+    }
+
+    // Locks the page. Returns false if the timeout expires first.
+    fn lock_page(&self, owner: usize, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut current = self.owner.lock().unwrap_or_else(|e| e.into_inner());
+        while current.is_some() {
+            current = match deadline {
+                None => self.unlocked.wait(current).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.unlocked.wait_timeout(current, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+                }
+            };
+        }
+        *current = Some(owner);
+        true
+    }
+
+    // Unlocks the page, if it is locked by the given owner.
+    fn unlock_page(&self, owner: usize) {
+        let mut current = self.owner.lock().unwrap_or_else(|e| e.into_inner());
+        if *current == Some(owner) {
+            *current = None;
+            self.unlocked.notify_all();
+        }
+    }
+
+    // Copies between the page and the bus image, in the direction of the frame.
+    // The page must be locked by the caller.
+    fn transfer(&self) {
+        let mut bus = self.bus.lock().unwrap_or_else(|e| e.into_inner());
+        let page = unsafe { &mut *self.page.get() };
+        match self.direction {
+            PortDirection::Input => page.copy_from_slice(&bus),
+            PortDirection::Output => bus.copy_from_slice(page)
+        }
+    }
+}
+
+struct State {
+    frames: HashMap<(String, PortDirection), Arc<Frame>>,
+    handler: ffi::PlcOperationHandler,
+    hardware_id: [u8; 32],
+    last_error: String,
+    failures: HashMap<String, String>,
+    waveforms: Vec<(String, Waveform)>,
+    time: Duration,
+    bus_cycle: Duration
+}
+
+impl State {
+    // Finds the frame that contains a port.
+    fn frame(&self, port_name: &str) -> Result<&Arc<Frame>> {
+        self.frames.values()
+            .find(|f| f.port(port_name).is_some())
+            .ok_or_else(|| PlcnextError::invalid_value(&format!("Port '{}' is not in the simulated port table", port_name)))
+    }
+
+    // Returns the message if the next call to the function should fail.
+    fn failure(&mut self, function: &str) -> Option<String> {
+        self.failures.remove(function)
+    }
+
+    // Sets every input port with a waveform on the bus to its value at the current time.
+    fn apply_waveforms(&self) {
+        for (port_name, waveform) in &self.waveforms {
+            if let Ok(frame) = self.frame(port_name) {
+                if let Some(port) = frame.port(port_name) {
+                    let mut bus = frame.bus.lock().unwrap_or_else(|e| e.into_inner());
+                    // The data type and size were checked by set_waveform().
+                    let _ = waveform::encode(port_name, port.data_type, waveform.value_at(self.time),
+                        &mut bus[port.offset..port.offset + port.size]);
+                }
+            }
+        }
+    }
+}
+
+/// An in-process emulation of the ANSI-C library, enabled by the simulation feature.
+///
+/// The ports are defined by a port table. Each fieldbus I/O system has an input and an
+/// output frame, each with its own GDS buffer, in which the ports are laid out in the order
+/// of the table. Besides the GDS page, each frame has a bus image, which stands in for
+/// the devices on the bus: read_from_axio_to_gds() copies the bus image of every input
+/// frame to its page, and write_from_gds_to_axio() copies the page of every output frame
+/// to its bus image. Tests set inputs with set_input() and check outputs with output().
+///
+/// Input ports can also follow a waveform over the simulated bus time, which advances by
+/// one bus cycle with every read_from_axio_to_gds(), so that tests are deterministic.
+///
+/// The simulation doesn't run a PLC lifecycle of its own. PLC operations are passed to the
+/// application with trigger(), in the same way as the ANSI-C library calls our callback function.
+pub struct Simulation {
+    state: Mutex<State>
+}
+
+// The bus cycle of the simulation, until it is set with set_bus_cycle().
+const DEFAULT_BUS_CYCLE: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    static ref SIMULATION: Simulation = Simulation {
+        state: Mutex::new(State {
+            frames: HashMap::new(),
+            handler: None,
+            hardware_id: [0; 32],
+            last_error: String::new(),
+            failures: HashMap::new(),
+            waveforms: Vec::new(),
+            time: Duration::from_secs(0),
+            bus_cycle: DEFAULT_BUS_CYCLE
+        })
+    };
+}
+
+/// Gets the simulation that replaces the ANSI-C library.
+pub fn simulation() -> &'static Simulation {
+    &SIMULATION
+}
+
+impl Simulation {
+    /// Replaces the port table. All inputs and outputs are set to zero,
+    /// the waveforms are removed, and the bus time is set to zero.
+    /// This should be done before load(), like downloading a project to a controller,
+    /// because GDS buffers that were acquired from the old table keep the old layout.
+    /// * 'ports' - The ports, in the order in which they are laid out in their frames
+    pub fn configure(&self, ports: &[SimulatedPort]) -> Result<()> {
+        let mut layouts: HashMap<(String, PortDirection), Vec<FramePort>> = HashMap::new();
+        let mut sizes: HashMap<(String, PortDirection), usize> = HashMap::new();
+        for (index, port) in ports.iter().enumerate() {
+            if port.size == 0 {
+                return Err(PlcnextError::invalid_value(&format!("Port '{}' has no size", port.name)));
+            }
+            if ports[..index].iter().any(|p| p.name == port.name) {
+                return Err(PlcnextError::invalid_value(&format!("Port '{}' is in the port table twice", port.name)));
+            }
+            let key = (port.name.fb_io_system_name().to_string(), port.direction);
+            let size = sizes.entry(key.clone()).or_insert(0);
+            layouts.entry(key).or_default().push(FramePort {
+                name: port.name.as_str().to_string(),
+                offset: *size,
+                size: port.size,
+                data_type: port.data_type
+            });
+            *size += port.size;
+        }
+
+        let frames = layouts.into_iter().map(|(key, ports)| {
+            let size = sizes[&key];
+            let frame = Frame {
+                direction: key.1,
+                ports,
+                page: UnsafeCell::new(vec![0; size].into_boxed_slice()),
+                bus: Mutex::new(vec![0; size]),
+                owner: Mutex::new(None),
+                unlocked: Condvar::new()
+            };
+            (key, Arc::new(frame))
+        }).collect();
+
+        let mut state = self.lock();
+        state.frames = frames;
+        state.waveforms.clear();
+        state.time = Duration::from_secs(0);
+        Ok(())
+    }
+
+    /// Sets a port both in the GDS and on the bus, e.g. to give it an initial value.
+    /// The length of 'value' must be the size of the port.
+    pub fn initialize(&self, port_name: &str, value: &[u8]) -> Result<()> {
+        let (frame, offset) = {
+            let state = self.lock();
+            let frame = state.frame(port_name)?;
+            let port = frame.port(port_name).filter(|p| p.size == value.len()).ok_or_else(|| {
+                let size = frame.port(port_name).map_or(0, |p| p.size);
+                PlcnextError::length_mismatch(port_name, size, value.len())
+            })?;
+            (frame.clone(), port.offset)
+        };
+        // The simulation isn't locked while waiting for the page,
+        // so that the application can unlock it.
+        frame.lock_page(TRANSFER, None);
+        let page = unsafe { &mut *frame.page.get() };
+        page[offset..offset + value.len()].copy_from_slice(value);
+        frame.bus.lock().unwrap_or_else(|e| e.into_inner())[offset..offset + value.len()].copy_from_slice(value);
+        frame.unlock_page(TRANSFER);
+        Ok(())
+    }
+
+    /// Makes an input port follow a waveform on the bus, instead of the value from set_input().
+    /// The port must have a numeric data type. The value is encoded in the byte order
+    /// of the controller, and integers are rounded.
+    pub fn set_waveform(&self, port_name: &str, waveform: Waveform) -> Result<()> {
+        let mut state = self.lock();
+        {
+            let frame = state.frame(port_name)?;
+            let port = port_on_bus(frame, port_name, PortDirection::Input, 0)?;
+            let mut bus = frame.bus.lock().unwrap_or_else(|e| e.into_inner());
+            waveform::encode(port_name, port.data_type, waveform.value_at(state.time), &mut bus[port.offset..port.offset + port.size])?;
+        }
+        state.waveforms.retain(|(name, _)| name != port_name);
+        state.waveforms.push((port_name.to_string(), waveform));
+        Ok(())
+    }
+
+    /// Sets the time by which the bus time advances with every read_from_axio_to_gds().
+    /// By default, this is 10 ms.
+    pub fn set_bus_cycle(&self, bus_cycle: Duration) {
+        self.lock().bus_cycle = bus_cycle;
+    }
+
+    /// The simulated bus time, at which the waveforms are evaluated
+    /// by the next read_from_axio_to_gds().
+    pub fn time(&self) -> Duration {
+        self.lock().time
+    }
+
+    /// Sets the simulated bus time, e.g. to skip to a later part of the waveforms.
+    pub fn set_time(&self, time: Duration) {
+        self.lock().time = time;
+    }
+
+    /// Sets the value of an input port on the bus, and removes its waveform, if any.
+    /// The value reaches the GDS with the next read_from_axio_to_gds().
+    /// The length of 'value' must be the size of the port.
+    pub fn set_input(&self, port_name: &str, value: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        {
+            let frame = state.frame(port_name)?;
+            let port = port_on_bus(frame, port_name, PortDirection::Input, value.len())?;
+            frame.bus.lock().unwrap_or_else(|e| e.into_inner())[port.offset..port.offset + port.size].copy_from_slice(value);
+        }
+        state.waveforms.retain(|(name, _)| name != port_name);
+        Ok(())
+    }
+
+    /// Gets the value of an output port on the bus,
+    /// as written by the last write_from_gds_to_axio().
+    pub fn output(&self, port_name: &str) -> Result<Vec<u8>> {
+        let state = self.lock();
+        let frame = state.frame(port_name)?;
+        let port = port_on_bus(frame, port_name, PortDirection::Output, 0)?;
+        let bus = frame.bus.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(bus[port.offset..port.offset + port.size].to_vec())
+    }
+
+    /// Passes a PLC operation to the application, as the ANSI-C library does
+    /// when the PLC changes state. This returns after all subscribers have handled it.
+    pub fn trigger(&self, operation: PlcOperation) {
+        crate::install_event_handler();
+        // The handler is called without holding the lock, because subscribers
+        // may access ports while they handle the operation.
+        let handler = self.lock().handler;
+        if let Some(handler) = handler {
+            unsafe { handler(operation as ffi::PlcOperation) };
+        }
+    }
+
+    /// Sets the id returned by get_unique_hardware_id(). By default, it is all zeros.
+    pub fn set_unique_hardware_id(&self, id: [u8; 32]) {
+        self.lock().hardware_id = id;
+    }
+
+    /// Makes the next call to an ANSI-C function fail, with the given message
+    /// as its last error, e.g. to test how the application handles a failed transfer.
+    /// * 'function' - Name of the ANSI-C function, e.g. "ArpPlcAxio_ReadFromAxioToGds"
+    /// * 'message' - The text that get_last_error() returns after the call
+    pub fn fail_next(&self, function: &str, message: &str) {
+        self.lock().failures.insert(function.to_string(), message.to_string());
+    }
+
+    // The simulation is always consistent, so a poisoned lock is simply taken over.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// The simulation, the event bus and the PLC state are shared by the whole process,
+// so the tests that use them run one at a time.
+#[cfg(test)]
+pub(crate) fn exclusive() -> MutexGuard<'static, ()> {
+    lazy_static! {
+        static ref EXCLUSIVE: Mutex<()> = Mutex::new(());
+    }
+    EXCLUSIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Finds a port of the given direction in a frame.
+// A non-zero length must be the size of the port.
+fn port_on_bus<'a>(frame: &'a Frame, port_name: &str, direction: PortDirection, length: usize) -> Result<&'a FramePort> {
+    let port = frame.port(port_name).ok_or_else(|| PlcnextError::invalid_value(&format!("Port '{}' is not in the frame", port_name)))?;
+    if frame.direction != direction {
+        return Err(PlcnextError::invalid_value(&format!("Port '{}' is not an {:?} port", port_name, direction)));
+    }
+    if length != 0 && length != port.size {
+        return Err(PlcnextError::length_mismatch(port_name, port.size, length));
+    }
+    Ok(port)
+}
+
+// The emulated ANSI-C functions, with the same names and signatures as in the plcnext-sys crate.
+// As in the ANSI-C library, a function that fails returns false and sets the last error.
+#[allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+pub(crate) mod ffi {
+    use super::{PortDirection, State, TRANSFER, SIMULATION};
+
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int, c_ulong};
+    use std::sync::{Arc, MutexGuard};
+    use std::time::Duration;
+
+    pub(crate) type PlcOperation = c_int;
+    pub(crate) const PlcOperation_PlcOperation_None: PlcOperation = 0;
+    pub(crate) const PlcOperation_PlcOperation_Load: PlcOperation = 1;
+    pub(crate) const PlcOperation_PlcOperation_Setup: PlcOperation = 2;
+    pub(crate) const PlcOperation_PlcOperation_StartCold: PlcOperation = 3;
+    pub(crate) const PlcOperation_PlcOperation_StartWarm: PlcOperation = 4;
+    pub(crate) const PlcOperation_PlcOperation_StartHot: PlcOperation = 5;
+    pub(crate) const PlcOperation_PlcOperation_Stop: PlcOperation = 6;
+    pub(crate) const PlcOperation_PlcOperation_Reset: PlcOperation = 7;
+    pub(crate) const PlcOperation_PlcOperation_Unload: PlcOperation = 8;
+    pub(crate) type PlcOperationHandler = Option<unsafe extern "C" fn(operation: PlcOperation)>;
+
+    // A GDS buffer, which refers to the frame of the port it was acquired for.
+    pub(crate) struct TGdsBuffer {
+        frame: Arc<super::Frame>
+    }
+
+    pub(crate) struct TDataLayoutInfo {
+        pub(crate) Offset: usize,
+        pub(crate) Size: usize,
+        pub(crate) DataType: u32
+    }
+
+    fn state() -> MutexGuard<'static, State> {
+        SIMULATION.lock()
+    }
+
+    // Sets the last error and returns false.
+    fn fail(state: &mut State, message: String) -> bool {
+        state.last_error = message;
+        false
+    }
+
+    // Turns a timeout in milliseconds into a duration, where zero means forever.
+    // The conversion is only needed where c_ulong is 32 bits, e.g. on ARM.
+    #[allow(clippy::useless_conversion)]
+    fn timeout(milliseconds: c_ulong) -> Option<Duration> {
+        if milliseconds == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(u64::from(milliseconds)))
+        }
+    }
+
+    unsafe fn string(value: *const c_char) -> String {
+        CStr::from_ptr(value).to_string_lossy().into_owned()
+    }
+
+    pub(crate) unsafe fn ArpSystemModule_Load(_arp_binary_dir: *const c_char, _application_name: *const c_char, _acf_settings_path: *const c_char) -> c_int {
+        let mut state = state();
+        match state.failure("ArpSystemModule_Load") {
+            Some(message) => {
+                fail(&mut state, message);
+                -1
+            },
+            None => 0
+        }
+    }
+
+    pub(crate) unsafe fn ArpPlcDomain_SetHandler(handler: PlcOperationHandler) {
+        state().handler = handler;
+    }
+
+    pub(crate) unsafe fn ArpPlcDevice_GetUniqueHardwareId(out_id: *mut u8) -> bool {
+        let mut state = state();
+        if let Some(message) = state.failure("ArpPlcDevice_GetUniqueHardwareId") {
+            return fail(&mut state, message);
+        }
+        std::ptr::copy_nonoverlapping(state.hardware_id.as_ptr(), out_id, state.hardware_id.len());
+        true
+    }
+
+    pub(crate) unsafe fn ArpPlc_GetLastError(buffer: *mut u8, size: c_int) {
+        let message = std::mem::take(&mut state().last_error);
+        if size <= 0 {
+            return;
+        }
+        // The message is truncated to leave room for the terminating NUL character.
+        let length = message.len().min(size as usize - 1);
+        std::ptr::copy_nonoverlapping(message.as_ptr(), buffer, length);
+        *buffer.add(length) = 0;
+    }
+
+    pub(crate) unsafe fn ArpPlcIo_GetBufferPtrByPortName(fb_io_system_name: *const c_char, port_name: *const c_char, gds_buffer: *mut *mut TGdsBuffer) -> bool {
+        let mut state = state();
+        if let Some(message) = state.failure("ArpPlcIo_GetBufferPtrByPortName") {
+            return fail(&mut state, message);
+        }
+        let (fb_io_system_name, port_name) = (string(fb_io_system_name), string(port_name));
+        let frame = [PortDirection::Input, PortDirection::Output].iter()
+            .filter_map(|&direction| state.frames.get(&(fb_io_system_name.clone(), direction)))
+            .find(|f| f.port(&port_name).is_some())
+            .cloned();
+        match frame {
+            Some(frame) => {
+                *gds_buffer = Box::into_raw(Box::new(TGdsBuffer { frame }));
+                true
+            },
+            None => fail(&mut state, format!("Port '{}' not found in I/O system '{}'", port_name, fb_io_system_name))
+        }
+    }
+
+    pub(crate) unsafe fn ArpPlcIo_ReleaseGdsBuffer(gds_buffer: *mut TGdsBuffer) -> bool {
+        let mut state = state();
+        if let Some(message) = state.failure("ArpPlcIo_ReleaseGdsBuffer") {
+            return fail(&mut state, message);
+        }
+        let buffer = Box::from_raw(gds_buffer);
+        buffer.frame.unlock_page(gds_buffer as usize);
+        true
+    }
+
+    pub(crate) unsafe fn ArpPlcGds_GetDataLayoutInfo(gds_buffer: *mut TGdsBuffer, port_name: *const c_char, layout_info: *mut TDataLayoutInfo) -> bool {
+        let mut state = state();
+        if let Some(message) = state.failure("ArpPlcGds_GetDataLayoutInfo") {
+            return fail(&mut state, message);
+        }
+        let port_name = string(port_name);
+        match (*gds_buffer).frame.port(&port_name) {
+            Some(port) => {
+                *layout_info = TDataLayoutInfo { Offset: port.offset, Size: port.size, DataType: port.data_type.to_raw() };
+                true
+            },
+            None => fail(&mut state, format!("Port '{}' is not in this GDS buffer", port_name))
+        }
+    }
+
+    pub(crate) unsafe fn ArpPlcGds_GetVariableOffset(gds_buffer: *mut TGdsBuffer, port_name: *const c_char, offset: *mut usize) -> bool {
+        let mut state = state();
+        if let Some(message) = state.failure("ArpPlcGds_GetVariableOffset") {
+            return fail(&mut state, message);
+        }
+        let port_name = string(port_name);
+        match (*gds_buffer).frame.port(&port_name) {
+            Some(port) => {
+                *offset = port.offset;
+                true
+            },
+            None => fail(&mut state, format!("Port '{}' is not in this GDS buffer", port_name))
+        }
+    }
+
+    // Locks the page of a buffer and returns a pointer to it.
+    // The simulation doesn't distinguish readers from writers, so every lock is exclusive.
+    unsafe fn begin(function: &str, gds_buffer: *mut TGdsBuffer, data_buffer_page: *mut *mut c_char) -> bool {
+        let frame = {
+            let mut state = state();
+            if let Some(message) = state.failure(function) {
+                return fail(&mut state, message);
+            }
+            (*gds_buffer).frame.clone()
+        };
+        // The simulation isn't locked while waiting, so that the owner can unlock the page.
+        frame.lock_page(gds_buffer as usize, None);
+        *data_buffer_page = (*frame.page.get()).as_mut_ptr() as *mut c_char;
+        true
+    }
+
+    unsafe fn end(function: &str, gds_buffer: *mut TGdsBuffer) -> bool {
+        let mut state = state();
+        if let Some(message) = state.failure(function) {
+            return fail(&mut state, message);
+        }
+        (*gds_buffer).frame.unlock_page(gds_buffer as usize);
+        true
+    }
+
+    pub(crate) unsafe fn ArpPlcGds_BeginRead(gds_buffer: *mut TGdsBuffer, data_buffer_page: *mut *mut c_char) -> bool {
+        begin("ArpPlcGds_BeginRead", gds_buffer, data_buffer_page)
+    }
+
+    pub(crate) unsafe fn ArpPlcGds_EndRead(gds_buffer: *mut TGdsBuffer) -> bool {
+        end("ArpPlcGds_EndRead", gds_buffer)
+    }
+
+    pub(crate) unsafe fn ArpPlcGds_BeginWrite(gds_buffer: *mut TGdsBuffer, data_buffer_page: *mut *mut c_char) -> bool {
+        begin("ArpPlcGds_BeginWrite", gds_buffer, data_buffer_page)
+    }
+
+    pub(crate) unsafe fn ArpPlcGds_EndWrite(gds_buffer: *mut TGdsBuffer) -> bool {
+        end("ArpPlcGds_EndWrite", gds_buffer)
+    }
+
+    // Copies every frame of the given direction between its page and its bus image.
+    fn transfer(function: &str, direction: PortDirection, milliseconds: c_ulong) -> bool {
+        let frames: Vec<_> = {
+            let mut state = state();
+            if let Some(message) = state.failure(function) {
+                return fail(&mut state, message);
+            }
+            // The waveforms are applied to the bus before the inputs are transferred,
+            // and the bus time moves on to the next cycle.
+            if direction == PortDirection::Input {
+                state.apply_waveforms();
+                let bus_cycle = state.bus_cycle;
+                state.time += bus_cycle;
+            }
+            state.frames.values().filter(|f| f.direction == direction).cloned().collect()
+        };
+        for frame in frames {
+            if !frame.lock_page(TRANSFER, timeout(milliseconds)) {
+                return fail(&mut state(), format!("Timeout after {} ms waiting for a GDS buffer", milliseconds));
+            }
+            frame.transfer();
+            frame.unlock_page(TRANSFER);
+        }
+        true
+    }
+
+    pub(crate) unsafe fn ArpPlcAxio_ReadFromAxioToGds(timeout: c_ulong) -> bool {
+        transfer("ArpPlcAxio_ReadFromAxioToGds", PortDirection::Input, timeout)
+    }
+
+    pub(crate) unsafe fn ArpPlcAxio_WriteFromGdsToAxio(timeout: c_ulong) -> bool {
+        transfer("ArpPlcAxio_WriteFromGdsToAxio", PortDirection::Output, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SystemError;
+    use crate::events;
+    use crate::gds::GdsBuffer;
+    use crate::state::{plc_state, PlcState};
+    use crate::SystemHandle;
+
+    use std::sync::mpsc;
+
+    const INPUT: &str = "Arp.Io.AxlC/0.DI16";
+    const OUTPUT: &str = "Arp.Io.AxlC/1.DO16";
+
+    // Configures one input and one output port, and unloads the PLC,
+    // so that no port handles are left over from another test.
+    fn configure() -> SystemHandle {
+        simulation().configure(&[
+            SimulatedPort::new(INPUT, PortDirection::Input, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new(OUTPUT, PortDirection::Output, PortDataType::UInt16).unwrap()
+        ]).unwrap();
+        simulation().trigger(PlcOperation::Unload);
+        SystemHandle::new()
+    }
+
+    #[test]
+    fn gds_write_and_read_back() {
+        let _simulation = exclusive();
+        let system = configure();
+
+        let mut writer = GdsBuffer::new(&system, "Arp.Io.AxlC", OUTPUT).unwrap();
+        assert_eq!(writer.size(), 2);
+        assert_eq!(writer.data_type(), PortDataType::UInt16);
+        let mut guard = writer.write().unwrap();
+        guard.as_mut_slice().copy_from_slice(&[0x12, 0x34]);
+        guard.end().unwrap();
+
+        let mut reader = GdsBuffer::new(&system, "Arp.Io.AxlC", OUTPUT).unwrap();
+        assert_eq!(&*reader.read().unwrap(), &[0x12, 0x34]);
+        // Nothing reaches the bus before the transfer.
+        assert_eq!(simulation().output(OUTPUT).unwrap(), [0, 0]);
+        reader.release().unwrap();
+        writer.release().unwrap();
+    }
+
+    #[test]
+    fn axioline_transfers() {
+        let _simulation = exclusive();
+        let system = configure();
+
+        simulation().set_input(INPUT, &[1, 2]).unwrap();
+        let mut value = [0u8; 2];
+        crate::read_port_data(&system, INPUT, &mut value).unwrap();
+        assert_eq!(value, [0, 0]);
+        crate::read_from_axio_to_gds(&system, 100).unwrap();
+        crate::read_port_data(&system, INPUT, &mut value).unwrap();
+        assert_eq!(value, [1, 2]);
+
+        crate::write_port_data(&system, OUTPUT, &[3, 4]).unwrap();
+        assert_eq!(simulation().output(OUTPUT).unwrap(), [0, 0]);
+        crate::write_from_gds_to_axio(&system, 100).unwrap();
+        assert_eq!(simulation().output(OUTPUT).unwrap(), [3, 4]);
+
+        // Inputs are only read from the bus, and outputs only written to it.
+        assert!(simulation().set_input(OUTPUT, &[0, 0]).is_err());
+        assert!(simulation().output(INPUT).is_err());
+        assert!(simulation().set_input(INPUT, &[0]).is_err());
+    }
+
+    #[test]
+    fn fail_next_fails_one_call() {
+        let _simulation = exclusive();
+        let system = configure();
+
+        simulation().fail_next("ArpPlcAxio_ReadFromAxioToGds", "Bus error");
+        match crate::read_from_axio_to_gds(&system, 100) {
+            Err(PlcnextError::System(SystemError { function, message })) => {
+                assert_eq!(function, "ArpPlcAxio_ReadFromAxioToGds");
+                assert_eq!(message, "Bus error");
+            },
+            other => panic!("Expected a system error, got {:?}", other)
+        }
+        crate::read_from_axio_to_gds(&system, 100).unwrap();
+
+        simulation().fail_next("ArpPlcIo_GetBufferPtrByPortName", "No such port");
+        match GdsBuffer::new(&system, "Arp.Io.AxlC", OUTPUT) {
+            Err(PlcnextError::System(e)) => assert_eq!(e.to_string(), "ArpPlcIo_GetBufferPtrByPortName failed: No such port"),
+            other => panic!("Expected a system error, got {:?}", other.map(|buffer| buffer.info()))
+        }
+    }
+
+    #[test]
+    fn operations_drive_the_event_bus_and_the_state() {
+        let _simulation = exclusive();
+        configure();
+        let illegal = plc_state().illegal_transition_count();
+
+        let (sender, received) = mpsc::channel();
+        let id = events::subscribe(move |operation| {
+            let _ = sender.send((operation, plc_state().state()));
+        });
+        let sequence = [
+            (PlcOperation::Load, PlcState::Loaded),
+            (PlcOperation::Setup, PlcState::Configured),
+            (PlcOperation::StartCold, PlcState::Running),
+            (PlcOperation::Stop, PlcState::Stopped),
+            (PlcOperation::Unload, PlcState::Unloaded)
+        ];
+        for &(operation, state) in &sequence {
+            simulation().trigger(operation);
+            // The operation has been delivered, with the new state, by the time trigger() returns.
+            assert_eq!(received.try_recv().unwrap(), (operation, state));
+            assert_eq!(plc_state().state(), state);
+        }
+        assert!(events::unsubscribe(id));
+        assert_eq!(plc_state().illegal_transition_count(), illegal);
+    }
+}
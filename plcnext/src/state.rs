@@ -0,0 +1,344 @@
+use crate::PlcOperation;
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// The number of illegal transitions and unknown operations that a tracker keeps.
+const HISTORY_LEN: usize = 64;
+
+/// The lifecycle state of the PLC, as tracked by PlcStateTracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlcState {
+    /// No PLC program is loaded. This is the initial state.
+    Unloaded,
+    /// The PLC program has been loaded, but not set up.
+    Loaded,
+    /// The PLC program has been set up, or reset, and can be started.
+    Configured,
+    /// The PLC program is running.
+    Running,
+    /// The PLC program has been stopped, and can be started again.
+    Stopped
+}
+
+impl PlcState {
+    // The state that the PLC is in after an operation, whatever state it was in before.
+    // Returns None for operations that don't change the state.
+    fn after(operation: PlcOperation) -> Option<PlcState> {
+        match operation {
+            PlcOperation::Load => Some(PlcState::Loaded),
+            PlcOperation::Setup => Some(PlcState::Configured),
+            PlcOperation::StartCold | PlcOperation::StartWarm | PlcOperation::StartHot => Some(PlcState::Running),
+            PlcOperation::Stop => Some(PlcState::Stopped),
+            PlcOperation::Reset => Some(PlcState::Configured),
+            PlcOperation::Unload => Some(PlcState::Unloaded),
+            PlcOperation::None | PlcOperation::Unknown => None
+        }
+    }
+
+    // Returns true if the operation is expected in this state.
+    fn allows(self, operation: PlcOperation) -> bool {
+        match operation {
+            PlcOperation::Load => self == PlcState::Unloaded,
+            PlcOperation::Setup => self == PlcState::Loaded,
+            PlcOperation::StartCold | PlcOperation::StartWarm | PlcOperation::StartHot =>
+                matches!(self, PlcState::Configured | PlcState::Stopped),
+            PlcOperation::Stop => self == PlcState::Running,
+            PlcOperation::Reset => matches!(self, PlcState::Configured | PlcState::Stopped),
+            PlcOperation::Unload => matches!(self, PlcState::Loaded | PlcState::Configured | PlcState::Stopped),
+            PlcOperation::None | PlcOperation::Unknown => true
+        }
+    }
+}
+
+/// An operation that was received in a state in which it isn't expected,
+/// e.g. Setup while the PLC is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IllegalTransition {
+    /// The state that the PLC was in.
+    pub from: PlcState,
+    /// The operation that was received.
+    pub operation: PlcOperation,
+    /// The state that the PLC is in after the operation.
+    pub to: PlcState,
+    /// When the operation was received.
+    pub timestamp: Instant
+}
+
+/// An operation code from the ANSI-C library that isn't known to this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownOperation {
+    /// The raw operation code.
+    pub code: u32,
+    /// The state that the PLC was in, which is not changed by the operation.
+    pub state: PlcState,
+    /// When the operation was received.
+    pub timestamp: Instant
+}
+
+struct Tracked {
+    state: PlcState,
+    illegal_transitions: History<IllegalTransition>,
+    unknown_operations: History<UnknownOperation>
+}
+
+// The last HISTORY_LEN entries, oldest first, and the number of entries so far,
+// so that a PLC that keeps misbehaving doesn't make the tracker grow without bound.
+struct History<T> {
+    entries: VecDeque<T>,
+    count: u64
+}
+
+impl<T: Clone> History<T> {
+    fn new() -> History<T> {
+        History { entries: VecDeque::with_capacity(HISTORY_LEN), count: 0 }
+    }
+
+    fn push(&mut self, entry: T) {
+        if self.entries.len() == HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.count += 1;
+    }
+
+    fn to_vec(&self) -> Vec<T> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+/// Tracks the lifecycle state of the PLC from the PLC operations.
+///
+/// The tracker returned by plc_state() is updated automatically, before the
+/// operation is passed to any subscriber. Other trackers can be created and fed
+/// with operations by hand, e.g. to follow operations that are replayed from a log.
+///
+/// An operation that isn't expected in the current state is recorded as an
+/// illegal transition, and the tracker still moves to the state that the operation
+/// leads to, because the PLC has carried out the operation anyway.
+/// The tracker keeps the last 64 illegal transitions and unknown operations,
+/// and counts all of them.
+pub struct PlcStateTracker {
+    tracked: Mutex<Tracked>,
+    changed: Condvar
+}
+
+impl PlcStateTracker {
+    /// Creates a tracker in the Unloaded state.
+    pub fn new() -> PlcStateTracker {
+        PlcStateTracker {
+            tracked: Mutex::new(Tracked {
+                state: PlcState::Unloaded,
+                illegal_transitions: History::new(),
+                unknown_operations: History::new()
+            }),
+            changed: Condvar::new()
+        }
+    }
+
+    /// Updates the state from an operation.
+    /// Returns the transition if the operation isn't expected in the current state.
+    /// None and Unknown don't change the state. The code of an unknown
+    /// operation should be passed to record_unknown() instead.
+    pub fn apply(&self, operation: PlcOperation) -> Option<IllegalTransition> {
+        let mut tracked = self.lock();
+        let from = tracked.state;
+        let to = PlcState::after(operation)?;
+
+        let illegal = if from.allows(operation) {
+            None
+        } else {
+            let transition = IllegalTransition { from, operation, to, timestamp: Instant::now() };
+            tracked.illegal_transitions.push(transition);
+            Some(transition)
+        };
+
+        tracked.state = to;
+        self.changed.notify_all();
+        illegal
+    }
+
+    /// Records an operation code from the ANSI-C library that isn't known to this crate.
+    /// The state is not changed.
+    pub fn record_unknown(&self, code: u32) {
+        let mut tracked = self.lock();
+        let state = tracked.state;
+        tracked.unknown_operations.push(UnknownOperation { code, state, timestamp: Instant::now() });
+    }
+
+    /// The current state.
+    pub fn state(&self) -> PlcState {
+        self.lock().state
+    }
+
+    /// The last illegal transitions, oldest first.
+    pub fn illegal_transitions(&self) -> Vec<IllegalTransition> {
+        self.lock().illegal_transitions.to_vec()
+    }
+
+    /// The number of illegal transitions so far, including those that are no longer kept.
+    pub fn illegal_transition_count(&self) -> u64 {
+        self.lock().illegal_transitions.count
+    }
+
+    /// The last unknown operation codes received, oldest first.
+    pub fn unknown_operations(&self) -> Vec<UnknownOperation> {
+        self.lock().unknown_operations.to_vec()
+    }
+
+    /// The number of unknown operation codes received so far, including those that are no longer kept.
+    pub fn unknown_operation_count(&self) -> u64 {
+        self.lock().unknown_operations.count
+    }
+
+    /// Blocks until the tracker is in the given state, or the timeout expires.
+    /// Returns immediately if the tracker is already in that state.
+    /// Returns false if the timeout expired first.
+    /// * 'timeout' - The longest time to wait. If None, waits forever.
+    pub fn wait_for(&self, state: PlcState, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut tracked = self.lock();
+        while tracked.state != state {
+            tracked = match deadline {
+                None => self.changed.wait(tracked).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.changed.wait_timeout(tracked, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+                }
+            };
+        }
+        true
+    }
+
+    // The tracked state is always consistent, so a poisoned lock is simply taken over.
+    fn lock(&self) -> MutexGuard<'_, Tracked> {
+        self.tracked.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for PlcStateTracker {
+    fn default() -> PlcStateTracker {
+        PlcStateTracker::new()
+    }
+}
+
+lazy_static! {
+    static ref PLC_STATE: PlcStateTracker = PlcStateTracker::new();
+}
+
+/// Gets the tracker that follows the operations from the ANSI-C library.
+/// The tracker is only updated once our callback function is registered,
+/// so this should be called before load(), so that the Load operation isn't missed.
+pub fn plc_state() -> &'static PlcStateTracker {
+    crate::install_event_handler();
+    &PLC_STATE
+}
+
+// Updates the tracker from an operation received from the ANSI-C library.
+pub(crate) fn track(code: u32, operation: PlcOperation) {
+    if operation == PlcOperation::Unknown {
+        PLC_STATE.record_unknown(code);
+    } else {
+        PLC_STATE.apply(operation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_keeps_the_last_entries_and_counts_all() {
+        let tracker = PlcStateTracker::new();
+        for code in 0..1000 {
+            tracker.record_unknown(code);
+            tracker.apply(PlcOperation::Stop);
+        }
+        assert_eq!(tracker.unknown_operation_count(), 1000);
+        let unknown = tracker.unknown_operations();
+        assert_eq!(unknown.len(), HISTORY_LEN);
+        assert_eq!(unknown.first().map(|u| u.code), Some(1000 - HISTORY_LEN as u32));
+        assert_eq!(unknown.last().map(|u| u.code), Some(999));
+
+        // Stop is only expected while the PLC is running, so every one of them is illegal.
+        assert_eq!(tracker.illegal_transition_count(), 1000);
+        assert_eq!(tracker.illegal_transitions().len(), HISTORY_LEN);
+        assert_eq!(tracker.state(), PlcState::Stopped);
+    }
+
+    #[test]
+    fn legal_sequence() {
+        let tracker = PlcStateTracker::new();
+        let sequence = [
+            (PlcOperation::Load, PlcState::Loaded),
+            (PlcOperation::Setup, PlcState::Configured),
+            (PlcOperation::StartCold, PlcState::Running),
+            (PlcOperation::Stop, PlcState::Stopped),
+            (PlcOperation::StartWarm, PlcState::Running),
+            (PlcOperation::Stop, PlcState::Stopped),
+            (PlcOperation::Reset, PlcState::Configured),
+            (PlcOperation::Unload, PlcState::Unloaded)
+        ];
+        for (operation, state) in sequence.iter() {
+            assert_eq!(tracker.apply(*operation), None, "{:?}", operation);
+            assert_eq!(tracker.state(), *state);
+        }
+        assert_eq!(tracker.illegal_transition_count(), 0);
+        assert!(tracker.illegal_transitions().is_empty());
+    }
+
+    #[test]
+    fn illegal_transition_still_changes_the_state() {
+        let tracker = PlcStateTracker::new();
+        tracker.apply(PlcOperation::Load);
+        let transition = tracker.apply(PlcOperation::StartHot).unwrap();
+        assert_eq!((transition.from, transition.operation, transition.to),
+                   (PlcState::Loaded, PlcOperation::StartHot, PlcState::Running));
+        assert_eq!(tracker.state(), PlcState::Running);
+        assert_eq!(tracker.illegal_transitions(), vec![transition]);
+    }
+
+    #[test]
+    fn none_and_unknown_leave_the_state() {
+        let tracker = PlcStateTracker::new();
+        tracker.apply(PlcOperation::Load);
+        tracker.apply(PlcOperation::Setup);
+        assert_eq!(tracker.apply(PlcOperation::None), None);
+        assert_eq!(tracker.apply(PlcOperation::Unknown), None);
+        tracker.record_unknown(42);
+        assert_eq!(tracker.state(), PlcState::Configured);
+        assert_eq!(tracker.illegal_transition_count(), 0);
+        let unknown = tracker.unknown_operations();
+        assert_eq!((unknown.len(), unknown[0].code, unknown[0].state), (1, 42, PlcState::Configured));
+    }
+
+    #[test]
+    fn wait_for_a_state_from_another_thread() {
+        let tracker = std::sync::Arc::new(PlcStateTracker::new());
+        assert!(tracker.wait_for(PlcState::Unloaded, Some(Duration::from_millis(0))));
+
+        let applier = {
+            let tracker = tracker.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                tracker.apply(PlcOperation::Load);
+                tracker.apply(PlcOperation::Setup);
+            })
+        };
+        assert!(tracker.wait_for(PlcState::Configured, Some(Duration::from_secs(10))));
+        applier.join().unwrap();
+    }
+
+    #[test]
+    fn wait_for_times_out() {
+        let tracker = PlcStateTracker::new();
+        let start = Instant::now();
+        assert!(!tracker.wait_for(PlcState::Running, Some(Duration::from_millis(50))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(tracker.state(), PlcState::Unloaded);
+    }
+}
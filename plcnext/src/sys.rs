@@ -0,0 +1,12 @@
+// The ANSI-C library, as seen by the rest of this crate.
+// With the simulation feature, the calls go to the in-process emulation
+// in the simulation module instead of the plcnext-sys crate.
+
+#[cfg(not(feature = "simulation"))]
+pub(crate) use plcnext_sys::*;
+
+#[cfg(feature = "simulation")]
+pub(crate) use crate::simulation::ffi::*;
+
+#[cfg(not(any(feature = "simulation", feature = "plcnext-sys")))]
+compile_error!("Either the default plcnext-sys feature or the simulation feature must be enabled");
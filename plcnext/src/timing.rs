@@ -0,0 +1,316 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The number of buckets in a jitter histogram.
+/// The last bucket counts every cycle whose jitter doesn't fit in the others.
+pub const JITTER_BUCKETS: usize = 16;
+
+/// Min, max and mean of the duration of one phase of a cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseStats {
+    count: u64,
+    min: Duration,
+    max: Duration,
+    total: Duration
+}
+
+impl PhaseStats {
+    fn new() -> PhaseStats {
+        PhaseStats { count: 0, min: Duration::from_secs(0), max: Duration::from_secs(0), total: Duration::from_secs(0) }
+    }
+
+    fn record(&mut self, duration: Duration) {
+        if self.count == 0 || duration < self.min {
+            self.min = duration;
+        }
+        if duration > self.max {
+            self.max = duration;
+        }
+        self.count += 1;
+        self.total += duration;
+    }
+
+    /// The number of measurements.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The shortest duration, or zero if there are no measurements.
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// The longest duration, or zero if there are no measurements.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The mean duration, or zero if there are no measurements.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+    }
+}
+
+impl fmt::Display for PhaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "min {:?}, max {:?}, mean {:?}", self.min, self.max, self.mean())
+    }
+}
+
+/// A histogram of the time by which each cycle started after its deadline.
+/// Bucket n counts cycles with a jitter from n * bucket_width up to (n + 1) * bucket_width,
+/// and the last bucket also counts every longer jitter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitterHistogram {
+    bucket_width: Duration,
+    buckets: [u64; JITTER_BUCKETS]
+}
+
+impl JitterHistogram {
+    fn new(bucket_width: Duration) -> JitterHistogram {
+        JitterHistogram { bucket_width, buckets: [0; JITTER_BUCKETS] }
+    }
+
+    fn record(&mut self, jitter: Duration) {
+        let width = self.bucket_width.as_nanos().max(1);
+        let bucket = (jitter.as_nanos() / width).min(JITTER_BUCKETS as u128 - 1) as usize;
+        self.buckets[bucket] += 1;
+    }
+
+    /// The range of jitter covered by each bucket.
+    pub fn bucket_width(&self) -> Duration {
+        self.bucket_width
+    }
+
+    /// The number of cycles in each bucket.
+    pub fn buckets(&self) -> &[u64; JITTER_BUCKETS] {
+        &self.buckets
+    }
+}
+
+/// Timing statistics of a CyclicTask.
+/// A snapshot is returned by CycleStatsHandle::snapshot(), and can be
+/// written to a log with its Display implementation, or exported field by field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleStats {
+    period: Duration,
+    cycles: u64,
+    overruns: u64,
+    consecutive_overruns: u64,
+    read: PhaseStats,
+    logic: PhaseStats,
+    write: PhaseStats,
+    total: PhaseStats,
+    jitter: PhaseStats,
+    jitter_histogram: JitterHistogram
+}
+
+impl CycleStats {
+    pub(crate) fn new(period: Duration, jitter_bucket_width: Duration) -> CycleStats {
+        CycleStats {
+            period,
+            cycles: 0,
+            overruns: 0,
+            consecutive_overruns: 0,
+            read: PhaseStats::new(),
+            logic: PhaseStats::new(),
+            write: PhaseStats::new(),
+            total: PhaseStats::new(),
+            jitter: PhaseStats::new(),
+            jitter_histogram: JitterHistogram::new(jitter_bucket_width)
+        }
+    }
+
+    // Records the timing of one cycle. This doesn't allocate.
+    pub(crate) fn record(&mut self, times: &CycleTimes) {
+        self.cycles += 1;
+        self.read.record(times.read);
+        self.logic.record(times.logic);
+        self.write.record(times.write);
+        self.total.record(times.read + times.logic + times.write);
+        self.jitter.record(times.jitter);
+        self.jitter_histogram.record(times.jitter);
+        if times.overrun {
+            self.overruns += 1;
+            self.consecutive_overruns += 1;
+        } else {
+            self.consecutive_overruns = 0;
+        }
+    }
+
+    /// The period of the task.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// The number of cycles that have been run.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The number of cycles that finished after the start of the next period.
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    /// The number of overruns since the last cycle that finished in time.
+    pub fn consecutive_overruns(&self) -> u64 {
+        self.consecutive_overruns
+    }
+
+    /// The time taken to transfer inputs from the bus and read the input image.
+    pub fn read(&self) -> &PhaseStats {
+        &self.read
+    }
+
+    /// The time taken by the cycle logic.
+    pub fn logic(&self) -> &PhaseStats {
+        &self.logic
+    }
+
+    /// The time taken to write the output image and transfer outputs to the bus.
+    pub fn write(&self) -> &PhaseStats {
+        &self.write
+    }
+
+    /// The time taken by the whole cycle.
+    pub fn total(&self) -> &PhaseStats {
+        &self.total
+    }
+
+    /// The time by which each cycle started after its deadline.
+    pub fn jitter(&self) -> &PhaseStats {
+        &self.jitter
+    }
+
+    /// The distribution of the jitter.
+    pub fn jitter_histogram(&self) -> &JitterHistogram {
+        &self.jitter_histogram
+    }
+}
+
+impl fmt::Display for CycleStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "period {:?}, {} cycles, {} overruns; read: {}; logic: {}; write: {}; total: {}; jitter: {}, histogram ({:?} buckets) {:?}",
+            self.period, self.cycles, self.overruns, self.read, self.logic, self.write, self.total,
+            self.jitter, self.jitter_histogram.bucket_width, self.jitter_histogram.buckets)
+    }
+}
+
+// The timing of one cycle.
+pub(crate) struct CycleTimes {
+    pub(crate) jitter: Duration,
+    pub(crate) read: Duration,
+    pub(crate) logic: Duration,
+    pub(crate) write: Duration,
+    pub(crate) overrun: bool
+}
+
+/// Gives access to the timing statistics of a CyclicTask from any thread.
+#[derive(Clone)]
+pub struct CycleStatsHandle {
+    stats: Arc<Mutex<CycleStats>>
+}
+
+impl CycleStatsHandle {
+    pub(crate) fn new(stats: CycleStats) -> CycleStatsHandle {
+        CycleStatsHandle { stats: Arc::new(Mutex::new(stats)) }
+    }
+
+    /// A copy of the statistics so far.
+    pub fn snapshot(&self) -> CycleStats {
+        *self.lock()
+    }
+
+    /// Clears the statistics, e.g. after the application has warmed up.
+    pub fn reset(&self) {
+        let mut stats = self.lock();
+        *stats = CycleStats::new(stats.period, stats.jitter_histogram.bucket_width);
+    }
+
+    pub(crate) fn record(&self, times: &CycleTimes) {
+        self.lock().record(times);
+    }
+
+    // The statistics are always consistent, so a poisoned lock is simply taken over.
+    fn lock(&self) -> std::sync::MutexGuard<'_, CycleStats> {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn micros(us: u64) -> Duration {
+        Duration::from_micros(us)
+    }
+
+    fn cycle(read: u64, logic: u64, write: u64, jitter: u64, overrun: bool) -> CycleTimes {
+        CycleTimes { jitter: micros(jitter), read: micros(read), logic: micros(logic), write: micros(write), overrun }
+    }
+
+    #[test]
+    fn phase_stats() {
+        let mut stats = PhaseStats::new();
+        assert_eq!((stats.count(), stats.min(), stats.max(), stats.mean()), (0, micros(0), micros(0), micros(0)));
+
+        for us in [30, 10, 50, 20].iter() {
+            stats.record(micros(*us));
+        }
+        assert_eq!((stats.count(), stats.min(), stats.max(), stats.mean()), (4, micros(10), micros(50), micros(27) + Duration::from_nanos(500)));
+        assert_eq!(stats.to_string(), "min 10µs, max 50µs, mean 27.5µs");
+    }
+
+    #[test]
+    fn jitter_histogram_bucket_edges() {
+        let mut histogram = JitterHistogram::new(micros(10));
+        for jitter in [micros(0), Duration::from_nanos(9_999), micros(10), micros(19), micros(20),
+                       micros(150), Duration::from_nanos(159_999), micros(160), Duration::from_secs(1)].iter() {
+            histogram.record(*jitter);
+        }
+        let mut expected = [0; JITTER_BUCKETS];
+        expected[0] = 2;
+        expected[1] = 2;
+        expected[2] = 1;
+        expected[15] = 4;
+        assert_eq!(histogram.buckets(), &expected);
+        assert_eq!(histogram.bucket_width(), micros(10));
+    }
+
+    #[test]
+    fn zero_bucket_width() {
+        let mut histogram = JitterHistogram::new(micros(0));
+        histogram.record(Duration::from_nanos(0));
+        histogram.record(Duration::from_nanos(3));
+        histogram.record(micros(1));
+        assert_eq!(&histogram.buckets()[..4], &[1, 0, 0, 1]);
+        assert_eq!(histogram.buckets()[15], 1);
+    }
+
+    #[test]
+    fn cycle_stats_and_overruns() {
+        let handle = CycleStatsHandle::new(CycleStats::new(micros(1000), micros(10)));
+        handle.record(&cycle(100, 200, 100, 5, false));
+        handle.record(&cycle(300, 900, 100, 25, true));
+        handle.record(&cycle(100, 1000, 200, 15, true));
+
+        let stats = handle.snapshot();
+        assert_eq!((stats.period(), stats.cycles(), stats.overruns(), stats.consecutive_overruns()), (micros(1000), 3, 2, 2));
+        assert_eq!((stats.read().min(), stats.read().max()), (micros(100), micros(300)));
+        assert_eq!((stats.total().min(), stats.total().max(), stats.total().mean()), (micros(400), micros(1300), micros(1000)));
+        assert_eq!(stats.jitter().mean(), micros(15));
+        assert_eq!(&stats.jitter_histogram().buckets()[..3], &[1, 1, 1]);
+
+        handle.record(&cycle(100, 200, 100, 5, false));
+        assert_eq!((handle.snapshot().overruns(), handle.snapshot().consecutive_overruns()), (2, 0));
+
+        handle.reset();
+        let stats = handle.snapshot();
+        assert_eq!(stats, CycleStats::new(micros(1000), micros(10)));
+    }
+}
@@ -0,0 +1,332 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::registry::PortHandle;
+
+use std::time::Duration;
+
+/// A Rust type that can be read from, and written to, a GDS port.
+///
+/// The IEC 61131-3 elementary types map to Rust types as follows:
+///
+/// | IEC 61131-3    | Rust        |
+/// |----------------|-------------|
+/// | BOOL           | bool        |
+/// | BYTE, USINT    | u8          |
+/// | WORD, UINT     | u16         |
+/// | DWORD, UDINT   | u32         |
+/// | LWORD, ULINT   | u64         |
+/// | SINT           | i8          |
+/// | INT            | i16         |
+/// | DINT           | i32         |
+/// | LINT           | i64         |
+/// | REAL           | f32         |
+/// | LREAL          | f64         |
+/// | TIME           | IecTime     |
+/// | STRING         | String      |
+///
+/// Numeric values are stored in the GDS in the byte order of the controller.
+/// Ports that carry raw fieldbus data in a fixed byte order can be read and
+/// written with the BigEndian and LittleEndian wrappers.
+pub trait PortValue: Sized {
+    /// The size of the value in bytes, or None if the value
+    /// takes its size from the port, e.g. STRING.
+    const SIZE: Option<usize>;
+
+    /// Decodes the value from port data.
+    /// If SIZE is not None, 'bytes' is exactly SIZE bytes long.
+    fn from_port_bytes(bytes: &[u8]) -> Self;
+
+    /// Encodes the value into port data.
+    /// If SIZE is not None, 'bytes' is exactly SIZE bytes long.
+    fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()>;
+}
+
+/// Reads a typed value from a port.
+pub fn read_port<T: PortValue>(port: &PortHandle) -> Result<T> {
+    check_size::<T>(port)?;
+
+    // Decode straight from the locked buffer page, without copying.
+    port.with_data(T::from_port_bytes)
+}
+
+/// Writes a typed value to a port.
+pub fn write_port<T: PortValue>(port: &PortHandle, value: &T) -> Result<()> {
+    check_size::<T>(port)?;
+
+    // Encode straight into the locked buffer page, without copying.
+    port.try_with_data_mut(|data| value.to_port_bytes(data))
+}
+
+pub(crate) fn check_size<T: PortValue>(port: &PortHandle) -> Result<()> {
+    match T::SIZE {
+        Some(size) if size != port.size() => Err(PlcnextError::length_mismatch(port.port_name(), port.size(), size)),
+        _ => Ok(())
+    }
+}
+
+/// A numeric port value stored in big-endian byte order,
+/// regardless of the byte order of the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BigEndian<T>(pub T);
+
+/// A numeric port value stored in little-endian byte order,
+/// regardless of the byte order of the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LittleEndian<T>(pub T);
+
+// Implements PortValue for a numeric type in native, big-endian and little-endian byte order.
+macro_rules! impl_numeric_port_value {
+    ($($t:ty),*) => {$(
+        impl PortValue for $t {
+            const SIZE: Option<usize> = Some(std::mem::size_of::<$t>());
+
+            fn from_port_bytes(bytes: &[u8]) -> Self {
+                let mut raw = [0u8; std::mem::size_of::<$t>()];
+                raw.copy_from_slice(bytes);
+                <$t>::from_ne_bytes(raw)
+            }
+
+            fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()> {
+                bytes.copy_from_slice(&self.to_ne_bytes());
+                Ok(())
+            }
+        }
+
+        impl PortValue for BigEndian<$t> {
+            const SIZE: Option<usize> = Some(std::mem::size_of::<$t>());
+
+            fn from_port_bytes(bytes: &[u8]) -> Self {
+                let mut raw = [0u8; std::mem::size_of::<$t>()];
+                raw.copy_from_slice(bytes);
+                BigEndian(<$t>::from_be_bytes(raw))
+            }
+
+            fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()> {
+                bytes.copy_from_slice(&self.0.to_be_bytes());
+                Ok(())
+            }
+        }
+
+        impl PortValue for LittleEndian<$t> {
+            const SIZE: Option<usize> = Some(std::mem::size_of::<$t>());
+
+            fn from_port_bytes(bytes: &[u8]) -> Self {
+                let mut raw = [0u8; std::mem::size_of::<$t>()];
+                raw.copy_from_slice(bytes);
+                LittleEndian(<$t>::from_le_bytes(raw))
+            }
+
+            fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()> {
+                bytes.copy_from_slice(&self.0.to_le_bytes());
+                Ok(())
+            }
+        }
+    )*}
+}
+
+impl_numeric_port_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// BOOL is stored in a single byte. Any non-zero value is read as true.
+impl PortValue for bool {
+    const SIZE: Option<usize> = Some(1);
+
+    fn from_port_bytes(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+
+    fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()> {
+        bytes[0] = *self as u8;
+        Ok(())
+    }
+}
+
+/// An IEC 61131-3 TIME value, in milliseconds.
+/// Unlike Duration, a TIME value can be negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct IecTime(pub i32);
+
+impl IecTime {
+    /// Converts the TIME value to a Duration.
+    /// Returns None if the value is negative.
+    pub fn as_duration(self) -> Option<Duration> {
+        if self.0 < 0 {
+            None
+        } else {
+            Some(Duration::from_millis(self.0 as u64))
+        }
+    }
+
+    /// Converts a Duration to a TIME value.
+    /// Returns None if the duration is too long to be represented.
+    pub fn from_duration(duration: Duration) -> Option<IecTime> {
+        let millis = duration.as_millis();
+        if millis > i32::MAX as u128 {
+            None
+        } else {
+            Some(IecTime(millis as i32))
+        }
+    }
+}
+
+impl PortValue for IecTime {
+    const SIZE: Option<usize> = Some(4);
+
+    fn from_port_bytes(bytes: &[u8]) -> Self {
+        IecTime(i32::from_port_bytes(bytes))
+    }
+
+    fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()> {
+        self.0.to_port_bytes(bytes)
+    }
+}
+
+/// STRING is stored as a fixed-length, null-terminated array of 8-bit characters,
+/// whose length is set by the port. Characters are decoded as Latin-1.
+/// Writing a string that doesn't fit in the port, including the terminating null,
+/// or that contains characters outside Latin-1, returns an error.
+impl PortValue for String {
+    const SIZE: Option<usize> = None;
+
+    fn from_port_bytes(bytes: &[u8]) -> Self {
+        bytes.iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| b as char)
+            .collect()
+    }
+
+    fn to_port_bytes(&self, bytes: &mut [u8]) -> Result<()> {
+        let length = self.chars().count();
+        if length >= bytes.len() {
+            return Err(PlcnextError::invalid_value(&format!(
+                "String of {} characters doesn't fit in a port of {} bytes", length, bytes.len())));
+        }
+        // Check every character before writing any, so that the port isn't left half written.
+        if let Some(c) = self.chars().find(|&c| c as u32 > 0xFF) {
+            return Err(PlcnextError::invalid_value(&format!("Character '{}' can't be stored in a STRING port", c)));
+        }
+        for (byte, c) in bytes.iter_mut().zip(self.chars()) {
+            *byte = c as u8;
+        }
+        // Fill the rest of the port with nulls, so that no old characters are left behind.
+        for byte in bytes.iter_mut().skip(length) {
+            *byte = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes a value into a port of the given size.
+    fn encode<T: PortValue>(value: &T, size: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0xff; size];
+        value.to_port_bytes(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn byte_order() {
+        assert_eq!(encode(&BigEndian(0x1234u16), 2).unwrap(), [0x12, 0x34]);
+        assert_eq!(encode(&LittleEndian(0x1234u16), 2).unwrap(), [0x34, 0x12]);
+        assert_eq!(encode(&0x1234u16, 2).unwrap(), 0x1234u16.to_ne_bytes());
+        assert_eq!(encode(&BigEndian(-2i32), 4).unwrap(), [0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(encode(&LittleEndian(1.0f32), 4).unwrap(), [0x00, 0x00, 0x80, 0x3f]);
+
+        assert_eq!(BigEndian::<u32>::from_port_bytes(&[1, 2, 3, 4]), BigEndian(0x0102_0304));
+        assert_eq!(LittleEndian::<u32>::from_port_bytes(&[1, 2, 3, 4]), LittleEndian(0x0403_0201));
+        assert_eq!(BigEndian::<f64>::from_port_bytes(&[0x3f, 0xf0, 0, 0, 0, 0, 0, 0]), BigEndian(1.0));
+        assert_eq!(i64::from_port_bytes(&(-5i64).to_ne_bytes()), -5);
+    }
+
+    #[test]
+    fn bool_is_any_non_zero_byte() {
+        assert!(!bool::from_port_bytes(&[0]));
+        assert!(bool::from_port_bytes(&[1]));
+        assert!(bool::from_port_bytes(&[0x80]));
+        assert!(bool::from_port_bytes(&[0xff]));
+        assert_eq!(encode(&true, 1).unwrap(), [1]);
+        assert_eq!(encode(&false, 1).unwrap(), [0]);
+    }
+
+    #[test]
+    fn string_is_latin1() {
+        assert_eq!(String::from_port_bytes(b"abc\0def"), "abc");
+        assert_eq!(String::from_port_bytes(&[0x47, 0x72, 0xfc, 0xdf, 0x65]), "Grüße");
+        assert_eq!(String::from_port_bytes(&[0, 0x41]), "");
+
+        assert_eq!(encode(&"Grüße".to_string(), 6).unwrap(), [0x47, 0x72, 0xfc, 0xdf, 0x65, 0]);
+        // The rest of the port is filled with nulls.
+        assert_eq!(encode(&"ab".to_string(), 5).unwrap(), [b'a', b'b', 0, 0, 0]);
+        assert_eq!(encode(&String::new(), 2).unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn string_errors() {
+        // The terminating null must fit as well, and characters are counted, not bytes.
+        assert!(encode(&"abcde".to_string(), 5).unwrap_err().to_string().contains("String of 5 characters doesn't fit in a port of 5 bytes"));
+        assert!(encode(&"ü".to_string(), 2).is_ok());
+        assert!(encode(&"€".to_string(), 4).unwrap_err().to_string().contains("Character '€' can't be stored"));
+        assert!(encode(&String::new(), 0).is_err());
+
+        // Nothing is written if a character can't be stored.
+        let mut bytes = [0xffu8; 4];
+        assert!("a€".to_string().to_port_bytes(&mut bytes).is_err());
+        assert_eq!(bytes, [0xff; 4]);
+    }
+
+    #[test]
+    fn iec_time() {
+        assert_eq!(IecTime(1500).as_duration(), Some(Duration::from_millis(1500)));
+        assert_eq!(IecTime(0).as_duration(), Some(Duration::from_secs(0)));
+        assert_eq!(IecTime(-1).as_duration(), None);
+        assert_eq!(IecTime(i32::MIN).as_duration(), None);
+
+        assert_eq!(IecTime::from_duration(Duration::from_micros(2999)), Some(IecTime(2)));
+        assert_eq!(IecTime::from_duration(Duration::from_millis(i32::MAX as u64)), Some(IecTime(i32::MAX)));
+        assert_eq!(IecTime::from_duration(Duration::from_millis(i32::MAX as u64 + 1)), None);
+        assert_eq!(IecTime::from_duration(Duration::from_secs(u64::MAX)), None);
+
+        assert_eq!(encode(&IecTime(-2), 4).unwrap(), (-2i32).to_ne_bytes());
+        assert_eq!(IecTime::from_port_bytes(&(-2i32).to_ne_bytes()), IecTime(-2));
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn size_mismatch() {
+        use crate::error::ParameterError;
+        use crate::layout::PortDataType;
+        use crate::registry::port_registry;
+        use crate::simulation::{self, simulation, PortDirection, SimulatedPort};
+        use crate::{PlcOperation, SystemHandle};
+
+        let _simulation = simulation::exclusive();
+        simulation().configure(&[
+            SimulatedPort::new("Arp.Io.AxlC/0.DO16", PortDirection::Output, PortDataType::UInt16).unwrap(),
+            SimulatedPort::new("Arp.Io.AxlC/1.Name", PortDirection::Output, PortDataType::StaticString).unwrap().with_size(4)
+        ]).unwrap();
+        simulation().trigger(PlcOperation::Unload);
+        let registry = port_registry(&SystemHandle::new());
+        let port = registry.get("Arp.Io.AxlC", "Arp.Io.AxlC/0.DO16").unwrap();
+
+        write_port(&port, &0x1234u16).unwrap();
+        assert_eq!(read_port::<u16>(&port).unwrap(), 0x1234);
+        assert_eq!(read_port::<BigEndian<u16>>(&port).unwrap(), BigEndian(u16::from_be_bytes(0x1234u16.to_ne_bytes())));
+        match read_port::<u32>(&port) {
+            Err(PlcnextError::Parameter(ParameterError::LengthMismatch { port_name, expected, actual })) => {
+                assert_eq!((port_name.as_str(), expected, actual), ("Arp.Io.AxlC/0.DO16", 2, 4));
+            },
+            other => panic!("Expected a length mismatch, got {:?}", other)
+        }
+        assert!(write_port(&port, &true).is_err());
+        assert_eq!(read_port::<u16>(&port).unwrap(), 0x1234);
+
+        // A string takes its size from the port.
+        let name = registry.get("Arp.Io.AxlC", "Arp.Io.AxlC/1.Name").unwrap();
+        write_port(&name, &"abc".to_string()).unwrap();
+        assert_eq!(read_port::<String>(&name).unwrap(), "abc");
+        assert!(write_port(&name, &"abcd".to_string()).is_err());
+        assert_eq!(read_port::<String>(&name).unwrap(), "abc");
+    }
+}
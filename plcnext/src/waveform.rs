@@ -0,0 +1,248 @@
+use crate::error::Result;
+use crate::error::PlcnextError;
+use crate::layout::PortDataType;
+
+use std::path::Path;
+use std::time::Duration;
+
+/// A scripted value of a simulated input port over time.
+///
+/// The time is the simulated bus time, which starts at zero and advances by one
+/// bus cycle with every read_from_axio_to_gds(), so a waveform produces the same
+/// values in every run, however long the application takes for each cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Waveform {
+    /// 'before' until 'at', and 'after' from then on.
+    Step { before: f64, after: f64, at: Duration },
+    /// 'from' until 'start', then a straight line to 'to' at 'start' + 'duration',
+    /// and 'to' from then on.
+    Ramp { from: f64, to: f64, start: Duration, duration: Duration },
+    /// 'high' for the first 'duty' part of each period, from 0 to 1, and 'low' for the rest.
+    Square { low: f64, high: f64, period: Duration, duty: f64 },
+    /// Each value holds from its time until the time of the next one.
+    /// The first value also holds before its time. The samples must be in order of time.
+    Samples(Vec<(Duration, f64)>)
+}
+
+impl Waveform {
+    /// Reads samples from CSV text, with the time in milliseconds in the first column
+    /// and the value in the second. A first line that isn't numeric is taken as a header.
+    /// Empty lines are skipped.
+    pub fn from_csv(text: &str) -> Result<Waveform> {
+        let mut samples: Vec<(Duration, f64)> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let sample = match fields.as_slice() {
+                [time, value] => time.parse::<u64>().ok().zip(value.parse::<f64>().ok()),
+                _ => None
+            };
+            let (time, value) = match sample {
+                Some(sample) => sample,
+                None if index == 0 => continue,
+                None => return Err(PlcnextError::invalid_value(&format!(
+                    "CSV line {} is not '<time in ms>,<value>': {}", index + 1, line)))
+            };
+            let time = Duration::from_millis(time);
+            if matches!(samples.last(), Some(&(last, _)) if time < last) {
+                return Err(PlcnextError::invalid_value(&format!("CSV line {} is earlier than the line before", index + 1)));
+            }
+            samples.push((time, value));
+        }
+        if samples.is_empty() {
+            return Err(PlcnextError::invalid_value("The CSV text has no samples"));
+        }
+        Ok(Waveform::Samples(samples))
+    }
+
+    /// Reads samples from a CSV file, in the format of from_csv().
+    pub fn load_csv<P: AsRef<Path>>(path: P) -> Result<Waveform> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| PlcnextError::invalid_value(
+            &format!("Failed to read {}: {}", path.display(), e)))?;
+        Waveform::from_csv(&text)
+    }
+
+    /// The value at the given time.
+    pub fn value_at(&self, time: Duration) -> f64 {
+        match self {
+            Waveform::Step { before, after, at } => if time < *at { *before } else { *after },
+            Waveform::Ramp { from, to, start, duration } => {
+                if time <= *start {
+                    *from
+                } else if time >= *start + *duration {
+                    *to
+                } else {
+                    let part = (time - *start).as_secs_f64() / duration.as_secs_f64();
+                    from + (to - from) * part
+                }
+            },
+            Waveform::Square { low, high, period, duty } => {
+                if period.as_nanos() == 0 {
+                    return *high;
+                }
+                let part = (time.as_nanos() % period.as_nanos()) as f64 / period.as_nanos() as f64;
+                if part < *duty { *high } else { *low }
+            },
+            Waveform::Samples(samples) => samples.iter()
+                .take_while(|(at, _)| *at <= time)
+                .last()
+                .or_else(|| samples.first())
+                .map_or(0.0, |&(_, value)| value)
+        }
+    }
+}
+
+// Encodes a number into port data of a numeric data type, in the byte order of the controller.
+// Integers are rounded, and saturate at the limits of their type.
+pub(crate) fn encode(port_name: &str, data_type: PortDataType, value: f64, bytes: &mut [u8]) -> Result<()> {
+    let encoded = match data_type {
+        PortDataType::Bit | PortDataType::Boolean => vec![(value != 0.0) as u8],
+        PortDataType::UInt8 | PortDataType::Char8 => vec![value.round() as u8],
+        PortDataType::Int8 => (value.round() as i8).to_ne_bytes().to_vec(),
+        PortDataType::UInt16 | PortDataType::Char16 => (value.round() as u16).to_ne_bytes().to_vec(),
+        PortDataType::Int16 => (value.round() as i16).to_ne_bytes().to_vec(),
+        PortDataType::UInt32 => (value.round() as u32).to_ne_bytes().to_vec(),
+        PortDataType::Int32 | PortDataType::IecTime => (value.round() as i32).to_ne_bytes().to_vec(),
+        PortDataType::UInt64 => (value.round() as u64).to_ne_bytes().to_vec(),
+        PortDataType::Int64 | PortDataType::IecTime64 => (value.round() as i64).to_ne_bytes().to_vec(),
+        PortDataType::Float32 => (value as f32).to_ne_bytes().to_vec(),
+        PortDataType::Float64 => value.to_ne_bytes().to_vec(),
+        PortDataType::StaticString | PortDataType::IecString | PortDataType::Other(_) =>
+            return Err(PlcnextError::invalid_value(&format!(
+                "Port '{}' has data type {:?}, which can't hold a number", port_name, data_type)))
+    };
+    if encoded.len() != bytes.len() {
+        return Err(PlcnextError::length_mismatch(port_name, bytes.len(), encoded.len()));
+    }
+    bytes.copy_from_slice(&encoded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(milliseconds: u64) -> Duration {
+        Duration::from_millis(milliseconds)
+    }
+
+    fn error(result: Result<Waveform>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn step() {
+        let step = Waveform::Step { before: 1.0, after: 2.0, at: ms(50) };
+        assert_eq!(step.value_at(ms(0)), 1.0);
+        assert_eq!(step.value_at(ms(50) - Duration::from_nanos(1)), 1.0);
+        assert_eq!(step.value_at(ms(50)), 2.0);
+        assert_eq!(step.value_at(ms(5000)), 2.0);
+    }
+
+    #[test]
+    fn ramp() {
+        let ramp = Waveform::Ramp { from: 10.0, to: -10.0, start: ms(100), duration: ms(200) };
+        assert_eq!(ramp.value_at(ms(0)), 10.0);
+        assert_eq!(ramp.value_at(ms(100)), 10.0);
+        assert_eq!(ramp.value_at(ms(150)), 5.0);
+        assert_eq!(ramp.value_at(ms(200)), 0.0);
+        assert_eq!(ramp.value_at(ms(300)), -10.0);
+        assert_eq!(ramp.value_at(ms(1000)), -10.0);
+
+        // A ramp without a duration is a step at its start.
+        let instant = Waveform::Ramp { from: 0.0, to: 1.0, start: ms(10), duration: ms(0) };
+        assert_eq!(instant.value_at(ms(10)), 0.0);
+        assert_eq!(instant.value_at(ms(11)), 1.0);
+    }
+
+    #[test]
+    fn square() {
+        let square = Waveform::Square { low: 0.0, high: 1.0, period: ms(100), duty: 0.25 };
+        assert_eq!(square.value_at(ms(0)), 1.0);
+        assert_eq!(square.value_at(ms(25) - Duration::from_nanos(1)), 1.0);
+        assert_eq!(square.value_at(ms(25)), 0.0);
+        assert_eq!(square.value_at(ms(99)), 0.0);
+        // Every period starts high again.
+        assert_eq!(square.value_at(ms(100)), 1.0);
+        assert_eq!(square.value_at(ms(1024)), 1.0);
+        assert_eq!(square.value_at(ms(1025)), 0.0);
+
+        let without_period = Waveform::Square { low: 0.0, high: 1.0, period: ms(0), duty: 0.5 };
+        assert_eq!(without_period.value_at(ms(7)), 1.0);
+    }
+
+    #[test]
+    fn samples() {
+        let samples = Waveform::Samples(vec![(ms(10), 1.0), (ms(20), 2.0), (ms(20), 3.0), (ms(40), 4.0)]);
+        // The first value holds before its time, and the last one holds after the end.
+        assert_eq!(samples.value_at(ms(0)), 1.0);
+        assert_eq!(samples.value_at(ms(10)), 1.0);
+        assert_eq!(samples.value_at(ms(19)), 1.0);
+        // Of two samples at the same time, the later one wins.
+        assert_eq!(samples.value_at(ms(20)), 3.0);
+        assert_eq!(samples.value_at(ms(39)), 3.0);
+        assert_eq!(samples.value_at(ms(40)), 4.0);
+        assert_eq!(samples.value_at(ms(100_000)), 4.0);
+        assert_eq!(Waveform::Samples(Vec::new()).value_at(ms(0)), 0.0);
+    }
+
+    #[test]
+    fn from_csv() {
+        let csv = Waveform::from_csv("time_ms, value\n0, 1.5\n\n  20,-3\n20,4e1\n").unwrap();
+        assert_eq!(csv, Waveform::Samples(vec![(ms(0), 1.5), (ms(20), -3.0), (ms(20), 40.0)]));
+        // Without a header, the first line is a sample.
+        assert_eq!(Waveform::from_csv("5,1").unwrap(), Waveform::Samples(vec![(ms(5), 1.0)]));
+    }
+
+    #[test]
+    fn from_csv_errors() {
+        assert!(error(Waveform::from_csv("time,value\n0,1\n10\n")).contains("CSV line 3 is not '<time in ms>,<value>': 10"));
+        assert!(error(Waveform::from_csv("0,1\ntime,value\n")).contains("CSV line 2 is not"));
+        assert!(error(Waveform::from_csv("0,1\n-5,1\n")).contains("CSV line 2 is not"));
+        assert!(error(Waveform::from_csv("0,1\n1,2,3\n")).contains("CSV line 2 is not"));
+        assert!(error(Waveform::from_csv("10,1\n\n5,2\n")).contains("CSV line 3 is earlier than the line before"));
+        assert!(error(Waveform::from_csv("time,value\n")).contains("no samples"));
+        assert!(error(Waveform::from_csv("")).contains("no samples"));
+    }
+
+    #[test]
+    fn encode_numbers() {
+        let mut bytes = [0u8; 2];
+        encode("p", PortDataType::UInt16, 1.5, &mut bytes).unwrap();
+        assert_eq!(bytes, 2u16.to_ne_bytes());
+        encode("p", PortDataType::Int16, -1.5, &mut bytes).unwrap();
+        assert_eq!(bytes, (-2i16).to_ne_bytes());
+        // Integers saturate at the limits of their type.
+        encode("p", PortDataType::UInt16, -1.0, &mut bytes).unwrap();
+        assert_eq!(bytes, 0u16.to_ne_bytes());
+        encode("p", PortDataType::Int16, 1e9, &mut bytes).unwrap();
+        assert_eq!(bytes, i16::MAX.to_ne_bytes());
+
+        let mut byte = [0u8; 1];
+        encode("p", PortDataType::Boolean, 0.1, &mut byte).unwrap();
+        assert_eq!(byte, [1]);
+        encode("p", PortDataType::Bit, 0.0, &mut byte).unwrap();
+        assert_eq!(byte, [0]);
+        encode("p", PortDataType::Int8, -200.0, &mut byte).unwrap();
+        assert_eq!(byte, (-128i8).to_ne_bytes());
+
+        let mut float = [0u8; 4];
+        encode("p", PortDataType::Float32, 0.25, &mut float).unwrap();
+        assert_eq!(float, 0.25f32.to_ne_bytes());
+        encode("p", PortDataType::IecTime, 1000.4, &mut float).unwrap();
+        assert_eq!(float, 1000i32.to_ne_bytes());
+    }
+
+    #[test]
+    fn encode_errors() {
+        let mut bytes = [0u8; 8];
+        assert!(encode("p", PortDataType::StaticString, 1.0, &mut bytes).unwrap_err().to_string().contains("can't hold a number"));
+        assert!(encode("p", PortDataType::Other(99), 1.0, &mut bytes).is_err());
+        assert!(matches!(encode("p", PortDataType::UInt16, 1.0, &mut bytes),
+            Err(PlcnextError::Parameter(crate::error::ParameterError::LengthMismatch { expected: 8, actual: 2, .. }))));
+    }
+}
@@ -0,0 +1,54 @@
+// A derived ProcessImage, read and written through the simulation.
+#![cfg(feature = "simulation")]
+
+use plcnext::{simulation, PlcOperation, PortDataType, PortDirection, ProcessImage, SimulatedPort};
+
+#[derive(ProcessImage, Default)]
+struct Io {
+    #[port("Arp.Io.AxlC/0.DI16", input)]
+    inputs: u16,
+    #[port("Arp.Io.AxlC/0.AI2", input)]
+    analog: plcnext::BigEndian<i16>,
+    #[port("Arp.Io.AxlC/1.DO16", output)]
+    outputs: u16,
+    #[port("Arp.Io.AxlC/2.AO1")]
+    setpoint: i16,
+    cycles: u64
+}
+
+#[test]
+fn read_all_and_write_all() {
+    simulation().configure(&[
+        SimulatedPort::new("Arp.Io.AxlC/0.DI16", PortDirection::Input, PortDataType::UInt16).unwrap(),
+        SimulatedPort::new("Arp.Io.AxlC/0.AI2", PortDirection::Input, PortDataType::Int16).unwrap(),
+        SimulatedPort::new("Arp.Io.AxlC/1.DO16", PortDirection::Output, PortDataType::UInt16).unwrap(),
+        SimulatedPort::new("Arp.Io.AxlC/2.AO1", PortDirection::Output, PortDataType::Int16).unwrap()
+    ]).unwrap();
+    let system = plcnext::load("/usr/lib", "test", "/etc/acf.settings").unwrap();
+    simulation().trigger(PlcOperation::Load);
+
+    assert_eq!(Io::INPUT_PORTS, [
+        ("Arp.Io.AxlC", "Arp.Io.AxlC/0.DI16"), ("Arp.Io.AxlC", "Arp.Io.AxlC/0.AI2"), ("Arp.Io.AxlC", "Arp.Io.AxlC/2.AO1")]);
+    assert_eq!(Io::OUTPUT_PORTS, [("Arp.Io.AxlC", "Arp.Io.AxlC/1.DO16"), ("Arp.Io.AxlC", "Arp.Io.AxlC/2.AO1")]);
+
+    simulation().set_input("Arp.Io.AxlC/0.DI16", &0x00f0u16.to_ne_bytes()).unwrap();
+    simulation().set_input("Arp.Io.AxlC/0.AI2", &[0xff, 0x38]).unwrap();
+    simulation().initialize("Arp.Io.AxlC/2.AO1", &(-7i16).to_ne_bytes()).unwrap();
+    plcnext::read_from_axio_to_gds(&system, 100).unwrap();
+
+    let mut io = Io { cycles: 3, ..Io::default() };
+    io.read_all(&system).unwrap();
+    assert_eq!((io.inputs, io.analog.0, io.outputs, io.setpoint, io.cycles), (0x00f0, -200, 0, -7, 3));
+
+    io.outputs = io.inputs << 4;
+    io.setpoint = io.analog.0 / 2;
+    io.write_all(&system).unwrap();
+    plcnext::write_from_gds_to_axio(&system, 100).unwrap();
+    assert_eq!(simulation().output("Arp.Io.AxlC/1.DO16").unwrap(), 0x0f00u16.to_ne_bytes());
+    assert_eq!(simulation().output("Arp.Io.AxlC/2.AO1").unwrap(), (-100i16).to_ne_bytes());
+
+    // The fields that aren't inputs are left alone by read_all().
+    let mut again = Io::default();
+    again.read_all(&system).unwrap();
+    assert_eq!((again.outputs, again.setpoint), (0, -100));
+}